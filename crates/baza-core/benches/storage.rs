@@ -0,0 +1,58 @@
+//! Dump and restore of a vault with thousands of entries.
+
+use baza_core::{storage, Config};
+use criterion::{criterion_group, criterion_main, Criterion};
+
+const ENTRIES: usize = 5_000;
+const VALUE_LEN: usize = 256;
+
+/// Point the config at a temporary datadir, never at a real vault.
+fn setup() -> tempfile::TempDir {
+    let dir = tempfile::tempdir().expect("Failed to create tempdir");
+    let config = dir.path().join("baza.toml");
+    std::fs::write(
+        &config,
+        format!(
+            "[main]\ndatadir = {:?}\nbox_delimiter = \"::\"\nbundle_delimiter = \".\"\n\n[storage]\ntype = \"redb\"\n",
+            dir.path().to_string_lossy()
+        ),
+    )
+    .expect("Failed to write config");
+    Config::build(&config).expect("Failed to build config");
+    dir
+}
+
+/// Dump of a vault with `ENTRIES` bundles, restores check that it decrypts.
+fn entries() -> Vec<(String, Vec<u8>)> {
+    pollster::block_on(async {
+        baza_core::init(Some("bench".into()))
+            .await
+            .expect("Failed to initialize vault");
+        for i in 0..ENTRIES {
+            let content = "x".repeat(VALUE_LEN);
+            storage::save_content(format!("bench::{i:05}::login"), content)
+                .await
+                .expect("Failed to save bundle");
+        }
+        storage::dump().await.expect("Failed to dump")
+    })
+}
+
+fn bench_storage(c: &mut Criterion) {
+    let _dir = setup();
+    let entries = entries();
+
+    c.bench_function("dump", |b| {
+        b.iter(|| pollster::block_on(storage::dump()).expect("Failed to dump"))
+    });
+    c.bench_function("restore", |b| {
+        b.iter_batched(
+            || entries.clone(),
+            |data| pollster::block_on(storage::restore(data, None)).expect("Failed to restore"),
+            criterion::BatchSize::LargeInput,
+        )
+    });
+}
+
+criterion_group!(benches, bench_storage);
+criterion_main!(benches);
@@ -0,0 +1,172 @@
+//! Authenticated encryption of stored entries.
+//!
+//! Ciphertexts start with a header: magic bytes, the format version and the cipher id.
+//! The header and the name of the entry are authenticated as associated data.
+
+use crate::{error::Error, BazaR};
+use aes_gcm::aead::{Aead, KeyInit, Nonce, Payload};
+use aes_gcm::Aes256Gcm;
+use chacha20poly1305::XChaCha20Poly1305;
+use exn::ResultExt;
+use rand::RngExt;
+use serde::{Deserialize, Serialize};
+use zeroize::Zeroizing;
+
+const MAGIC: &[u8] = b"BZ";
+/// Header without a cipher id, AES-256-GCM only.
+const VERSION_1: u8 = 1;
+const VERSION: u8 = 2;
+
+/// Cipher used to encrypt new entries. Existing entries keep the cipher they were written with.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum Cipher {
+    #[default]
+    #[serde(rename = "aes-256-gcm")]
+    Aes256Gcm,
+    /// 192-bit random nonces, safe for any number of encryptions under one key.
+    #[serde(rename = "xchacha20-poly1305")]
+    XChaCha20Poly1305,
+}
+
+impl Cipher {
+    fn id(self) -> u8 {
+        match self {
+            Cipher::Aes256Gcm => 1,
+            Cipher::XChaCha20Poly1305 => 2,
+        }
+    }
+
+    fn from_id(id: u8) -> BazaR<Self> {
+        match id {
+            1 => Ok(Cipher::Aes256Gcm),
+            2 => Ok(Cipher::XChaCha20Poly1305),
+            _ => exn::bail!(Error::Message(format!("Unsupported cipher id {id}"))),
+        }
+    }
+
+    fn nonce_len(self) -> usize {
+        match self {
+            Cipher::Aes256Gcm => 12,
+            Cipher::XChaCha20Poly1305 => 24,
+        }
+    }
+
+    fn seal(self, key: &[u8], nonce: &[u8], payload: Payload) -> BazaR<Vec<u8>> {
+        match self {
+            Cipher::Aes256Gcm => seal::<Aes256Gcm>(key, nonce, payload),
+            Cipher::XChaCha20Poly1305 => seal::<XChaCha20Poly1305>(key, nonce, payload),
+        }
+    }
+
+    /// Decrypt `ciphertext`, the nonce followed by the sealed data.
+    fn open(self, key: &[u8], ciphertext: &[u8], aad: &[u8]) -> BazaR<Zeroizing<Vec<u8>>> {
+        if ciphertext.len() < self.nonce_len() {
+            exn::bail!(Error::Message("Invalid ciphertext: too short".into()));
+        }
+        let (nonce, sealed) = ciphertext.split_at(self.nonce_len());
+        let payload = Payload { msg: sealed, aad };
+        match self {
+            Cipher::Aes256Gcm => open::<Aes256Gcm>(key, nonce, payload),
+            Cipher::XChaCha20Poly1305 => open::<XChaCha20Poly1305>(key, nonce, payload),
+        }
+    }
+}
+
+fn seal<A: Aead + KeyInit>(key: &[u8], nonce: &[u8], payload: Payload) -> BazaR<Vec<u8>> {
+    let cipher =
+        A::new_from_slice(key).or_raise(|| Error::Message("Failed to initialize cipher".into()))?;
+    cipher
+        .encrypt(Nonce::<A>::from_slice(nonce), payload)
+        .or_raise(|| Error::Message("Failed to encrypt data".into()))
+}
+
+fn open<A: Aead + KeyInit>(
+    key: &[u8],
+    nonce: &[u8],
+    payload: Payload,
+) -> BazaR<Zeroizing<Vec<u8>>> {
+    let cipher =
+        A::new_from_slice(key).or_raise(|| Error::Message("Failed to initialize cipher".into()))?;
+    cipher
+        .decrypt(Nonce::<A>::from_slice(nonce), payload)
+        .map(Zeroizing::new)
+        .map_err(|e| exn::Exn::new(e.into()))
+}
+
+/// Encrypt `plaintext` for the entry `name`; decryption under any other name fails.
+pub(crate) fn encrypt(cipher: Cipher, plaintext: &[u8], key: &[u8], name: &str) -> BazaR<Vec<u8>> {
+    let header = [MAGIC, &[VERSION, cipher.id()]].concat();
+    let mut nonce = vec![0u8; cipher.nonce_len()];
+    rand::rng().fill(nonce.as_mut_slice());
+    let payload = Payload {
+        msg: plaintext,
+        aad: &[header.as_slice(), name.as_bytes()].concat(),
+    };
+    let sealed = cipher.seal(key, &nonce, payload)?;
+    Ok([header, nonce, sealed].concat())
+}
+
+/// Decrypt a ciphertext of any format: dispatch on the header, or treat it as
+/// headerless AES-256-GCM written by older versions.
+pub(crate) fn decrypt(ciphertext: &[u8], key: &[u8], name: &str) -> BazaR<Zeroizing<Vec<u8>>> {
+    let versioned = match ciphertext.strip_prefix(MAGIC) {
+        Some([VERSION, id, sealed @ ..]) => Some((Cipher::from_id(*id), MAGIC.len() + 2, sealed)),
+        Some([VERSION_1, sealed @ ..]) => Some((Ok(Cipher::Aes256Gcm), MAGIC.len() + 1, sealed)),
+        _ => None,
+    };
+    match versioned {
+        Some((cipher, header_len, sealed)) => {
+            let aad = [&ciphertext[..header_len], name.as_bytes()].concat();
+            cipher
+                .and_then(|cipher| cipher.open(key, sealed, &aad))
+                // A legacy nonce may start with the header bytes by chance
+                .or_else(|e| Cipher::Aes256Gcm.open(key, ciphertext, &[]).map_err(|_| e))
+        }
+        None => Cipher::Aes256Gcm.open(key, ciphertext, &[]),
+    }
+}
+
+#[cfg(test)]
+#[cfg(not(target_arch = "wasm32"))]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; 32] = [42u8; 32];
+
+    #[test]
+    fn test_ciphers() {
+        for cipher in [Cipher::Aes256Gcm, Cipher::XChaCha20Poly1305] {
+            let ciphertext =
+                encrypt(cipher, b"secret", &KEY, "bank::login").expect("encrypt failed");
+            assert_eq!(ciphertext[..4], [b'B', b'Z', VERSION, cipher.id()]);
+            let plaintext = decrypt(&ciphertext, &KEY, "bank::login").expect("decrypt failed");
+            assert_eq!(plaintext.as_slice(), b"secret");
+            assert!(decrypt(&ciphertext, &KEY, "forum::login").is_err());
+            assert!(decrypt(&ciphertext, &[0u8; 32], "bank::login").is_err());
+        }
+    }
+
+    #[test]
+    fn test_older_formats() {
+        let nonce = [7u8; 12];
+
+        // Headerless, without associated data
+        let sealed =
+            seal::<Aes256Gcm>(&KEY, &nonce, b"secret".as_slice().into()).expect("seal failed");
+        let legacy = [nonce.as_slice(), &sealed].concat();
+        let plaintext = decrypt(&legacy, &KEY, "any::name").expect("decrypt failed");
+        assert_eq!(plaintext.as_slice(), b"secret");
+
+        // Version 1 header, AES-256-GCM without a cipher id
+        let header = [MAGIC, &[VERSION_1]].concat();
+        let payload = Payload {
+            msg: b"secret",
+            aad: &[header.as_slice(), b"bank::login"].concat(),
+        };
+        let sealed = seal::<Aes256Gcm>(&KEY, &nonce, payload).expect("seal failed");
+        let v1 = [header.as_slice(), &nonce, &sealed].concat();
+        let plaintext = decrypt(&v1, &KEY, "bank::login").expect("decrypt failed");
+        assert_eq!(plaintext.as_slice(), b"secret");
+        assert!(decrypt(&v1, &KEY, "forum::login").is_err());
+    }
+}
@@ -88,8 +88,8 @@ impl Container {
         #[cfg(not(target_arch = "wasm32"))]
         crate::m(&content, crate::MessageType::Clean);
         #[cfg(target_arch = "wasm32")]
-        tracing::info!("Content: {}", content);
-        Ok(())
+        tracing::info!("Content: {}", content.as_str());
+        meta::touch(&name).await
     }
 
     async fn update(self) -> BazaR<Self> {
@@ -100,7 +100,7 @@ impl Container {
 
             let temp = tempfile::NamedTempFile::new()
                 .map_err(|e| crate::error::Error::Message(e.to_string()))?;
-            std::fs::write(temp.path(), content)
+            std::fs::write(temp.path(), content.as_bytes())
                 .map_err(|e| crate::error::Error::Message(e.to_string()))?;
 
             let editor = std::env::var("EDITOR").unwrap_or(String::from("vi"));
@@ -161,6 +161,7 @@ impl Container {
 
     async fn copy_to_clipboard(self, ttl: u64) -> BazaR<()> {
         let name = self.name();
+        meta::touch(&name).await?;
         storage::copy_to_clipboard(name, ttl).await?;
         Ok(())
     }
@@ -178,7 +179,7 @@ pub async fn add(str: String, data: Option<String>) -> BazaR<()> {
 
 pub async fn generate(str: String) -> BazaR<()> {
     let password = Password::default();
-    add(str, Some(password.inner.to_string())).await
+    add(str, Some(password.as_str().to_string())).await
 }
 
 pub async fn read(str: String) -> BazaR<()> {
@@ -238,7 +239,9 @@ mod tests {
 
     fn create(str: &str) {
         let str = str.to_string();
-        let password = crate::Password::generate(255, false, false, false).as_str();
+        let password = crate::Password::generate(255, false, false, false)
+            .as_str()
+            .to_string();
         match pollster::block_on(add(str, Some(password))) {
             Ok(_) => {}
             Err(e) => panic!("add failed: {}", e),
@@ -263,26 +266,11 @@ mod tests {
 
     #[test]
     fn it_works() {
-        let _lock = crate::TEST_MUTEX.lock().unwrap();
-        let test_dir = std::path::PathBuf::from(crate::test_datadir());
-        let _ = std::fs::remove_dir_all(&test_dir);
-        std::fs::create_dir_all(&test_dir).expect("Failed to create test dir");
-
-        let config_path = test_dir.join("baza.toml");
-        let mut config = Config::default();
-        config.main.datadir = test_dir.to_string_lossy().to_string();
-        let config_str = match toml::to_string(&config) {
-            Ok(s) => s,
-            Err(e) => panic!("toml serialize failed: {}", e),
-        };
-        if let Err(e) = std::fs::write(&config_path, config_str) {
-            panic!("write config failed: {}", e);
-        }
-        if let Err(e) = Config::build(&config_path) {
-            panic!("Config::build failed: {}", e);
-        }
+        crate::test_config();
 
-        let password = crate::Password::generate(255, false, false, false).as_str();
+        let password = crate::Password::generate(255, false, false, false)
+            .as_str()
+            .to_string();
         if let Err(e) = pollster::block_on(init(Some(password.clone()))) {
             panic!("init failed: {}", e);
         }
@@ -312,9 +300,7 @@ mod tests {
 
     #[test]
     fn test_create_from_str() {
-        let _lock = crate::TEST_MUTEX.lock().unwrap();
-        // Ensure Config is initialized
-        let _ = Config::get();
+        crate::test_config();
 
         // Case 1: Multiple boxes and a bundle
         let builder = ContainerBuilder::new()
@@ -0,0 +1,376 @@
+//! Integrity check of every entry in the vault.
+//!
+//! Each encrypted entry is decrypted with the session key and parsed as what its key says
+//! it is. Broken entries can be quarantined: moved as they are under the system box, where
+//! nothing reads them, so the rest of the vault keeps working.
+
+use crate::{
+    error::Error,
+    index,
+    meta::Meta,
+    storage::{self, Change},
+    trash, BazaR, HISTORY_PREFIX, INDEX_KEY, QUARANTINE_PREFIX, TOTP_KEY, TOTP_UUID_KEY,
+    TRASH_PREFIX, VERIFIER_KEY,
+};
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use zeroize::Zeroizing;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Kind {
+    /// Does not decrypt with the vault key.
+    Undecryptable,
+    /// Decrypts, but is not the record its key says it is.
+    Corrupted,
+    /// Text that is not valid UTF-8.
+    InvalidUtf8,
+    /// Belongs to no bundle of the index.
+    Orphaned,
+    /// In the index, but not stored.
+    Missing,
+    /// Contradicts another entry.
+    Inconsistent,
+}
+
+impl Kind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Kind::Undecryptable => "undecryptable",
+            Kind::Corrupted => "corrupted",
+            Kind::InvalidUtf8 => "invalid UTF-8",
+            Kind::Orphaned => "orphaned",
+            Kind::Missing => "missing",
+            Kind::Inconsistent => "inconsistent",
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Issue {
+    /// Storage key of the entry.
+    pub key: String,
+    /// Bundle the entry belongs to, when the index knows it.
+    pub name: Option<String>,
+    pub kind: Kind,
+    pub detail: String,
+    /// Moved to quarantine, or dropped from the index when its bundle is missing.
+    pub fixed: bool,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct Report {
+    /// Number of entries walked.
+    pub checked: usize,
+    pub issues: Vec<Issue>,
+}
+
+impl Report {
+    /// Issues left in the vault.
+    pub fn remaining(&self) -> usize {
+        self.issues.iter().filter(|issue| !issue.fixed).count()
+    }
+
+    fn add(&mut self, key: &str, name: Option<&String>, (kind, detail): (Kind, String)) {
+        self.issues.push(Issue {
+            key: key.to_string(),
+            name: name.cloned(),
+            kind,
+            detail,
+            fixed: false,
+        });
+    }
+}
+
+/// Entries the vault can't be opened without; quarantining them would not help.
+fn is_essential(key: &str) -> bool {
+    key == INDEX_KEY || key == VERIFIER_KEY || key == TOTP_KEY || crate::is_raw_key(key)
+}
+
+fn decrypt(value: &[u8], key: &[u8], name: &str) -> Result<Vec<u8>, (Kind, String)> {
+    crate::decrypt_data(value, key, name)
+        .map(|plaintext| plaintext.to_vec())
+        .map_err(|e| (Kind::Undecryptable, e.to_string()))
+}
+
+fn utf8(plaintext: &[u8]) -> Result<(), (Kind, String)> {
+    std::str::from_utf8(plaintext)
+        .map(|_| ())
+        .map_err(|e| (Kind::InvalidUtf8, e.to_string()))
+}
+
+fn parse<T: serde::de::DeserializeOwned>(
+    plaintext: &[u8],
+    what: &str,
+) -> Result<(), (Kind, String)> {
+    postcard::from_bytes::<T>(plaintext)
+        .map(|_| ())
+        .map_err(|e| (Kind::Corrupted, format!("Not a valid {what}: {e}")))
+}
+
+/// What is wrong with the entry stored under `id`, given bundle names by identifier.
+fn check_entry(
+    id: &str,
+    value: &[u8],
+    names: &HashMap<String, String>,
+    key: &[u8],
+) -> Result<(), (Kind, String)> {
+    if id == TOTP_UUID_KEY {
+        return utf8(value);
+    }
+    if crate::is_raw_key(id) {
+        return Ok(());
+    }
+    if id.starts_with(TRASH_PREFIX) {
+        // Records are stored under their own name
+        return parse::<trash::Record>(&decrypt(value, key, id)?, "trash record");
+    }
+    if let Some((prefix, bundle)) = index::split_record(id) {
+        let Some(name) = names.get(bundle) else {
+            return Err((
+                Kind::Orphaned,
+                format!("No bundle {} in the index", index::short_id(bundle)),
+            ));
+        };
+        let plaintext = decrypt(value, key, &format!("{prefix}{name}"))?;
+        return if prefix == HISTORY_PREFIX {
+            parse::<Vec<(u64, Zeroizing<String>)>>(&plaintext, "history record")
+        } else {
+            parse::<Meta>(&plaintext, "metadata record")
+        };
+    }
+    if crate::is_system_key(id) {
+        let plaintext = decrypt(value, key, id)?;
+        if id == TOTP_KEY {
+            utf8(&plaintext)?;
+            let secret = String::from_utf8_lossy(&plaintext);
+            crate::totp::get_totp(&secret).map_err(|e| (Kind::Corrupted, e.to_string()))?;
+        }
+        return Ok(());
+    }
+    if !index::is_id(id) {
+        return Err((
+            Kind::Orphaned,
+            "Stored under a plaintext name, outside the index".into(),
+        ));
+    }
+    match names.get(id) {
+        Some(name) => utf8(&decrypt(value, key, name)?),
+        None => Err((Kind::Orphaned, "Not in the index".into())),
+    }
+}
+
+/// Check every entry of the unlocked vault, moving broken ones to quarantine if asked.
+pub async fn check(quarantine: bool) -> BazaR<Report> {
+    let key = crate::key()?;
+    let entries: BTreeMap<String, Vec<u8>> = storage::with_backend(|backend| backend.entries())
+        .await?
+        .into_iter()
+        .collect();
+    let mut report = Report {
+        checked: entries.len(),
+        issues: Vec::new(),
+    };
+    let index = match entries.get(INDEX_KEY) {
+        Some(sealed) => match index::open(sealed, &key) {
+            Ok(index) => index,
+            Err(e) => {
+                // Without names nothing else can be told apart from garbage
+                report.add(INDEX_KEY, None, (Kind::Undecryptable, e.to_string()));
+                return Ok(report);
+            }
+        },
+        None => BTreeSet::new(),
+    };
+    let mut names = HashMap::new();
+    for name in index {
+        let id = index::id(&name, &key)?;
+        if !entries.contains_key(&id) {
+            report.add(
+                &id,
+                Some(&name),
+                (Kind::Missing, "Bundle content is gone".into()),
+            );
+        }
+        names.insert(id, name);
+    }
+
+    for (id, value) in entries.iter().filter(|(id, _)| *id != INDEX_KEY) {
+        if let Err(problem) = check_entry(id, value, &names, &key) {
+            let bundle = index::split_record(id).map_or(id.as_str(), |(_, bundle)| bundle);
+            report.add(id, names.get(bundle), problem);
+        }
+    }
+
+    if entries.contains_key(TOTP_KEY) != entries.contains_key(TOTP_UUID_KEY) {
+        let (present, absent) = if entries.contains_key(TOTP_KEY) {
+            (TOTP_KEY, TOTP_UUID_KEY)
+        } else {
+            (TOTP_UUID_KEY, TOTP_KEY)
+        };
+        report.add(
+            present,
+            None,
+            (Kind::Inconsistent, format!("Stored without {absent}")),
+        );
+    }
+
+    if quarantine {
+        self::quarantine(&mut report, &entries, &names, &key).await?;
+    }
+    Ok(report)
+}
+
+/// Move the entries of fixable issues to quarantine, dropping their bundles from the index
+/// together with the records kept next to them.
+async fn quarantine(
+    report: &mut Report,
+    entries: &BTreeMap<String, Vec<u8>>,
+    names: &HashMap<String, String>,
+    key: &[u8],
+) -> BazaR<()> {
+    let mut moved = Vec::new();
+    let mut dropped = BTreeSet::new();
+    for issue in report.issues.iter_mut() {
+        if is_essential(&issue.key) || issue.kind == Kind::Inconsistent {
+            continue;
+        }
+        if names.contains_key(&issue.key) {
+            dropped.insert(issue.key.clone());
+        }
+        if entries.contains_key(&issue.key) {
+            moved.push(issue.key.clone());
+        }
+        issue.fixed = true;
+    }
+    for id in entries.keys() {
+        let Some((_, bundle)) = index::split_record(id) else {
+            continue;
+        };
+        if dropped.contains(bundle) && !moved.contains(id) {
+            report.issues.push(Issue {
+                key: id.clone(),
+                name: names.get(bundle).cloned(),
+                kind: Kind::Orphaned,
+                detail: "Its bundle was quarantined".into(),
+                fixed: true,
+            });
+            moved.push(id.clone());
+        }
+    }
+    if moved.is_empty() && dropped.is_empty() {
+        return Ok(());
+    }
+
+    let mut changes = Vec::with_capacity(moved.len() * 2 + 1);
+    for id in &moved {
+        changes.push(Change::Set(
+            format!("{QUARANTINE_PREFIX}{id}"),
+            entries[id].clone(),
+        ));
+        changes.push(Change::Remove(id.clone()));
+    }
+    if !dropped.is_empty() {
+        let kept: BTreeSet<String> = names
+            .iter()
+            .filter(|(id, _)| !dropped.contains(*id))
+            .map(|(_, name)| name.clone())
+            .collect();
+        changes.push(Change::Set(INDEX_KEY.to_string(), index::seal(&kept, key)?));
+    }
+    storage::with_backend(|backend| backend.apply(changes)).await?;
+    storage::checkpoint(format!("Quarantine {} broken entries", moved.len())).await
+}
+
+/// Entries moved to quarantine by earlier checks, under their original key.
+pub async fn quarantined() -> BazaR<Vec<String>> {
+    let keys = storage::with_backend(|backend| backend.list_keys()).await?;
+    Ok(keys
+        .into_iter()
+        .filter_map(|key| key.strip_prefix(QUARANTINE_PREFIX).map(str::to_string))
+        .collect())
+}
+
+/// Error for a report with issues left in the vault.
+pub fn ensure_healthy(report: &Report) -> BazaR<()> {
+    match report.remaining() {
+        0 => Ok(()),
+        count => exn::bail!(Error::Message(format!(
+            "The vault has {count} problem(s), 'baza doctor --quarantine' moves broken entries aside"
+        ))),
+    }
+}
+
+#[cfg(test)]
+#[cfg(not(target_arch = "wasm32"))]
+mod tests {
+    use super::*;
+    use crate::{init, storage::with_backend};
+
+    #[test]
+    fn test_doctor() {
+        crate::test_config();
+
+        pollster::block_on(async {
+            init(Some("doctor".into())).await.expect("init failed");
+            storage::save_content("site::mail::me".into(), "secret".into())
+                .await
+                .expect("save failed");
+            storage::save_content("site::mail::me".into(), "secret 2".into())
+                .await
+                .expect("save failed");
+            storage::save_content("site::bank::me".into(), "pin".into())
+                .await
+                .expect("save failed");
+
+            let report = check(false).await.expect("check failed");
+            assert!(report.issues.is_empty(), "{:?}", report.issues);
+            assert!(report.checked > 0);
+
+            // Break things behind the vault's back
+            let key = crate::key().expect("key failed");
+            let bank = index::id("site::bank::me", &key).expect("id failed");
+            let mail = index::id("site::mail::me", &key).expect("id failed");
+            let stray = index::id("site::gone::me", &key).expect("id failed");
+            let invalid =
+                crate::encrypt_data(&[0xff, 0xfe], &key, "site::mail::me").expect("encrypt failed");
+            with_backend(|backend| {
+                backend.apply(vec![
+                    Change::Set(bank.clone(), b"garbage".to_vec()),
+                    Change::Set(mail.clone(), invalid),
+                    Change::Set(crate::meta::storage_key(&stray), b"meta".to_vec()),
+                    Change::Set(TOTP_UUID_KEY.into(), b"uuid".to_vec()),
+                ])
+            })
+            .await
+            .expect("apply failed");
+
+            let report = check(false).await.expect("check failed");
+            let kinds: Vec<_> = report
+                .issues
+                .iter()
+                .map(|issue| (issue.key.as_str(), issue.kind))
+                .collect();
+            assert_eq!(report.issues.len(), 4, "{kinds:?}");
+            assert!(kinds.contains(&(bank.as_str(), Kind::Undecryptable)));
+            assert!(kinds.contains(&(mail.as_str(), Kind::InvalidUtf8)));
+            assert!(kinds.contains(&(crate::meta::storage_key(&stray).as_str(), Kind::Orphaned)));
+            assert!(kinds.contains(&(TOTP_UUID_KEY, Kind::Inconsistent)));
+            assert!(ensure_healthy(&report).is_err());
+
+            let report = check(true).await.expect("check failed");
+            assert_eq!(report.remaining(), 1);
+            // Both bundles went, along with their metadata and history
+            assert_eq!(quarantined().await.expect("quarantined failed").len(), 6);
+            assert!(storage::list_all_keys()
+                .await
+                .expect("list failed")
+                .is_empty());
+
+            // Quarantined entries are left alone
+            let report = check(false).await.expect("check failed");
+            let kinds: Vec<_> = report.issues.iter().map(|issue| issue.kind).collect();
+            assert_eq!(kinds, [Kind::Inconsistent], "{:?}", report.issues);
+        });
+    }
+}
@@ -5,6 +5,15 @@ pub enum Error {
     #[error("{0}")]
     Message(String),
 
+    #[error("Invalid passphrase")]
+    InvalidPassphrase,
+
+    #[error("Invalid TOTP code (ID: {0})")]
+    InvalidTotp(String),
+
+    #[error("Too many failed unlock attempts, try again in {0} seconds")]
+    Throttled(u64),
+
     #[error(transparent)]
     Io(#[from] std::io::Error),
 
@@ -0,0 +1,191 @@
+//! Previous revisions of bundles. Saving a bundle keeps the content it replaces,
+//! encrypted in a single record per bundle next to it, newest revision first.
+
+use crate::{error::Error, index, storage, utils::get_timestamp, BazaR, Config, HISTORY_PREFIX};
+use exn::ResultExt;
+use zeroize::Zeroizing;
+
+/// Default number of revisions kept per bundle.
+pub(crate) fn default_limit() -> usize {
+    10
+}
+
+/// Content of a bundle before one of its saves.
+pub struct Revision {
+    /// UNIX timestamp of the save that replaced this content.
+    pub timestamp: u64,
+    pub content: Zeroizing<String>,
+}
+
+/// Key of the history record of the bundle stored under `id`.
+pub(crate) fn storage_key(id: &str) -> String {
+    index::record_key(HISTORY_PREFIX, id)
+}
+
+fn record_name(name: &str) -> String {
+    format!("{HISTORY_PREFIX}{name}")
+}
+
+pub(crate) async fn load(name: &str, key: &[u8]) -> BazaR<Vec<Revision>> {
+    let id = storage_key(&index::id(name, key)?);
+    if !storage::contains(&id).await? {
+        return Ok(Vec::new());
+    }
+    let encrypted = storage::with_backend(|backend| backend.get(&id)).await?;
+    let plaintext = crate::decrypt_data(&encrypted, key, &record_name(name))?;
+    let revisions: Vec<(u64, Zeroizing<String>)> = postcard::from_bytes(&plaintext)
+        .or_raise(|| Error::Message(format!("Failed to parse history of '{name}'")))?;
+    Ok(revisions
+        .into_iter()
+        .map(|(timestamp, content)| Revision { timestamp, content })
+        .collect())
+}
+
+pub(crate) fn seal(name: &str, revisions: &[Revision], key: &[u8]) -> BazaR<Vec<u8>> {
+    let revisions: Vec<(u64, &str)> = revisions
+        .iter()
+        .map(|revision| (revision.timestamp, revision.content.as_str()))
+        .collect();
+    let plaintext = Zeroizing::new(
+        postcard::to_stdvec(&revisions)
+            .or_raise(|| Error::Message(format!("Failed to serialize history of '{name}'")))?,
+    );
+    crate::encrypt_data(&plaintext, key, &record_name(name))
+}
+
+/// Updated history record of the bundle `name` when its content is replaced by `content`,
+/// or `None` when nothing has to be kept.
+pub(crate) async fn record(
+    name: &str,
+    content: &str,
+    key: &[u8],
+) -> BazaR<Option<storage::Change>> {
+    let limit = Config::get().main.history;
+    if limit == 0 {
+        return Ok(None);
+    }
+    let previous = storage::get_content(name).await?;
+    if previous.as_str() == content {
+        return Ok(None);
+    }
+    let mut revisions = load(name, key).await?;
+    revisions.insert(
+        0,
+        Revision {
+            timestamp: get_timestamp(),
+            content: previous,
+        },
+    );
+    revisions.truncate(limit);
+    let id = storage_key(&index::id(name, key)?);
+    Ok(Some(storage::Change::Set(id, seal(name, &revisions, key)?)))
+}
+
+/// Previous revisions of the bundle `name`, newest first.
+pub async fn list(name: &str) -> BazaR<Vec<Revision>> {
+    let key = crate::key()?;
+    load(name, &key).await
+}
+
+/// Revision `number` of the bundle `name`, 1 being the most recent one.
+pub async fn get(name: &str, number: usize) -> BazaR<Revision> {
+    list(name)
+        .await?
+        .into_iter()
+        .nth(number.wrapping_sub(1))
+        .ok_or_else(|| {
+            exn::Exn::new(Error::Message(format!(
+                "Bundle '{name}' has no revision {number}"
+            )))
+        })
+}
+
+/// Unified diff from revision `number` of the bundle `name` to its current content.
+pub async fn diff(name: &str, number: usize) -> BazaR<Zeroizing<String>> {
+    let revision = get(name, number).await?;
+    let current = storage::get_content(name).await?;
+    let diff = similar::TextDiff::from_lines(revision.content.as_str(), current.as_str())
+        .unified_diff()
+        .header(&format!("{name}@{number}"), name)
+        .to_string();
+    Ok(Zeroizing::new(diff))
+}
+
+/// Bring back revision `number` of the bundle `name`. The replaced content becomes
+/// the newest revision, so a rollback can be rolled back too.
+pub async fn rollback(name: &str, number: usize) -> BazaR<()> {
+    let revision = get(name, number).await?;
+    storage::save_content(name.to_string(), revision.content.to_string()).await?;
+    tracing::info!("Bundle '{name}' rolled back to revision {number}");
+    Ok(())
+}
+
+#[cfg(test)]
+#[cfg(not(target_arch = "wasm32"))]
+mod tests {
+    use super::*;
+    use crate::{init, rekey};
+
+    const NAME: &str = "test::history::login";
+
+    async fn contents() -> Vec<String> {
+        list(NAME)
+            .await
+            .expect("list failed")
+            .iter()
+            .map(|revision| revision.content.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_history() {
+        crate::test_config();
+
+        pollster::block_on(async {
+            init(Some("passphrase".into())).await.expect("init failed");
+            for content in ["first", "second", "second", "third"] {
+                storage::save_content(NAME.into(), content.into())
+                    .await
+                    .expect("save_content failed");
+            }
+            // Saving unchanged content keeps no revision
+            assert_eq!(contents().await, vec!["second", "first"]);
+            assert!(get(NAME, 0).await.is_err());
+            assert!(get(NAME, 3).await.is_err());
+
+            let diff = diff(NAME, 2).await.expect("diff failed");
+            assert!(diff.contains("-first"));
+            assert!(diff.contains("+third"));
+
+            rollback(NAME, 2).await.expect("rollback failed");
+            let content = storage::get_content(NAME)
+                .await
+                .expect("get_content failed");
+            assert_eq!(content.as_str(), "first");
+            assert_eq!(contents().await, vec!["third", "second", "first"]);
+
+            // Older revisions are dropped past the limit
+            for i in 0..default_limit() {
+                storage::save_content(NAME.into(), format!("edit {i}"))
+                    .await
+                    .expect("save_content failed");
+            }
+            let revisions = contents().await;
+            assert_eq!(revisions.len(), default_limit());
+            assert_eq!(revisions[0], format!("edit {}", default_limit() - 2));
+
+            // History follows the bundle to its new identifier
+            rekey("passphrase".into()).await.expect("rekey failed");
+            assert_eq!(contents().await, revisions);
+
+            storage::delete_by_name(NAME.into())
+                .await
+                .expect("delete_by_name failed");
+            assert!(contents().await.is_empty());
+            let keys = storage::with_backend(|backend| backend.list_keys())
+                .await
+                .expect("list_keys failed");
+            assert!(keys.iter().all(|key| index::split_record(key).is_none()));
+        });
+    }
+}
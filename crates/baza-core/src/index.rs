@@ -0,0 +1,229 @@
+//! Bundle names are not stored in plaintext: entries are keyed by an HMAC of their
+//! name, and the names themselves live in an encrypted index under the system box.
+//!
+//! With `storage.layout = "paths"`, the `files` and `git` backends key bundles by their
+//! name instead, so each bundle file mirrors it. The index is kept all the same.
+
+use crate::{
+    error::Error,
+    storage::{self, Change},
+    BazaR, HISTORY_PREFIX, INDEX_KEY, META_PREFIX,
+};
+#[cfg(not(target_arch = "wasm32"))]
+use crate::{Config, Layout, Type};
+use exn::ResultExt;
+use hmac::{Hmac, KeyInit, Mac};
+use sha2::Sha256;
+use std::collections::{BTreeSet, HashMap};
+
+const NAME_KEY_CONTEXT: &[u8] = b"baza name index";
+const ID_LEN: usize = 64;
+
+fn hmac(key: &[u8], data: &[u8]) -> BazaR<Vec<u8>> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key)
+        .or_raise(|| Error::Message("Failed to initialize HMAC".into()))?;
+    mac.update(data);
+    Ok(mac.finalize().into_bytes().to_vec())
+}
+
+/// Whether bundles are keyed by their name, with the `paths` layout on a backend storing
+/// one file per entry.
+pub(crate) fn by_name() -> bool {
+    #[cfg(not(target_arch = "wasm32"))]
+    if Config::get().storage.layout == Some(Layout::Paths) {
+        return matches!(storage::current_type(), Type::Files | Type::Git);
+    }
+    false
+}
+
+/// Storage identifier of a bundle: hex HMAC of its name under a key derived from the data
+/// key, or the name itself when bundles are keyed [`by_name`].
+pub(crate) fn id(name: &str, data_key: &[u8]) -> BazaR<String> {
+    if by_name() {
+        return Ok(name.to_string());
+    }
+    let name_key = hmac(data_key, NAME_KEY_CONTEXT)?;
+    let mac = hmac(&name_key, name.as_bytes())?;
+    Ok(mac.iter().map(|byte| format!("{byte:02x}")).collect())
+}
+
+/// Start of a bundle identifier, enough to tell bundles apart in messages.
+pub(crate) fn short_id(id: &str) -> &str {
+    match id.char_indices().nth(12) {
+        Some((end, _)) if !by_name() => &id[..end],
+        _ => id,
+    }
+}
+
+pub(crate) fn is_id(key: &str) -> bool {
+    if by_name() {
+        return !crate::is_system_key(key);
+    }
+    key.len() == ID_LEN && key.bytes().all(|byte| byte.is_ascii_hexdigit())
+}
+
+/// Key under which `name` is stored. System entries keep their own name.
+pub(crate) fn storage_key(name: &str, data_key: &[u8]) -> BazaR<String> {
+    if crate::is_system_key(name) {
+        return Ok(name.to_string());
+    }
+    id(name, data_key)
+}
+
+/// Prefixes of the records kept next to every bundle, keyed by the bundle identifier
+/// and encrypted for the prefixed bundle name.
+const RECORD_PREFIXES: [&str; 2] = [HISTORY_PREFIX, META_PREFIX];
+
+/// Key of the record with `prefix` of the bundle stored under `id`.
+pub(crate) fn record_key(prefix: &str, id: &str) -> String {
+    format!("{prefix}{id}")
+}
+
+/// Prefix and bundle identifier of the record stored under `key`, if it is one.
+pub(crate) fn split_record(key: &str) -> Option<(&'static str, &str)> {
+    RECORD_PREFIXES
+        .iter()
+        .find_map(|prefix| key.strip_prefix(prefix).map(|id| (*prefix, id)))
+}
+
+/// Name the entry stored under `key` is encrypted for, given bundle names by identifier.
+///
+/// Records kept next to a bundle are encrypted for the prefixed name of their bundle,
+/// entries outside the index for their own key.
+pub(crate) fn entry_name(key: &str, names: &HashMap<String, String>) -> String {
+    if let Some((prefix, name)) =
+        split_record(key).and_then(|(prefix, id)| Some((prefix, names.get(id)?)))
+    {
+        return format!("{prefix}{name}");
+    }
+    names.get(key).cloned().unwrap_or_else(|| key.to_string())
+}
+
+/// Names of all bundles in the vault.
+pub(crate) async fn load(data_key: &[u8]) -> BazaR<BTreeSet<String>> {
+    if !storage::contains(INDEX_KEY).await? {
+        return Ok(BTreeSet::new());
+    }
+    let encrypted = storage::with_backend(|backend| backend.get(INDEX_KEY)).await?;
+    open(&encrypted, data_key)
+}
+
+/// Names in the encrypted index record `encrypted`.
+pub(crate) fn open(encrypted: &[u8], data_key: &[u8]) -> BazaR<BTreeSet<String>> {
+    let plaintext = crate::decrypt_data(encrypted, data_key, INDEX_KEY)?;
+    postcard::from_bytes(&plaintext)
+        .or_raise(|| Error::Message("Failed to parse name index".into()))
+}
+
+/// Encrypted index record for `names`.
+pub(crate) fn seal(names: &BTreeSet<String>, data_key: &[u8]) -> BazaR<Vec<u8>> {
+    let plaintext = postcard::to_stdvec(names)
+        .or_raise(|| Error::Message("Failed to serialize name index".into()))?;
+    crate::encrypt_data(&plaintext, data_key, INDEX_KEY)
+}
+
+/// Move entries still stored under their plaintext name to their identifier.
+///
+/// Entries, removals and the updated index are written in a single batch.
+pub(crate) async fn migrate(data_key: &[u8]) -> BazaR<()> {
+    let keys = storage::with_backend(|backend| backend.list_keys()).await?;
+    let legacy: Vec<String> = keys
+        .into_iter()
+        .filter(|key| !crate::is_system_key(key) && !is_id(key))
+        .collect();
+    if legacy.is_empty() {
+        return Ok(());
+    }
+
+    tracing::warn!(
+        "Found {} bundle names stored in plaintext, moving them to the encrypted index",
+        legacy.len()
+    );
+    let mut names = load(data_key).await?;
+    let values = storage::with_backend(|backend| backend.get_many(&legacy)).await?;
+    let mut changes = Vec::with_capacity(legacy.len() * 2 + 1);
+    for (name, value) in legacy.into_iter().zip(values) {
+        changes.push(Change::Set(id(&name, data_key)?, value));
+        changes.push(Change::Remove(name.clone()));
+        names.insert(name);
+    }
+    changes.push(Change::Set(INDEX_KEY.to_string(), seal(&names, data_key)?));
+    storage::with_backend(|backend| backend.apply(changes)).await
+}
+
+#[cfg(test)]
+#[cfg(not(target_arch = "wasm32"))]
+mod tests {
+    use super::*;
+    use crate::{init, lock, unlock};
+
+    #[test]
+    fn test_names_not_stored() {
+        crate::test_config();
+
+        pollster::block_on(async {
+            init(Some("passphrase".into())).await.expect("init failed");
+            storage::save_content("work::github::login".into(), "secret".into())
+                .await
+                .expect("save_content failed");
+
+            let keys = storage::with_backend(|backend| backend.list_keys())
+                .await
+                .expect("list_keys failed");
+            assert!(keys.iter().all(|key| !key.contains("github")));
+            assert_eq!(
+                storage::list_all_keys()
+                    .await
+                    .expect("list_all_keys failed"),
+                vec!["work::github::login".to_string()]
+            );
+
+            storage::delete_by_name("work::github::login".into())
+                .await
+                .expect("delete_by_name failed");
+            assert!(storage::list_all_keys()
+                .await
+                .expect("list_all_keys failed")
+                .is_empty());
+            assert!(storage::get_content("work::github::login").await.is_err());
+        });
+    }
+
+    #[test]
+    fn test_plaintext_names_migration() {
+        crate::test_config();
+
+        pollster::block_on(async {
+            init(Some("passphrase".into())).await.expect("init failed");
+            // An entry as written by versions before the name index
+            let encrypted = crate::encrypt_data(
+                b"secret",
+                &crate::key().expect("key failed"),
+                "work::legacy::login",
+            )
+            .expect("encrypt failed");
+            storage::with_backend(|backend| backend.set("work::legacy::login", encrypted))
+                .await
+                .expect("set failed");
+            lock().expect("lock failed");
+
+            unlock("passphrase".into(), None)
+                .await
+                .expect("unlock failed");
+            let keys = storage::with_backend(|backend| backend.list_keys())
+                .await
+                .expect("list_keys failed");
+            assert!(!keys.contains(&"work::legacy::login".to_string()));
+            assert_eq!(
+                storage::list_all_keys()
+                    .await
+                    .expect("list_all_keys failed"),
+                vec!["work::legacy::login".to_string()]
+            );
+            let content = storage::get_content("work::legacy::login")
+                .await
+                .expect("get_content failed");
+            assert_eq!(content.as_str(), "secret");
+        });
+    }
+}
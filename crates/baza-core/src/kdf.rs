@@ -0,0 +1,101 @@
+use crate::{error::Error, storage, BazaR, KDF_KEY};
+use argon2::{Algorithm, Argon2, Params, Version};
+use exn::ResultExt;
+use rand::RngExt;
+use serde::{Deserialize, Serialize};
+use zeroize::Zeroizing;
+
+const SALT_LEN: usize = 16;
+const KEY_LEN: usize = 32;
+
+// OWASP recommended minimum for Argon2id: 19 MiB, 2 iterations, 1 lane
+const DEFAULT_M_COST: u32 = 19 * 1024;
+const DEFAULT_T_COST: u32 = 2;
+const DEFAULT_P_COST: u32 = 1;
+
+/// Argon2id parameters of a key slot, stored unencrypted under the system box.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct KdfParams {
+    pub(crate) salt: Vec<u8>,
+    pub(crate) m_cost: u32,
+    pub(crate) t_cost: u32,
+    pub(crate) p_cost: u32,
+}
+
+impl KdfParams {
+    /// Fresh parameters with a random salt.
+    pub(crate) fn generate() -> Self {
+        let mut salt = vec![0u8; SALT_LEN];
+        rand::rng().fill(salt.as_mut_slice());
+        Self {
+            salt,
+            m_cost: DEFAULT_M_COST,
+            t_cost: DEFAULT_T_COST,
+            p_cost: DEFAULT_P_COST,
+        }
+    }
+
+    /// Derive the vault key from the passphrase and, if given, the keyfile digest.
+    pub(crate) fn derive(
+        &self,
+        passphrase: &str,
+        keyfile: Option<&[u8]>,
+    ) -> BazaR<Zeroizing<[u8; KEY_LEN]>> {
+        let params = Params::new(self.m_cost, self.t_cost, self.p_cost, Some(KEY_LEN))
+            .map_err(|e| exn::Exn::new(Error::Message(format!("Invalid KDF parameters: {e}"))))?;
+        let argon2 = match keyfile {
+            Some(secret) => {
+                Argon2::new_with_secret(secret, Algorithm::Argon2id, Version::V0x13, params)
+                    .map_err(|e| exn::Exn::new(Error::Message(format!("Invalid keyfile: {e}"))))?
+            }
+            None => Argon2::new(Algorithm::Argon2id, Version::V0x13, params),
+        };
+
+        let mut key = Zeroizing::new([0u8; KEY_LEN]);
+        argon2
+            .hash_password_into(passphrase.as_bytes(), &self.salt, key.as_mut())
+            .map_err(|e| exn::Exn::new(Error::Message(format!("Failed to derive key: {e}"))))?;
+        Ok(key)
+    }
+
+    /// Read the parameters of vaults created before key slots, `None` for vaults created
+    /// before Argon2id.
+    pub(crate) async fn load() -> BazaR<Option<Self>> {
+        if !storage::contains(KDF_KEY).await? {
+            return Ok(None);
+        }
+        let raw = storage::with_backend(|backend| backend.get(KDF_KEY)).await?;
+        let params = postcard::from_bytes(&raw)
+            .or_raise(|| Error::Message("Failed to parse KDF parameters".into()))?;
+        Ok(Some(params))
+    }
+}
+
+#[cfg(test)]
+#[cfg(not(target_arch = "wasm32"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_derive() {
+        let params = KdfParams::generate();
+        let key = params.derive("passphrase", None).expect("derive failed");
+        assert_eq!(
+            key,
+            params.derive("passphrase", None).expect("derive failed")
+        );
+        assert_ne!(key, params.derive("other", None).expect("derive failed"));
+        assert_ne!(
+            key,
+            params
+                .derive("passphrase", Some(b"keyfile"))
+                .expect("derive failed")
+        );
+        assert_ne!(
+            key,
+            KdfParams::generate()
+                .derive("passphrase", None)
+                .expect("derive failed")
+        );
+    }
+}
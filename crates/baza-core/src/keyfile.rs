@@ -0,0 +1,112 @@
+//! Keyfile as a second unlock factor: a digest of its contents is mixed into the
+//! passphrase key derivation as the Argon2id secret.
+
+use crate::{error::Error, BazaR, Config};
+use exn::ResultExt;
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+#[cfg(not(test))]
+use std::sync::OnceLock;
+use zeroize::Zeroizing;
+
+#[cfg(not(test))]
+static KEYFILE: OnceLock<Mutex<Option<PathBuf>>> = OnceLock::new();
+
+/// Keyfile set for the process, or for the calling thread under test.
+fn keyfile() -> &'static Mutex<Option<PathBuf>> {
+    #[cfg(not(test))]
+    {
+        KEYFILE.get_or_init(|| Mutex::new(None))
+    }
+    #[cfg(test)]
+    {
+        thread_local! {
+            static KEYFILE: &'static Mutex<Option<PathBuf>> = Box::leak(Box::default());
+        }
+        KEYFILE.with(|keyfile| *keyfile)
+    }
+}
+
+/// Use the keyfile at `path` for this session instead of `main.keyfile` from the config.
+pub fn set(path: Option<PathBuf>) -> BazaR<()> {
+    let mut guard = keyfile()
+        .lock()
+        .map_err(|_| Error::Message("Failed to lock keyfile mutex".into()))?;
+    *guard = path;
+    Ok(())
+}
+
+/// Keyfile in use: the one set for this session, or `main.keyfile` from the config.
+pub fn path() -> BazaR<Option<PathBuf>> {
+    let guard = keyfile()
+        .lock()
+        .map_err(|_| Error::Message("Failed to lock keyfile mutex".into()))?;
+    Ok(guard
+        .clone()
+        .or_else(|| Config::get().main.keyfile.as_ref().map(PathBuf::from)))
+}
+
+/// Digest of the keyfile at `path`.
+pub(crate) fn read(path: &Path) -> BazaR<Zeroizing<Vec<u8>>> {
+    let contents = Zeroizing::new(
+        std::fs::read(path)
+            .or_raise(|| Error::Message(format!("Failed to read keyfile '{}'", path.display())))?,
+    );
+    if contents.is_empty() {
+        exn::bail!(Error::Message(format!(
+            "Keyfile '{}' is empty",
+            path.display()
+        )));
+    }
+    Ok(Zeroizing::new(Sha256::digest(contents.as_slice()).to_vec()))
+}
+
+/// Digest of the configured keyfile, `None` when no keyfile is configured.
+pub(crate) fn secret() -> BazaR<Option<Zeroizing<Vec<u8>>>> {
+    path()?.map(|path| read(&path)).transpose()
+}
+
+#[cfg(test)]
+#[cfg(not(target_arch = "wasm32"))]
+mod tests {
+    use super::*;
+    use crate::{init, lock, storage, unlock};
+
+    #[test]
+    fn test_keyfile() {
+        crate::test_config();
+
+        let keyfile = std::path::Path::new(crate::test_datadir()).join("keyfile");
+        let other = std::path::Path::new(crate::test_datadir()).join("other");
+        std::fs::write(&keyfile, b"something you have").expect("Failed to write keyfile");
+        std::fs::write(&other, b"something else").expect("Failed to write keyfile");
+
+        pollster::block_on(async {
+            set(Some(keyfile.clone())).expect("set failed");
+            init(Some("passphrase".into())).await.expect("init failed");
+            storage::save_content("test::keyfile::login".into(), "secret".into())
+                .await
+                .expect("save_content failed");
+            lock().expect("lock failed");
+
+            set(None).expect("set failed");
+            assert!(unlock("passphrase".into(), None).await.is_err());
+
+            set(Some(other)).expect("set failed");
+            let err = unlock("passphrase".into(), None)
+                .await
+                .expect_err("unlock with a wrong keyfile succeeded");
+            assert!(matches!(*err, Error::InvalidPassphrase));
+
+            set(Some(keyfile)).expect("set failed");
+            unlock("passphrase".into(), None)
+                .await
+                .expect("unlock failed");
+            let content = storage::get_content("test::keyfile::login")
+                .await
+                .expect("get_content failed");
+            assert_eq!(content.as_str(), "secret");
+        });
+    }
+}
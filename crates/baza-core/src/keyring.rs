@@ -0,0 +1,287 @@
+//! Envelope encryption: entries are encrypted with a random data key, which is
+//! stored under the system box wrapped by the passphrase-derived key of every key slot.
+
+use crate::{
+    error::Error,
+    index,
+    kdf::KdfParams,
+    slot::{Slot, DEFAULT_SLOT},
+    storage::{self, Change},
+    BazaR, DATA_KEY, INDEX_KEY, KDF_KEY, KEYFILE_KEY,
+};
+use exn::ResultExt;
+use rand::RngExt;
+use std::collections::HashMap;
+use zeroize::Zeroizing;
+
+const KEY_LEN: usize = 32;
+
+/// Fresh random data key.
+pub(crate) fn generate() -> Zeroizing<Vec<u8>> {
+    let mut key = Zeroizing::new(vec![0u8; KEY_LEN]);
+    rand::rng().fill(key.as_mut_slice());
+    key
+}
+
+pub(crate) fn unwrap(wrapped: &[u8], kek: &[u8]) -> BazaR<Zeroizing<Vec<u8>>> {
+    crate::decrypt_data(wrapped, kek, DATA_KEY).or_raise(|| Error::InvalidPassphrase)
+}
+
+/// Wrapped data key of vaults created before key slots.
+async fn load() -> BazaR<Option<Vec<u8>>> {
+    if !storage::contains(DATA_KEY).await? {
+        return Ok(None);
+    }
+    let wrapped = storage::with_backend(|backend| backend.get(DATA_KEY)).await?;
+    Ok(Some(wrapped))
+}
+
+/// Move the single wrapped key of vaults created before key slots into the default slot.
+///
+/// The wrapped key is moved as is, no passphrase is needed.
+pub(crate) async fn upgrade() -> BazaR<()> {
+    let (Some(kdf), Some(wrapped)) = (KdfParams::load().await?, load().await?) else {
+        return Ok(());
+    };
+    tracing::info!("Moving the vault key into the '{DEFAULT_SLOT}' key slot");
+    let keyfile = storage::contains(KEYFILE_KEY).await?;
+    let slot = Slot {
+        kdf,
+        keyfile,
+        wrapped,
+    };
+    let changes = vec![
+        slot.store(DEFAULT_SLOT)?,
+        Change::Remove(KDF_KEY.to_string()),
+        Change::Remove(DATA_KEY.to_string()),
+        Change::Remove(KEYFILE_KEY.to_string()),
+    ];
+    storage::with_backend(|backend| backend.apply(changes)).await
+}
+
+/// One-time migration of a vault whose entries are encrypted directly with `old_key`.
+///
+/// Every entry is re-encrypted with a new data key and written together with the
+/// default key slot in a single batch, so the vault is either fully migrated or untouched.
+pub(crate) async fn migrate(
+    old_key: &[u8],
+    passphrase: &str,
+    keyfile: Option<&[u8]>,
+) -> BazaR<Zeroizing<Vec<u8>>> {
+    tracing::warn!("Vault has no wrapped data key, migrating to envelope encryption");
+    crate::verify_key(old_key, false).await?;
+
+    let data_key = generate();
+    let mut changes = vec![
+        Slot::new(&data_key, passphrase, keyfile)?.store(DEFAULT_SLOT)?,
+        Change::Remove(KDF_KEY.to_string()),
+    ];
+    changes.extend(reencrypt(old_key, &data_key).await?);
+    storage::with_backend(|backend| backend.apply(changes)).await?;
+
+    tracing::info!("Vault migrated to envelope encryption");
+    Ok(data_key)
+}
+
+/// Every encrypted entry of the vault, decrypted with `old_key` and encrypted with `new_key`.
+///
+/// Bundle identifiers depend on the data key, so bundles and the records kept next to
+/// them are also moved to their new identifier.
+pub(crate) async fn reencrypt(old_key: &[u8], new_key: &[u8]) -> BazaR<Vec<Change>> {
+    let names = index::load(old_key).await?;
+    let mut old_names = HashMap::with_capacity(names.len());
+    let mut new_ids = HashMap::with_capacity(names.len());
+    for name in &names {
+        let id = index::id(name, old_key)?;
+        new_ids.insert(id.clone(), index::id(name, new_key)?);
+        old_names.insert(id, name.clone());
+    }
+
+    let keys: Vec<String> = storage::with_backend(|backend| backend.list_keys())
+        .await?
+        .into_iter()
+        .filter(|key| !crate::is_raw_key(key) && key != INDEX_KEY)
+        .collect();
+    let values = storage::with_backend(|backend| backend.get_many(&keys)).await?;
+    let mut changes = Vec::with_capacity(keys.len() + 1);
+    for (key, encrypted) in keys.into_iter().zip(values) {
+        let name = index::entry_name(&key, &old_names);
+        let id = match index::split_record(&key) {
+            Some((prefix, id)) => new_ids.get(id).map(|id| index::record_key(prefix, id)),
+            None => new_ids.get(&key).cloned(),
+        }
+        .unwrap_or_else(|| key.clone());
+        let plaintext = crate::decrypt_data(&encrypted, old_key, &name)
+            .or_raise(|| Error::Message(format!("Failed to decrypt '{name}'")))?;
+        let encrypted = crate::encrypt_data(&plaintext, new_key, &name)?;
+        if id != key {
+            changes.push(Change::Remove(key));
+        }
+        changes.push(Change::Set(id, encrypted));
+    }
+    changes.push(Change::Set(
+        INDEX_KEY.to_string(),
+        index::seal(&names, new_key)?,
+    ));
+    Ok(changes)
+}
+
+#[cfg(test)]
+#[cfg(not(target_arch = "wasm32"))]
+mod tests {
+    use super::*;
+    use crate::{change_passphrase, init, lock, rekey, unlock};
+
+    #[test]
+    fn test_legacy_migration() {
+        crate::test_config();
+
+        pollster::block_on(async {
+            // A vault as written by versions before Argon2id
+            storage::initialize().expect("Failed to initialize storage");
+            let legacy_key = crate::as_hash("legacy");
+            let encrypted = crate::encrypt_data(b"secret", &legacy_key, "test::legacy::login")
+                .expect("encrypt failed");
+            storage::with_backend(|backend| backend.set("test::legacy::login", encrypted))
+                .await
+                .expect("set failed");
+
+            assert!(unlock("wrong".into(), None).await.is_err());
+            assert!(KdfParams::load().await.expect("load failed").is_none());
+            assert!(load().await.expect("load failed").is_none());
+
+            unlock("legacy".into(), None).await.expect("unlock failed");
+            assert!(KdfParams::load().await.expect("load failed").is_none());
+            let slots = crate::slot::load_all().await.expect("load_all failed");
+            assert_eq!(slots.len(), 1);
+            let content = storage::get_content("test::legacy::login")
+                .await
+                .expect("get_content failed");
+            assert_eq!(content.as_str(), "secret");
+
+            lock().expect("lock failed");
+            unlock("legacy".into(), None).await.expect("unlock failed");
+            let content = storage::get_content("test::legacy::login")
+                .await
+                .expect("get_content failed");
+            assert_eq!(content.as_str(), "secret");
+        });
+    }
+
+    #[test]
+    fn test_empty_legacy_vault() {
+        crate::test_config();
+
+        pollster::block_on(async {
+            // Nothing encrypted to check the passphrase against
+            storage::initialize().expect("Failed to initialize storage");
+            assert!(unlock("any".into(), None).await.is_err());
+            assert!(crate::slot::load_all()
+                .await
+                .expect("load_all failed")
+                .is_empty());
+            assert!(!storage::contains(crate::VERIFIER_KEY)
+                .await
+                .expect("contains failed"));
+        });
+    }
+
+    #[test]
+    fn test_slot_upgrade() {
+        crate::test_config();
+
+        pollster::block_on(async {
+            // A vault as written by versions before key slots
+            storage::initialize().expect("Failed to initialize storage");
+            let data_key = generate();
+            let kdf = KdfParams::generate();
+            let kek = kdf.derive("passphrase", None).expect("derive failed");
+            let wrapped =
+                crate::encrypt_data(&data_key, kek.as_slice(), DATA_KEY).expect("encrypt failed");
+            let changes = vec![
+                Change::Set(
+                    KDF_KEY.into(),
+                    postcard::to_stdvec(&kdf).expect("serialize failed"),
+                ),
+                Change::Set(DATA_KEY.into(), wrapped),
+            ];
+            storage::with_backend(|backend| backend.apply(changes))
+                .await
+                .expect("apply failed");
+
+            unlock("passphrase".into(), None)
+                .await
+                .expect("unlock failed");
+            assert_eq!(*crate::key().expect("key failed"), *data_key);
+            assert!(load().await.expect("load failed").is_none());
+            assert!(KdfParams::load().await.expect("load failed").is_none());
+            let slots = crate::slot::load_all().await.expect("load_all failed");
+            assert_eq!(slots.len(), 1);
+            assert_eq!(slots[0].0, DEFAULT_SLOT);
+        });
+    }
+
+    #[test]
+    fn test_change_passphrase() {
+        crate::test_config();
+
+        pollster::block_on(async {
+            init(Some("old".into())).await.expect("init failed");
+            storage::save_content("test::passwd::login".into(), "secret".into())
+                .await
+                .expect("save_content failed");
+
+            change_passphrase("new".into())
+                .await
+                .expect("change_passphrase failed");
+            lock().expect("lock failed");
+
+            assert!(unlock("old".into(), None).await.is_err());
+            unlock("new".into(), None).await.expect("unlock failed");
+            let content = storage::get_content("test::passwd::login")
+                .await
+                .expect("get_content failed");
+            assert_eq!(content.as_str(), "secret");
+        });
+    }
+
+    #[test]
+    fn test_rekey() {
+        crate::test_config();
+
+        pollster::block_on(async {
+            init(Some("passphrase".into())).await.expect("init failed");
+            storage::save_content("test::rekey::login".into(), "secret".into())
+                .await
+                .expect("save_content failed");
+            let data_key = crate::key().expect("key failed");
+            let id = index::id("test::rekey::login", &data_key).expect("id failed");
+            let encrypted = storage::with_backend(|backend| backend.get(&id))
+                .await
+                .expect("get failed");
+
+            assert!(rekey("wrong".into()).await.is_err());
+            let count = rekey("passphrase".into()).await.expect("rekey failed");
+            // Key-check record, seen unlock attempts, the entry, its metadata and the name index
+            assert_eq!(count, 5);
+            assert!(!storage::contains(&id).await.expect("contains failed"));
+
+            assert_ne!(data_key, crate::key().expect("key failed"));
+            let new_key = crate::key().expect("key failed");
+            assert!(crate::decrypt_data(&encrypted, &new_key, "test::rekey::login").is_err());
+            let content = storage::get_content("test::rekey::login")
+                .await
+                .expect("get_content failed");
+            assert_eq!(content.as_str(), "secret");
+
+            lock().expect("lock failed");
+            unlock("passphrase".into(), None)
+                .await
+                .expect("unlock failed");
+            let content = storage::get_content("test::rekey::login")
+                .await
+                .expect("get_content failed");
+            assert_eq!(content.as_str(), "secret");
+        });
+    }
+}
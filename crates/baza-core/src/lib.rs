@@ -4,13 +4,12 @@
 //!
 
 use crate::prelude::*;
-use aes_gcm::aead::{Aead, KeyInit};
-use aes_gcm::{Aes256Gcm, Nonce};
 #[cfg(not(target_arch = "wasm32"))]
 use core::str;
 use exn::ResultExt;
 use rand::RngExt;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 #[cfg(target_arch = "wasm32")]
 use std::fs;
 #[cfg(not(target_arch = "wasm32"))]
@@ -20,22 +19,36 @@ use std::io;
 #[cfg(not(target_arch = "wasm32"))]
 use std::io;
 use std::ops::Not;
-use std::path::Path;
-use std::sync::{Arc, Mutex, OnceLock};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
 use tracing::instrument;
 use uuid::Uuid;
+pub use zeroize::Zeroizing;
 
 pub mod r#box;
 pub mod bundle;
+pub mod cipher;
 pub mod container;
+pub mod doctor;
 pub mod dump;
 pub mod error;
+pub mod history;
+pub(crate) mod index;
+pub(crate) mod kdf;
+pub mod keyfile;
+pub(crate) mod keyring;
+pub mod meta;
 pub mod prelude;
+pub mod recovery;
 #[cfg(all(not(target_arch = "wasm32"), feature = "s3"))]
 pub mod s3;
+pub mod slot;
 pub mod storage;
+pub mod throttle;
 pub mod totp;
+pub mod trash;
 pub mod utils;
+pub mod vault;
 
 pub const SYSTEM_BOX: &str = "__baza__";
 pub const TOTP_KEY: &str = "__baza__::auth::totp";
@@ -43,8 +56,23 @@ pub const TTL_SECONDS: u64 = 15;
 pub const PASSWORD_DEFAULT_LEN: usize = 12;
 pub const DEFAULT_AUTHOR: &str = "Baza";
 pub const TOTP_UUID_KEY: &str = "__baza__::auth::totp::uuid";
+pub const KDF_KEY: &str = "__baza__::auth::kdf";
+pub const DATA_KEY: &str = "__baza__::auth::key";
+pub const VERIFIER_KEY: &str = "__baza__::auth::verifier";
+pub const INDEX_KEY: &str = "__baza__::index";
+pub const KEYFILE_KEY: &str = "__baza__::auth::keyfile";
+pub const SLOT_PREFIX: &str = "__baza__::auth::slot::";
+pub const ATTEMPTS_KEY: &str = "__baza__::auth::attempts";
+pub const ATTEMPTS_SEEN_KEY: &str = "__baza__::auth::attempts::seen";
+pub const BACKUP_KEY: &str = "__baza__::restore::backup";
+pub const HISTORY_PREFIX: &str = "__baza__::history::";
+pub const META_PREFIX: &str = "__baza__::meta::";
+pub const TRASH_PREFIX: &str = "__baza__::trash::";
+pub const QUARANTINE_PREFIX: &str = "__baza__::quarantine::";
+const VERIFIER_PLAINTEXT: &str = "baza";
 pub static CONFIG: OnceLock<Config> = OnceLock::new();
-static SESSION_KEY: OnceLock<Mutex<Option<Vec<u8>>>> = OnceLock::new();
+#[cfg(not(test))]
+static SESSION: OnceLock<Mutex<Option<Session>>> = OnceLock::new();
 pub type BazaR<T> = Result<T, exn::Exn<error::Error>>;
 
 pub fn is_system_key(key: &str) -> bool {
@@ -52,6 +80,18 @@ pub fn is_system_key(key: &str) -> bool {
     key.starts_with(&prefix)
 }
 
+/// System entries stored unencrypted, readable before the vault is unlocked.
+pub(crate) fn is_raw_key(key: &str) -> bool {
+    key == TOTP_UUID_KEY
+        || key == KDF_KEY
+        || key == DATA_KEY
+        || key == KEYFILE_KEY
+        || key == ATTEMPTS_KEY
+        || key == BACKUP_KEY
+        || key.starts_with(SLOT_PREFIX)
+        || key.starts_with(QUARANTINE_PREFIX)
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Config {
     pub main: MainConfig,
@@ -59,6 +99,15 @@ pub struct Config {
     #[cfg(feature = "s3")]
     #[serde(skip_serializing_if = "Option::is_none")]
     pub s3: Option<S3Config>,
+    /// Named vaults, each with its own data directory and storage.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub vaults: BTreeMap<String, vault::VaultConfig>,
+    /// Named vault `main` and `storage` were taken from, `None` for the default one.
+    #[serde(skip)]
+    pub selected: Option<String>,
+    /// File the config was read from.
+    #[serde(skip)]
+    pub path: Option<PathBuf>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -66,11 +115,57 @@ pub struct MainConfig {
     pub datadir: String,
     pub box_delimiter: String,
     pub bundle_delimiter: String,
+    /// Cipher used to encrypt new entries.
+    #[serde(default)]
+    pub cipher: cipher::Cipher,
+    /// Keyfile required to unlock the vault, mixed into the key derivation.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keyfile: Option<String>,
+    /// Refuse to unlock for a while after too many failed attempts.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lockout: Option<throttle::LockoutConfig>,
+    /// Previous revisions kept per bundle, 0 disables history.
+    #[serde(default = "history::default_limit")]
+    pub history: usize,
+    /// Author recorded in the metadata of new bundles.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub author: Option<String>,
+    /// Days deleted bundles stay in the trash, 0 deletes them right away.
+    #[serde(default = "trash::default_days")]
+    pub trash_days: u64,
+    /// Vault used when `--vault` is not given, the top-level one if unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vault: Option<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct StorageConfig {
     pub r#type: Type,
+    /// Directory of the `files` and `git` backends, `data/files` or `data/git` in the
+    /// data directory by default.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    /// Repository `baza git push` and `baza git pull` sync with.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub remote: Option<String>,
+    /// Compact the storage after `baza restore`.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub auto_compact: bool,
+    /// How the `files` and `git` backends name bundle files, by identifier if unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub layout: Option<Layout>,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            r#type: Type::Redb,
+            path: None,
+            remote: None,
+            auto_compact: false,
+            layout: None,
+        }
+    }
 }
 
 #[cfg(feature = "s3")]
@@ -89,10 +184,77 @@ pub struct S3Config {
     pub path_style: Option<bool>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// File names of bundles with the `files` and `git` backends.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Layout {
+    /// Named after the bundle identifier, revealing no bundle names.
+    #[serde(rename = "ids")]
+    Ids,
+    /// At a path mirroring the bundle name, such as `work/github/login.baza`.
+    #[serde(rename = "paths")]
+    Paths,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub enum Type {
     #[serde(rename = "redb", alias = "Redb")]
     Redb,
+    /// One encrypted file per entry, in a directory that can be versioned with git.
+    #[serde(rename = "files")]
+    Files,
+    /// The `files` layout in a git repository, with a commit per change.
+    #[serde(rename = "git")]
+    Git,
+    /// One row per entry in an SQLite database.
+    #[serde(rename = "sqlite")]
+    Sqlite,
+    /// Entries in memory only, gone when the process exits.
+    #[serde(rename = "memory")]
+    Memory,
+}
+
+impl Type {
+    const ALL: [Type; 5] = [
+        Type::Redb,
+        Type::Files,
+        Type::Git,
+        Type::Sqlite,
+        Type::Memory,
+    ];
+
+    /// Name of the backend in `storage.type`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Type::Redb => "redb",
+            Type::Files => "files",
+            Type::Git => "git",
+            Type::Sqlite => "sqlite",
+            Type::Memory => "memory",
+        }
+    }
+}
+
+impl std::fmt::Display for Type {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl std::str::FromStr for Type {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Type::ALL
+            .into_iter()
+            .find(|r#type| r#type.name() == s)
+            .ok_or_else(|| {
+                let names: Vec<_> = Type::ALL.iter().map(Type::name).collect();
+                format!(
+                    "Unknown storage type '{s}', expected one of: {}",
+                    names.join(", ")
+                )
+            })
+    }
 }
 
 impl Default for Config {
@@ -112,10 +274,20 @@ impl Default for Config {
                 datadir,
                 box_delimiter: "::".into(),
                 bundle_delimiter: ".".into(),
+                cipher: cipher::Cipher::default(),
+                keyfile: None,
+                lockout: None,
+                history: history::default_limit(),
+                author: None,
+                trash_days: trash::default_days(),
+                vault: None,
             },
-            storage: StorageConfig { r#type: Type::Redb },
+            storage: StorageConfig::default(),
             #[cfg(feature = "s3")]
             s3: None,
+            vaults: BTreeMap::new(),
+            selected: None,
+            path: None,
         }
     }
 }
@@ -140,15 +312,15 @@ impl Config {
     }
 
     pub fn build(path: &Path) -> BazaR<()> {
+        Self::build_for(path, None)
+    }
+
+    /// Build the config of the `vault` named vault, `main.vault` if `None`.
+    pub fn build_for(path: &Path, vault: Option<&str>) -> BazaR<()> {
         let config = if path.exists() {
-            let config = fs::read_to_string(path)
-                .or_raise(|| error::Error::Message("Failed to read config file".into()))?;
-            toml::from_str(&config)
-                .or_raise(|| error::Error::Message("Failed to parse config file".into()))?
+            Config::read(path)?
         } else {
             let config = Config::default();
-            let config_str = toml::to_string(&config)
-                .or_raise(|| error::Error::Message("Failed to serialize default config".into()))?;
             let parent = path.parent().ok_or_else(|| {
                 exn::Exn::new(error::Error::Message(
                     "Failed to determine config parent directory".into(),
@@ -156,18 +328,62 @@ impl Config {
             })?;
             fs::create_dir_all(parent)
                 .or_raise(|| error::Error::Message("Failed to create config directory".into()))?;
-            fs::write(path, config_str)
-                .or_raise(|| error::Error::Message("Failed to write config file".into()))?;
+            config.write(path)?;
             config
         };
 
+        let mut config = config.select(vault)?;
+        config.path = Some(path.to_path_buf());
         let _ = CONFIG.set(config);
         Ok(())
     }
+
+    /// Read the config file as is, without selecting a vault.
+    pub fn read(path: &Path) -> BazaR<Config> {
+        let config = fs::read_to_string(path)
+            .or_raise(|| error::Error::Message("Failed to read config file".into()))?;
+        toml::from_str(&config)
+            .or_raise(|| error::Error::Message("Failed to parse config file".into()))
+    }
+
+    pub fn write(&self, path: &Path) -> BazaR<()> {
+        let config_str = toml::to_string(self)
+            .or_raise(|| error::Error::Message("Failed to serialize config".into()))?;
+        fs::write(path, config_str)
+            .or_raise(|| error::Error::Message("Failed to write config file".into()))
+    }
+
+    /// Use the data directory, storage and S3 settings of the `vault` named vault,
+    /// `main.vault` if `None`.
+    pub fn select(mut self, vault: Option<&str>) -> BazaR<Config> {
+        let Some(name) = vault
+            .map(str::to_string)
+            .or_else(|| self.main.vault.clone())
+        else {
+            return Ok(self);
+        };
+        if name == vault::DEFAULT_VAULT {
+            return Ok(self);
+        }
+        let Some(selected) = self.vaults.get(&name).cloned() else {
+            exn::bail!(error::Error::Message(format!(
+                "Unknown vault '{name}', see 'baza vault list'"
+            )));
+        };
+        self.main.datadir = selected.datadir;
+        self.storage = selected.storage;
+        #[cfg(feature = "s3")]
+        {
+            self.s3 = selected.s3;
+        }
+        self.selected = Some(name);
+        Ok(self)
+    }
 }
 
+/// Generated password, wiped from memory when dropped.
 pub struct Password {
-    pub inner: std::sync::Arc<str>,
+    pub inner: Zeroizing<String>,
 }
 
 impl Default for Password {
@@ -179,7 +395,7 @@ impl Default for Password {
 impl Password {
     pub fn new(s: &str) -> Self {
         Self {
-            inner: Arc::from(s),
+            inner: Zeroizing::new(s.to_string()),
         }
     }
 
@@ -202,46 +418,121 @@ impl Password {
                 charset.chars().nth(idx).unwrap_or('a')
             })
             .collect();
-        Self::new(&password)
+        Self {
+            inner: Zeroizing::new(password),
+        }
     }
 
-    pub fn as_str(&self) -> String {
-        self.inner.to_string()
+    pub fn as_str(&self) -> &str {
+        &self.inner
     }
 }
 
-pub fn lock() -> BazaR<()> {
-    if let Some(mutex) = SESSION_KEY.get() {
-        let mut guard = mutex
-            .lock()
-            .map_err(|_| crate::error::Error::Message("Failed to lock key mutex".into()))?;
-        *guard = None;
+/// Unlocked vault: the data key and the key slot it was unwrapped with.
+struct Session {
+    key: Zeroizing<Vec<u8>>,
+    slot: String,
+}
+
+/// Session of the process, or of the calling thread under test so that tests can run
+/// in parallel.
+fn session() -> &'static Mutex<Option<Session>> {
+    #[cfg(not(test))]
+    {
+        SESSION.get_or_init(|| Mutex::new(None))
+    }
+    #[cfg(test)]
+    {
+        thread_local! {
+            static SESSION: &'static Mutex<Option<Session>> = Box::leak(Box::default());
+        }
+        SESSION.with(|session| *session)
     }
+}
+
+/// Forget the session key. Its memory is wiped when dropped.
+pub fn lock() -> BazaR<()> {
+    let mut guard = session()
+        .lock()
+        .map_err(|_| crate::error::Error::Message("Failed to lock key mutex".into()))?;
+    *guard = None;
+    Ok(())
+}
+
+fn set_session(key: Zeroizing<Vec<u8>>, slot: String) -> BazaR<()> {
+    let mut guard = session()
+        .lock()
+        .map_err(|_| crate::error::Error::Message("Failed to lock key mutex".into()))?;
+    *guard = Some(Session { key, slot });
     Ok(())
 }
 
 pub async fn unlock(passphrase: String, totp_code: Option<String>) -> BazaR<()> {
     let initialized = storage::is_initialized().await?;
     if !initialized {
-        let key_bytes = as_hash(passphrase.trim());
+        exn::bail!(crate::error::Error::Message(
+            "Vault is not initialized. Use 'baza init' to create one".into()
+        ));
+    }
 
-        let mutex = SESSION_KEY.get_or_init(|| std::sync::Mutex::new(None));
-        let mut guard = mutex
-            .lock()
-            .map_err(|_| crate::error::Error::Message("Failed to lock key mutex".into()))?;
-        *guard = Some(key_bytes.to_vec());
-        return Ok(());
+    throttle::check().await?;
+    match open(passphrase, totp_code).await {
+        Ok(()) => {
+            throttle::succeeded().await?;
+            if let Err(e) = trash::purge().await {
+                tracing::warn!("Failed to purge the trash: {e}");
+            }
+            Ok(())
+        }
+        Err(e) => {
+            if throttle::is_failure(&e) {
+                if let Err(record) = throttle::failed().await {
+                    tracing::error!("Failed to record unlock attempt: {record}");
+                }
+            }
+            Err(e)
+        }
     }
+}
 
-    let key_bytes = as_hash(passphrase.trim());
+/// Unwrap the data key with the passphrase and check the TOTP code, if enabled.
+async fn open(passphrase: String, totp_code: Option<String>) -> BazaR<()> {
+    let passphrase = Zeroizing::new(passphrase);
+    let passphrase = passphrase.trim();
+    let keyfile = keyfile::secret()?;
+    let keyfile = keyfile.as_ref().map(|secret| secret.as_slice());
+    keyring::upgrade().await?;
+    let slots = slot::load_all().await?;
+    let unwrapped = !slots.is_empty();
+    let (slot_name, key_bytes) = if unwrapped {
+        slot::open_any(slots, passphrase, keyfile)?
+    } else {
+        let key_bytes = match kdf::KdfParams::load().await? {
+            // Argon2id-derived key used directly, before envelope encryption
+            Some(params) => {
+                let kek = params.derive(passphrase, None)?;
+                keyring::migrate(kek.as_slice(), passphrase, keyfile).await?
+            }
+            // Legacy unsalted SHA-256 key
+            None => {
+                let kek = Zeroizing::new(as_hash(passphrase));
+                keyring::migrate(kek.as_slice(), passphrase, keyfile).await?
+            }
+        };
+        (slot::DEFAULT_SLOT.to_string(), key_bytes)
+    };
 
-    // Temporarily unlock by setting the SESSION_KEY so we can read the database
-    let mutex = SESSION_KEY.get_or_init(|| std::sync::Mutex::new(None));
-    {
-        let mut guard = mutex
-            .lock()
-            .map_err(|_| crate::error::Error::Message("Failed to lock key mutex".into()))?;
-        *guard = Some(key_bytes.to_vec());
+    // Temporarily unlock by setting the session so we can read the database
+    set_session(key_bytes.clone(), slot_name)?;
+
+    if let Err(e) = verify_key(&key_bytes, unwrapped).await {
+        let _ = lock();
+        return Err(e);
+    }
+
+    if let Err(e) = index::migrate(&key_bytes).await {
+        let _ = lock();
+        return Err(e);
     }
 
     // Determine if TOTP is enabled.
@@ -261,11 +552,7 @@ pub async fn unlock(passphrase: String, totp_code: Option<String>) -> BazaR<()>
             Ok(s) => s,
             Err(e) => {
                 let _ = lock();
-                if e.to_string().contains("aes") || e.to_string().contains("decrypt") {
-                    exn::bail!(crate::error::Error::Message("Invalid passphrase".into()));
-                } else {
-                    return Err(e);
-                }
+                return Err(e);
             }
         };
 
@@ -296,10 +583,7 @@ pub async fn unlock(passphrase: String, totp_code: Option<String>) -> BazaR<()>
                 .await
                 .unwrap_or_else(|_| "default".to_string());
             let _ = lock();
-            exn::bail!(crate::error::Error::Message(format!(
-                "Invalid TOTP code (ID: {})",
-                uuid
-            )));
+            exn::bail!(crate::error::Error::InvalidTotp(uuid));
         }
     }
 
@@ -307,20 +591,68 @@ pub async fn unlock(passphrase: String, totp_code: Option<String>) -> BazaR<()>
     Ok(())
 }
 
-pub(crate) fn key() -> BazaR<Vec<u8>> {
-    let mutex = SESSION_KEY.get_or_init(|| std::sync::Mutex::new(None));
-    let guard = mutex
+/// Check the key against the key-check record, so a wrong passphrase fails right at unlock.
+///
+/// `unwrapped` tells the key came out of a key slot, whose authenticated unwrap already
+/// proves the passphrase when the vault has nothing else to check it against.
+pub(crate) async fn verify_key(key: &[u8], unwrapped: bool) -> BazaR<()> {
+    if !storage::contains(VERIFIER_KEY).await? {
+        // Vaults created before the key-check record: the index or any entry stored under
+        // its name proves the key, those stored under an id are sealed with a name we lack
+        let names = storage::with_backend(|backend| backend.list_keys()).await?;
+        let name = names.iter().find(|name| *name == INDEX_KEY).or_else(|| {
+            names
+                .iter()
+                .find(|name| !is_raw_key(name) && !index::is_id(name))
+        });
+        match name {
+            Some(name) => {
+                let encrypted = storage::with_backend(|backend| backend.get(name)).await?;
+                decrypt_data(&encrypted, key, name)
+                    .or_raise(|| error::Error::InvalidPassphrase)?;
+            }
+            // A record written now would accept whatever passphrase comes first
+            None if !unwrapped => exn::bail!(error::Error::Message(
+                "The vault has nothing to check the passphrase against, run 'baza init' to start over".into()
+            )),
+            None => {}
+        }
+        tracing::info!("Writing key-check record");
+        let encrypted = encrypt_data(VERIFIER_PLAINTEXT.as_bytes(), key, VERIFIER_KEY)?;
+        return storage::with_backend(|backend| backend.set(VERIFIER_KEY, encrypted)).await;
+    }
+
+    let encrypted = storage::with_backend(|backend| backend.get(VERIFIER_KEY)).await?;
+    let plaintext =
+        decrypt_data(&encrypted, key, VERIFIER_KEY).or_raise(|| error::Error::InvalidPassphrase)?;
+    if plaintext.as_slice() != VERIFIER_PLAINTEXT.as_bytes() {
+        exn::bail!(error::Error::InvalidPassphrase);
+    }
+    Ok(())
+}
+
+fn with_session<R>(f: impl FnOnce(&Session) -> R) -> BazaR<R> {
+    let guard = session()
         .lock()
         .map_err(|_| crate::error::Error::Message("Failed to lock key mutex".into()))?;
 
     match &*guard {
-        Some(k) => Ok(k.clone()),
+        Some(session) => Ok(f(session)),
         None => exn::bail!(crate::error::Error::Message(
             "Vault is locked. Use '--passphrase' or 'BAZA_PASSPHRASE' env var".into()
         )),
     }
 }
 
+pub(crate) fn key() -> BazaR<Zeroizing<Vec<u8>>> {
+    with_session(|session| session.key.clone())
+}
+
+/// Name of the key slot the vault was unlocked with.
+pub(crate) fn slot() -> BazaR<String> {
+    with_session(|session| session.slot.clone())
+}
+
 #[cfg(target_arch = "wasm32")]
 pub fn cleanup_tmp_folder() -> BazaR<()> {
     Ok(())
@@ -341,6 +673,8 @@ pub async fn init(passphrase: Option<String>) -> BazaR<String> {
             .or_raise(|| error::Error::Message("Failed to create data directory".into()))?;
     }
 
+    let keyfile = keyfile::secret()?;
+
     crate::m(
         "  [+] Initializing database storage...",
         crate::MessageType::Clean,
@@ -354,39 +688,107 @@ pub async fn init(passphrase: Option<String>) -> BazaR<String> {
     );
     let passphrase = passphrase.unwrap_or_else(|| Uuid::new_v4().hyphenated().to_string());
 
-    self::unlock(passphrase.clone(), None).await?;
+    crate::m(
+        "  [+] Generating data key and deriving passphrase key (Argon2id)...",
+        crate::MessageType::Clean,
+    );
+    if keyfile.is_some() {
+        crate::m(
+            "  [+] Mixing keyfile into the passphrase key...",
+            crate::MessageType::Clean,
+        );
+    }
+    let data_key = keyring::generate();
+    let keyfile = keyfile.as_ref().map(|secret| secret.as_slice());
+    let mut changes = vec![
+        slot::Slot::new(&data_key, passphrase.trim(), keyfile)?.store(slot::DEFAULT_SLOT)?,
+        storage::Change::Set(
+            VERIFIER_KEY.to_string(),
+            encrypt_data(VERIFIER_PLAINTEXT.as_bytes(), &data_key, VERIFIER_KEY)?,
+        ),
+    ];
+    changes.extend(throttle::initial(&data_key)?);
+    storage::with_backend(|backend| backend.apply(changes)).await?;
+    storage::checkpoint("Initialize the vault".into()).await?;
+    set_session(data_key, slot::DEFAULT_SLOT.to_string())?;
 
     Ok(passphrase)
 }
 
-pub(crate) fn encrypt_data(plaintext: &[u8], key: &[u8]) -> BazaR<Vec<u8>> {
-    let cipher = Aes256Gcm::new_from_slice(key)
-        .or_raise(|| error::Error::Message("Failed to initialize cipher".into()))?;
-    let mut nonce_bytes = [0u8; 12];
-    rand::rng().fill(&mut nonce_bytes);
-    let nonce = Nonce::from_slice(&nonce_bytes);
-    let ciphertext = cipher
-        .encrypt(nonce, plaintext)
-        .or_raise(|| error::Error::Message("Failed to encrypt data".into()))?;
-    Ok([nonce_bytes.as_slice(), &ciphertext].concat())
+/// Replace the data key and re-encrypt every entry with it, in a single transaction.
+///
+/// The passphrase is checked against the key slot the vault was unlocked with and stays
+/// the same. Other key slots wrap the old key and are removed, they have to be added again.
+/// Recovery shares hold the old key and stop working, and the backup kept by the last
+/// restore is dropped so that undoing it cannot bring the old key back.
+/// Returns the number of re-encrypted entries.
+pub async fn rekey(passphrase: String) -> BazaR<usize> {
+    let passphrase = Zeroizing::new(passphrase);
+    let passphrase = passphrase.trim();
+    let name = slot()?;
+    let current = slot::load(&name).await?;
+    let keyfile = keyfile::secret()?;
+    let keyfile = keyfile
+        .as_ref()
+        .filter(|_| current.keyfile)
+        .map(|secret| secret.as_slice());
+    let old_key = current.open(passphrase, keyfile)?;
+
+    let new_key = keyring::generate();
+    let reencrypted = keyring::reencrypt(&old_key, &new_key).await?;
+    let count = reencrypted
+        .iter()
+        .filter(|change| matches!(change, storage::Change::Set(..)))
+        .count();
+    let mut changes = vec![slot::Slot::new(&new_key, passphrase, keyfile)?.store(&name)?];
+    for (other, _) in slot::load_all().await? {
+        if other != name {
+            tracing::warn!("Key slot '{other}' wraps the old key and is removed, add it again");
+            changes.push(storage::Change::Remove(slot::storage_key(&other)));
+        }
+    }
+    changes.extend(reencrypted);
+    changes.push(storage::Change::Remove(BACKUP_KEY.to_string()));
+    storage::with_backend(|backend| backend.apply(changes)).await?;
+    storage::checkpoint("Re-key the vault".into()).await?;
+    set_session(new_key, name)?;
+
+    tracing::info!("Vault re-keyed, {count} entries re-encrypted");
+    Ok(count)
 }
 
-#[instrument(skip_all)]
-pub(crate) fn decrypt_data(ciphertext: &[u8], key: &[u8]) -> BazaR<Vec<u8>> {
-    let cipher = Aes256Gcm::new_from_slice(key)
-        .or_raise(|| error::Error::Message("Failed to initialize cipher".into()))?;
-    if ciphertext.len() < 12 {
-        exn::bail!(error::Error::Message(
-            "Invalid ciphertext: too short".into()
-        ));
+/// Re-wrap the data key in the current key slot with a new passphrase and the configured
+/// keyfile, if any. Entries and other key slots are not touched, the backup kept by the
+/// last restore is dropped since it still holds the old passphrase.
+pub async fn change_passphrase(passphrase: String) -> BazaR<()> {
+    let passphrase = Zeroizing::new(passphrase);
+    let data_key = key()?;
+    if passphrase.trim().is_empty() {
+        exn::bail!(error::Error::Message("Passphrase cannot be empty".into()));
     }
-    let (nonce_bytes, actual_ciphertext) = ciphertext.split_at(12);
-    let nonce = Nonce::from_slice(nonce_bytes);
-    cipher
-        .decrypt(nonce, actual_ciphertext)
-        .map_err(|e| exn::Exn::new(e.into()))
+    let keyfile = keyfile::secret()?;
+    let keyfile = keyfile.as_ref().map(|secret| secret.as_slice());
+    let changes = vec![
+        slot::Slot::new(&data_key, passphrase.trim(), keyfile)?.store(&slot()?)?,
+        storage::Change::Remove(BACKUP_KEY.to_string()),
+    ];
+    storage::with_backend(|backend| backend.apply(changes)).await?;
+    storage::checkpoint("Change the passphrase".into()).await?;
+    tracing::info!("Passphrase changed");
+    Ok(())
+}
+
+/// Encrypt `plaintext` for the entry `name` with the configured cipher.
+pub(crate) fn encrypt_data(plaintext: &[u8], key: &[u8], name: &str) -> BazaR<Vec<u8>> {
+    cipher::encrypt(Config::get().main.cipher, plaintext, key, name)
+}
+
+#[instrument(skip_all)]
+pub(crate) fn decrypt_data(ciphertext: &[u8], key: &[u8], name: &str) -> BazaR<Zeroizing<Vec<u8>>> {
+    cipher::decrypt(ciphertext, key, name)
 }
 
+/// Held by the tests of the storage backends kept on disk, which share `test_datadir()`.
 #[cfg(test)]
 pub static TEST_MUTEX: std::sync::Mutex<()> = std::sync::Mutex::new(());
 
@@ -398,3 +800,83 @@ pub fn test_datadir() -> &'static str {
         .to_str()
         .unwrap()
 }
+
+/// Point the global config at `test_datadir()`.
+///
+/// The memory storage, session and keyfile are per thread under test, so every test
+/// starts with an uninitialized vault of its own.
+#[cfg(test)]
+pub fn test_config() {
+    static BUILT: std::sync::Once = std::sync::Once::new();
+    BUILT.call_once(|| {
+        let test_dir = std::path::PathBuf::from(test_datadir());
+        let config_path = test_dir.join("baza.toml");
+        let mut config = Config::default();
+        config.main.datadir = test_dir.to_string_lossy().to_string();
+        // Tests keep the vault in memory, backends are tested on their own
+        config.storage.r#type = Type::Memory;
+        let config_str = toml::to_string(&config).expect("Failed to serialize config");
+        std::fs::write(&config_path, config_str).expect("Failed to write config");
+        Config::build(&config_path).expect("Failed to build config");
+    });
+}
+
+#[cfg(test)]
+#[cfg(not(target_arch = "wasm32"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_invalid_passphrase() {
+        test_config();
+
+        pollster::block_on(async {
+            init(Some("right".into())).await.expect("init failed");
+            lock().expect("lock failed");
+
+            let err = unlock("wrong".into(), None)
+                .await
+                .expect_err("unlock with a wrong passphrase succeeded");
+            assert!(matches!(*err, error::Error::InvalidPassphrase));
+            assert!(key().is_err());
+
+            unlock("right".into(), None).await.expect("unlock failed");
+            assert!(key().is_ok());
+        });
+    }
+
+    #[test]
+    fn test_swapped_ciphertexts() {
+        test_config();
+
+        pollster::block_on(async {
+            init(Some("passphrase".into())).await.expect("init failed");
+            storage::save_content("bank::login".into(), "bank".into())
+                .await
+                .expect("save_content failed");
+            storage::save_content("forum::login".into(), "forum".into())
+                .await
+                .expect("save_content failed");
+
+            let key = key().expect("key failed");
+            let bank = index::id("bank::login", &key).expect("id failed");
+            let forum = index::id("forum::login", &key).expect("id failed");
+            let bank_value = storage::with_backend(|backend| backend.get(&bank))
+                .await
+                .expect("get failed");
+            let forum_value = storage::with_backend(|backend| backend.get(&forum))
+                .await
+                .expect("get failed");
+            let swapped = vec![
+                storage::Change::Set(bank, forum_value),
+                storage::Change::Set(forum, bank_value),
+            ];
+            storage::with_backend(|backend| backend.apply(swapped))
+                .await
+                .expect("apply failed");
+
+            assert!(storage::get_content("bank::login").await.is_err());
+            assert!(storage::get_content("forum::login").await.is_err());
+        });
+    }
+}
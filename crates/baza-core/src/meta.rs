@@ -0,0 +1,220 @@
+//! Bundle metadata: when a bundle was created, changed and last read, who created it
+//! and free-form tags, encrypted in a record next to the bundle.
+
+use crate::{
+    error::Error,
+    index,
+    storage::{self, Change},
+    utils::get_timestamp,
+    BazaR, Config, DEFAULT_AUTHOR, META_PREFIX,
+};
+use exn::ResultExt;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Meta {
+    /// UNIX timestamp of the first save.
+    pub created: u64,
+    /// UNIX timestamp of the last save.
+    pub updated: u64,
+    /// UNIX timestamp of the last time the bundle was shown or copied.
+    pub accessed: Option<u64>,
+    pub author: String,
+    pub tags: BTreeSet<String>,
+}
+
+impl Meta {
+    fn new(now: u64) -> Self {
+        Self {
+            created: now,
+            updated: now,
+            accessed: None,
+            author: Config::get()
+                .main
+                .author
+                .clone()
+                .unwrap_or_else(|| DEFAULT_AUTHOR.to_string()),
+            tags: BTreeSet::new(),
+        }
+    }
+
+    /// Metadata of a bundle changed in two copies of the vault: the most recently
+    /// saved one wins, with the last access of both.
+    pub(crate) fn merge(self, other: Meta) -> Meta {
+        let accessed = self.accessed.max(other.accessed);
+        let mut meta = if other.updated > self.updated {
+            other
+        } else {
+            self
+        };
+        meta.accessed = accessed;
+        meta
+    }
+
+    pub(crate) fn seal(&self, name: &str, key: &[u8]) -> BazaR<Change> {
+        let plaintext = postcard::to_stdvec(self)
+            .or_raise(|| Error::Message(format!("Failed to serialize metadata of '{name}'")))?;
+        let encrypted = crate::encrypt_data(&plaintext, key, &record_name(name))?;
+        Ok(Change::Set(storage_key(&index::id(name, key)?), encrypted))
+    }
+}
+
+/// Key of the metadata record of the bundle stored under `id`.
+pub(crate) fn storage_key(id: &str) -> String {
+    index::record_key(META_PREFIX, id)
+}
+
+fn record_name(name: &str) -> String {
+    format!("{META_PREFIX}{name}")
+}
+
+pub(crate) fn open(name: &str, encrypted: &[u8], key: &[u8]) -> BazaR<Meta> {
+    let plaintext = crate::decrypt_data(encrypted, key, &record_name(name))?;
+    postcard::from_bytes(&plaintext)
+        .or_raise(|| Error::Message(format!("Failed to parse metadata of '{name}'")))
+}
+
+pub(crate) async fn load(name: &str, key: &[u8]) -> BazaR<Option<Meta>> {
+    let id = storage_key(&index::id(name, key)?);
+    if !storage::contains(&id).await? {
+        return Ok(None);
+    }
+    let encrypted = storage::with_backend(|backend| backend.get(&id)).await?;
+    open(name, &encrypted, key).map(Some)
+}
+
+/// Updated metadata record of the bundle `name` when its content is saved.
+///
+/// Bundles saved before metadata existed get a record created at this save.
+pub(crate) async fn saved(name: &str, key: &[u8]) -> BazaR<Change> {
+    let now = get_timestamp();
+    let mut meta = load(name, key).await?.unwrap_or_else(|| Meta::new(now));
+    meta.updated = now;
+    meta.seal(name, key)
+}
+
+async fn ensure_exists(name: &str, key: &[u8]) -> BazaR<()> {
+    if !index::load(key).await?.contains(name) {
+        exn::bail!(Error::Message(format!("Bundle '{name}' not found")));
+    }
+    Ok(())
+}
+
+/// Metadata of the bundle `name`, `None` if it was never saved since metadata exists.
+pub async fn get(name: &str) -> BazaR<Option<Meta>> {
+    let key = crate::key()?;
+    ensure_exists(name, &key).await?;
+    load(name, &key).await
+}
+
+/// Metadata of every bundle that has a record, by bundle name, read in a single transaction.
+pub async fn list() -> BazaR<HashMap<String, Meta>> {
+    let key = crate::key()?;
+    let mut names = HashMap::new();
+    for name in index::load(&key).await? {
+        names.insert(storage_key(&index::id(&name, &key)?), name);
+    }
+    let keys: Vec<String> = storage::with_backend(|backend| backend.list_keys())
+        .await?
+        .into_iter()
+        .filter(|key| names.contains_key(key))
+        .collect();
+    let values = storage::with_backend(|backend| backend.get_many(&keys)).await?;
+    let mut metas = HashMap::with_capacity(keys.len());
+    for (id, encrypted) in keys.iter().zip(values) {
+        let name = &names[id];
+        metas.insert(name.clone(), open(name, &encrypted, &key)?);
+    }
+    Ok(metas)
+}
+
+/// Record that the bundle `name` was read. Bundles without metadata are left alone.
+pub async fn touch(name: &str) -> BazaR<()> {
+    let key = crate::key()?;
+    let Some(mut meta) = load(name, &key).await? else {
+        return Ok(());
+    };
+    meta.accessed = Some(get_timestamp());
+    let change = meta.seal(name, &key)?;
+    storage::with_backend(|backend| backend.apply(vec![change])).await?;
+    let message = format!("Read bundle {}", index::short_id(&index::id(name, &key)?));
+    storage::checkpoint(message).await
+}
+
+/// Add and remove tags of the bundle `name`, returning its updated metadata.
+pub async fn tag(name: &str, add: Vec<String>, remove: Vec<String>) -> BazaR<Meta> {
+    let key = crate::key()?;
+    ensure_exists(name, &key).await?;
+    let mut meta = load(name, &key)
+        .await?
+        .unwrap_or_else(|| Meta::new(get_timestamp()));
+    for tag in remove {
+        meta.tags.remove(tag.trim());
+    }
+    for tag in add {
+        let tag = tag.trim();
+        if tag.is_empty() {
+            exn::bail!(Error::Message("Tag cannot be empty".into()));
+        }
+        meta.tags.insert(tag.to_string());
+    }
+    let change = meta.seal(name, &key)?;
+    storage::with_backend(|backend| backend.apply(vec![change])).await?;
+    let message = format!("Tag bundle {}", index::short_id(&index::id(name, &key)?));
+    storage::checkpoint(message).await?;
+    Ok(meta)
+}
+
+#[cfg(test)]
+#[cfg(not(target_arch = "wasm32"))]
+mod tests {
+    use super::*;
+    use crate::{init, rekey};
+
+    const NAME: &str = "test::meta::login";
+
+    #[test]
+    fn test_meta() {
+        crate::test_config();
+
+        pollster::block_on(async {
+            init(Some("passphrase".into())).await.expect("init failed");
+            assert!(tag(NAME, vec!["work".into()], vec![]).await.is_err());
+            storage::save_content(NAME.into(), "first".into())
+                .await
+                .expect("save_content failed");
+            let created = get(NAME).await.expect("get failed").expect("no metadata");
+            assert_eq!(created.created, created.updated);
+            assert_eq!(created.author, DEFAULT_AUTHOR);
+            assert!(created.accessed.is_none());
+
+            touch(NAME).await.expect("touch failed");
+            storage::save_content(NAME.into(), "second".into())
+                .await
+                .expect("save_content failed");
+            let meta = tag(NAME, vec!["work".into(), " mail ".into()], vec![])
+                .await
+                .expect("tag failed");
+            assert_eq!(meta.created, created.created);
+            assert!(meta.accessed.is_some());
+            assert_eq!(meta.tags.iter().collect::<Vec<_>>(), vec!["mail", "work"]);
+            let meta = tag(NAME, vec![], vec!["mail".into()])
+                .await
+                .expect("tag failed");
+            assert_eq!(meta.tags.len(), 1);
+
+            // Metadata follows the bundle to its new identifier
+            rekey("passphrase".into()).await.expect("rekey failed");
+            let metas = list().await.expect("list failed");
+            assert_eq!(metas.len(), 1);
+            assert_eq!(metas[NAME], meta);
+
+            storage::delete_by_name(NAME.into())
+                .await
+                .expect("delete_by_name failed");
+            assert!(get(NAME).await.is_err());
+            assert!(list().await.expect("list failed").is_empty());
+        });
+    }
+}
@@ -1,8 +1,8 @@
 pub(crate) use crate::utils::as_hash;
 pub use crate::utils::{cleanup_tmp_folder, m, MessageType};
 pub use crate::Password;
+pub use crate::{change_passphrase, dump, init, lock, rekey, storage, totp, unlock};
 pub use crate::{container, error, BazaR, Config};
-pub use crate::{dump, init, lock, storage, totp, unlock};
 
 pub use exn::ResultExt;
 pub use sha2::Digest;
@@ -0,0 +1,186 @@
+//! Emergency access: the data key is split into Shamir shares, any threshold of them
+//! rebuilds it and opens a key slot with a new passphrase.
+
+use crate::{error::Error, keyfile, keyring, slot::Slot, storage, throttle, BazaR};
+use sharks::{Share, Sharks};
+use zeroize::Zeroizing;
+
+const CHECKSUM_LEN: usize = 4;
+
+fn encode(share: &Share, threshold: u8) -> String {
+    let mut bytes = Zeroizing::new(vec![threshold]);
+    bytes.extend(Vec::from(share));
+    let checksum = crc32fast::hash(&bytes).to_be_bytes();
+    bytes
+        .iter()
+        .chain(checksum.iter())
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// Share and the threshold it was split with.
+fn decode(share: &str) -> BazaR<(Share, u8)> {
+    let invalid = || Error::Message(format!("Invalid recovery share '{share}'"));
+    let share = share.trim();
+    if !share.len().is_multiple_of(2) || !share.is_ascii() {
+        exn::bail!(invalid());
+    }
+    let bytes = Zeroizing::new(
+        (0..share.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&share[i..i + 2], 16))
+            .collect::<Result<Vec<u8>, _>>()
+            .map_err(|_| invalid())?,
+    );
+    if bytes.len() < CHECKSUM_LEN + 3 {
+        exn::bail!(invalid());
+    }
+    let (payload, checksum) = bytes.split_at(bytes.len() - CHECKSUM_LEN);
+    if crc32fast::hash(payload).to_be_bytes() != checksum {
+        exn::bail!(Error::Message(format!(
+            "Recovery share '{share}' is mistyped, checksum does not match"
+        )));
+    }
+    let share = Share::try_from(&payload[1..]).map_err(|_| invalid())?;
+    Ok((share, payload[0]))
+}
+
+/// Split the data key of the unlocked vault into `shares` printable shares,
+/// any `threshold` of which rebuild it.
+///
+/// Shares hold the data key itself: `rekey` replaces it and makes them useless.
+pub fn split(shares: u8, threshold: u8) -> BazaR<Vec<String>> {
+    let data_key = crate::key()?;
+    if threshold < 2 {
+        exn::bail!(Error::Message("Threshold must be at least 2".into()));
+    }
+    if shares < threshold {
+        exn::bail!(Error::Message(format!(
+            "Cannot split into {shares} shares with a threshold of {threshold}"
+        )));
+    }
+    Ok(Sharks(threshold)
+        .dealer(&data_key)
+        .take(shares.into())
+        .map(|share| encode(&share, threshold))
+        .collect())
+}
+
+/// Rebuild the data key from recovery shares and wrap it in the key slot `name` with
+/// `passphrase` and the configured keyfile, if any. An existing slot is replaced.
+///
+/// The vault is unlocked with the new slot and failed unlock attempts are forgotten.
+pub async fn combine(shares: Vec<String>, name: String, passphrase: String) -> BazaR<()> {
+    let passphrase = Zeroizing::new(passphrase);
+    if !storage::is_initialized().await? {
+        exn::bail!(Error::Message(
+            "Vault is not initialized. Use 'baza init' to create one".into()
+        ));
+    }
+    let name = name.trim();
+    if name.is_empty() {
+        exn::bail!(Error::Message("Key slot name cannot be empty".into()));
+    }
+    if passphrase.trim().is_empty() {
+        exn::bail!(Error::Message("Passphrase cannot be empty".into()));
+    }
+
+    let mut decoded = Vec::with_capacity(shares.len());
+    let mut threshold = None;
+    for share in &shares {
+        let (share, share_threshold) = decode(share)?;
+        if *threshold.get_or_insert(share_threshold) != share_threshold {
+            exn::bail!(Error::Message(
+                "Recovery shares come from different splits".into()
+            ));
+        }
+        decoded.push(share);
+    }
+    let Some(threshold) = threshold else {
+        exn::bail!(Error::Message("No recovery shares given".into()));
+    };
+    let data_key = Zeroizing::new(Sharks(threshold).recover(&decoded).map_err(|_| {
+        Error::Message(format!(
+            "Not enough recovery shares, {threshold} different shares are required"
+        ))
+    })?);
+    crate::verify_key(&data_key, false).await.map_err(|_| {
+        Error::Message("Recovery shares do not match the vault key, was it re-keyed?".into())
+    })?;
+
+    // Legacy records would replace the default slot at the next unlock
+    keyring::upgrade().await?;
+    let keyfile = keyfile::secret()?;
+    let keyfile = keyfile.as_ref().map(|secret| secret.as_slice());
+    let change = Slot::new(&data_key, passphrase.trim(), keyfile)?.store(name)?;
+    storage::with_backend(|backend| backend.apply(vec![change])).await?;
+    storage::checkpoint("Recover the vault key".into()).await?;
+    crate::set_session(data_key, name.to_string())?;
+    throttle::succeeded().await?;
+    tracing::info!("Vault key recovered into key slot '{name}'");
+    Ok(())
+}
+
+#[cfg(test)]
+#[cfg(not(target_arch = "wasm32"))]
+mod tests {
+    use super::*;
+    use crate::{init, lock, rekey, slot::DEFAULT_SLOT, unlock};
+
+    #[test]
+    fn test_recovery() {
+        crate::test_config();
+
+        pollster::block_on(async {
+            init(Some("forgotten".into())).await.expect("init failed");
+            storage::save_content("test::recovery::login".into(), "secret".into())
+                .await
+                .expect("save_content failed");
+            assert!(split(3, 1).is_err());
+            assert!(split(2, 3).is_err());
+            let shares = split(5, 3).expect("split failed");
+            assert_eq!(shares.len(), 5);
+            lock().expect("lock failed");
+
+            assert!(split(5, 3).is_err());
+            let err = combine(shares[..2].to_vec(), DEFAULT_SLOT.into(), "new".into())
+                .await
+                .expect_err("combine with too few shares succeeded");
+            assert!(err.to_string().contains("Not enough recovery shares"));
+            let mut mistyped = shares[1].clone();
+            mistyped.replace_range(4..5, if &mistyped[4..5] == "0" { "1" } else { "0" });
+            assert!(combine(
+                vec![shares[0].clone(), mistyped, shares[2].clone()],
+                DEFAULT_SLOT.into(),
+                "new".into()
+            )
+            .await
+            .is_err());
+
+            combine(
+                vec![shares[4].clone(), shares[0].clone(), shares[2].clone()],
+                DEFAULT_SLOT.into(),
+                "new".into(),
+            )
+            .await
+            .expect("combine failed");
+            let content = storage::get_content("test::recovery::login")
+                .await
+                .expect("get_content failed");
+            assert_eq!(content.as_str(), "secret");
+            lock().expect("lock failed");
+
+            assert!(unlock("forgotten".into(), None).await.is_err());
+            unlock("new".into(), None).await.expect("unlock failed");
+
+            // Shares hold the old key after a rekey
+            rekey("new".into()).await.expect("rekey failed");
+            lock().expect("lock failed");
+            assert!(
+                combine(shares[..3].to_vec(), DEFAULT_SLOT.into(), "other".into())
+                    .await
+                    .is_err()
+            );
+        });
+    }
+}
@@ -76,8 +76,9 @@ pub fn push() -> BazaR<()> {
     Ok(())
 }
 
-/// Pull database from S3 and restore local storage
-pub fn pull() -> BazaR<()> {
+/// Pull database from S3 and restore local storage, once its own key slots open with
+/// `passphrase`.
+pub fn pull(passphrase: String) -> BazaR<()> {
     let config = Config::get();
     let s3_config = config.s3.as_ref().ok_or_else(|| {
         println!("S3 is not configured. Please add an [s3] section in baza.toml:");
@@ -149,7 +150,7 @@ pub fn pull() -> BazaR<()> {
         crate::storage::initialize()?;
     }
 
-    pollster::block_on(crate::storage::restore(restored))?;
+    pollster::block_on(crate::storage::restore(restored, Some(passphrase)))?;
 
     println!("Database restored successfully.");
     Ok(())
@@ -0,0 +1,254 @@
+//! Key slots: the data key is wrapped once per member passphrase, so several people
+//! can unlock one vault and a member can be removed without touching the others.
+
+use crate::{
+    error::Error,
+    kdf::KdfParams,
+    keyfile, keyring,
+    storage::{self, Change},
+    BazaR, BACKUP_KEY, DATA_KEY, SLOT_PREFIX,
+};
+use exn::ResultExt;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use zeroize::Zeroizing;
+
+/// Slot created by `init` and by the migration of single-passphrase vaults.
+pub const DEFAULT_SLOT: &str = "default";
+
+/// The data key wrapped with the key derived from one passphrase.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Slot {
+    pub(crate) kdf: KdfParams,
+    pub(crate) keyfile: bool,
+    pub(crate) wrapped: Vec<u8>,
+}
+
+/// Key slot as listed to the user.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SlotInfo {
+    pub name: String,
+    pub keyfile: bool,
+    /// The vault was unlocked with this slot.
+    pub current: bool,
+}
+
+pub(crate) fn storage_key(name: &str) -> String {
+    format!("{SLOT_PREFIX}{name}")
+}
+
+impl Slot {
+    /// Wrap `data_key` with fresh KDF parameters, the passphrase and the optional keyfile digest.
+    pub(crate) fn new(data_key: &[u8], passphrase: &str, keyfile: Option<&[u8]>) -> BazaR<Self> {
+        let kdf = KdfParams::generate();
+        let kek = kdf.derive(passphrase, keyfile)?;
+        let wrapped = crate::encrypt_data(data_key, kek.as_slice(), DATA_KEY)?;
+        Ok(Self {
+            kdf,
+            keyfile: keyfile.is_some(),
+            wrapped,
+        })
+    }
+
+    /// Unwrap the data key. The keyfile is only used if the slot was protected with one.
+    pub(crate) fn open(
+        &self,
+        passphrase: &str,
+        keyfile: Option<&[u8]>,
+    ) -> BazaR<Zeroizing<Vec<u8>>> {
+        let keyfile = match (self.keyfile, keyfile) {
+            (true, None) => exn::bail!(Error::Message(
+                "Vault requires a keyfile. Use '--keyfile' or 'BAZA_KEYFILE' env var".into()
+            )),
+            (true, keyfile) => keyfile,
+            (false, _) => None,
+        };
+        let kek = self.kdf.derive(passphrase, keyfile)?;
+        keyring::unwrap(&self.wrapped, kek.as_slice())
+    }
+
+    /// Change storing the slot under `name`.
+    pub(crate) fn store(&self, name: &str) -> BazaR<Change> {
+        let bytes = postcard::to_stdvec(self)
+            .or_raise(|| Error::Message("Failed to serialize key slot".into()))?;
+        Ok(Change::Set(storage_key(name), bytes))
+    }
+}
+
+/// All key slots of the vault, by name.
+pub(crate) async fn load_all() -> BazaR<Vec<(String, Slot)>> {
+    let keys: Vec<String> = storage::with_backend(|backend| backend.list_keys())
+        .await?
+        .into_iter()
+        .filter(|key| key.starts_with(SLOT_PREFIX))
+        .collect();
+    let values = storage::with_backend(|backend| backend.get_many(&keys)).await?;
+    let mut slots = Vec::with_capacity(keys.len());
+    for (key, raw) in keys.iter().zip(values) {
+        let name = &key[SLOT_PREFIX.len()..];
+        slots.push((name.to_string(), parse(name, &raw)?));
+    }
+    Ok(slots)
+}
+
+pub(crate) fn parse(name: &str, raw: &[u8]) -> BazaR<Slot> {
+    postcard::from_bytes(raw)
+        .or_raise(|| Error::Message(format!("Failed to parse key slot '{name}'")))
+}
+
+pub(crate) async fn load(name: &str) -> BazaR<Slot> {
+    let key = storage_key(name);
+    if !storage::contains(&key).await? {
+        exn::bail!(Error::Message(format!("No key slot named '{name}'")));
+    }
+    let raw = storage::with_backend(|backend| backend.get(&key)).await?;
+    parse(name, &raw)
+}
+
+/// Unwrap the data key with the first slot the passphrase opens.
+pub(crate) fn open_any(
+    slots: Vec<(String, Slot)>,
+    passphrase: &str,
+    keyfile: Option<&[u8]>,
+) -> BazaR<(String, Zeroizing<Vec<u8>>)> {
+    let mut error = None;
+    for (name, slot) in slots {
+        match slot.open(passphrase, keyfile) {
+            Ok(data_key) => return Ok((name, data_key)),
+            // A slot tried with a wrong passphrase explains the failure better than a missing keyfile
+            Err(e) if matches!(*e, Error::InvalidPassphrase) => error = Some(e),
+            Err(e) => {
+                error.get_or_insert(e);
+            }
+        }
+    }
+    Err(error.unwrap_or_else(|| exn::Exn::new(Error::InvalidPassphrase)))
+}
+
+/// Add a key slot wrapping the data key of the unlocked vault with another passphrase.
+pub async fn add(name: String, passphrase: String, keyfile: Option<PathBuf>) -> BazaR<()> {
+    let passphrase = Zeroizing::new(passphrase);
+    let data_key = crate::key()?;
+    let name = name.trim();
+    if name.is_empty() {
+        exn::bail!(Error::Message("Key slot name cannot be empty".into()));
+    }
+    if passphrase.trim().is_empty() {
+        exn::bail!(Error::Message("Passphrase cannot be empty".into()));
+    }
+    if storage::contains(&storage_key(name)).await? {
+        exn::bail!(Error::Message(format!("Key slot '{name}' already exists")));
+    }
+
+    let keyfile = keyfile.map(|path| keyfile::read(&path)).transpose()?;
+    let keyfile = keyfile.as_ref().map(|secret| secret.as_slice());
+    let change = Slot::new(&data_key, passphrase.trim(), keyfile)?.store(name)?;
+    storage::with_backend(|backend| backend.apply(vec![change])).await?;
+    storage::checkpoint("Add a key slot".into()).await?;
+    tracing::info!("Key slot '{name}' added");
+    Ok(())
+}
+
+/// Remove a key slot, and the backup kept by the last restore that still holds it.
+/// The last slot cannot be removed.
+pub async fn remove(name: String) -> BazaR<()> {
+    let _ = crate::key()?;
+    let slots = load_all().await?;
+    if !slots.iter().any(|(slot, _)| *slot == name) {
+        exn::bail!(Error::Message(format!("No key slot named '{name}'")));
+    }
+    if slots.len() == 1 {
+        exn::bail!(Error::Message("Cannot remove the last key slot".into()));
+    }
+    let changes = vec![
+        Change::Remove(storage_key(&name)),
+        Change::Remove(BACKUP_KEY.to_string()),
+    ];
+    storage::with_backend(|backend| backend.apply(changes)).await?;
+    storage::checkpoint("Remove a key slot".into()).await?;
+    tracing::info!("Key slot '{name}' removed");
+    Ok(())
+}
+
+pub async fn list() -> BazaR<Vec<SlotInfo>> {
+    let current = crate::slot()?;
+    Ok(load_all()
+        .await?
+        .into_iter()
+        .map(|(name, slot)| SlotInfo {
+            current: name == current,
+            keyfile: slot.keyfile,
+            name,
+        })
+        .collect())
+}
+
+#[cfg(test)]
+#[cfg(not(target_arch = "wasm32"))]
+mod tests {
+    use super::*;
+    use crate::{change_passphrase, init, lock, rekey, unlock};
+
+    #[test]
+    fn test_slots() {
+        crate::test_config();
+
+        pollster::block_on(async {
+            init(Some("admin".into())).await.expect("init failed");
+            storage::save_content("test::slot::login".into(), "secret".into())
+                .await
+                .expect("save_content failed");
+            add("alice".into(), "alice".into(), None)
+                .await
+                .expect("add failed");
+            assert!(add("alice".into(), "other".into(), None).await.is_err());
+            lock().expect("lock failed");
+
+            unlock("alice".into(), None).await.expect("unlock failed");
+            let content = storage::get_content("test::slot::login")
+                .await
+                .expect("get_content failed");
+            assert_eq!(content.as_str(), "secret");
+            let slots = list().await.expect("list failed");
+            assert_eq!(slots.len(), 2);
+            assert!(slots
+                .iter()
+                .any(|slot| slot.name == "alice" && slot.current));
+
+            // Changing the passphrase only touches the slot of the current member
+            change_passphrase("alice2".into())
+                .await
+                .expect("change_passphrase failed");
+            lock().expect("lock failed");
+            unlock("admin".into(), None).await.expect("unlock failed");
+            assert!(unlock("alice".into(), None).await.is_err());
+            unlock("alice2".into(), None).await.expect("unlock failed");
+
+            remove("alice".into()).await.expect("remove failed");
+            assert!(unlock("alice2".into(), None).await.is_err());
+            unlock("admin".into(), None).await.expect("unlock failed");
+            assert!(remove(DEFAULT_SLOT.into()).await.is_err());
+        });
+    }
+
+    #[test]
+    fn test_rekey_keeps_current_slot() {
+        crate::test_config();
+
+        pollster::block_on(async {
+            init(Some("admin".into())).await.expect("init failed");
+            add("alice".into(), "alice".into(), None)
+                .await
+                .expect("add failed");
+
+            rekey("admin".into()).await.expect("rekey failed");
+            let slots = load_all().await.expect("load_all failed");
+            assert_eq!(slots.len(), 1);
+            assert_eq!(slots[0].0, DEFAULT_SLOT);
+
+            lock().expect("lock failed");
+            assert!(unlock("alice".into(), None).await.is_err());
+            unlock("admin".into(), None).await.expect("unlock failed");
+        });
+    }
+}
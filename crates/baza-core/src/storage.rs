@@ -1,13 +1,26 @@
 #[cfg(not(target_arch = "wasm32"))]
+pub mod files;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod git;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod memory;
+#[cfg(not(target_arch = "wasm32"))]
 pub mod redb;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod sqlite;
 #[cfg(target_arch = "wasm32")]
 pub mod web;
 
-use crate::BazaR;
+use crate::{
+    error::Error, history, index, keyfile, meta, slot, trash, BazaR, BACKUP_KEY, INDEX_KEY,
+    SLOT_PREFIX, VERIFIER_KEY,
+};
 #[cfg(not(target_arch = "wasm32"))]
-use crate::Config;
+use crate::{Config, Type};
 use async_trait::async_trait;
 use exn::ResultExt;
+use std::collections::HashMap;
+use zeroize::Zeroizing;
 
 #[cfg(not(target_arch = "wasm32"))]
 pub fn storage_dir(dir: &'static str) -> std::path::PathBuf {
@@ -24,13 +37,150 @@ pub(crate) trait StorageBounds {}
 #[cfg(target_arch = "wasm32")]
 impl<T> StorageBounds for T {}
 
+/// A single write in a batch passed to [`StorageBackend::apply`].
+pub(crate) enum Change {
+    Set(String, Vec<u8>),
+    Remove(String),
+}
+
+/// Reads and writes inside [`StorageBackend::transaction`].
+pub(crate) trait Transaction {
+    fn list_keys(&self) -> BazaR<Vec<String>>;
+    fn get(&self, key: &str) -> BazaR<Option<Vec<u8>>>;
+    fn set(&mut self, key: &str, value: Vec<u8>) -> BazaR<()>;
+    fn remove(&mut self, key: &str) -> BazaR<()>;
+}
+
+/// Body of a transaction. Returning an error rolls every write back.
+pub(crate) type TransactionFn<'a> = Box<dyn FnOnce(&mut dyn Transaction) -> BazaR<()> + 'a>;
+
 #[async_trait(?Send)]
 pub(crate) trait StorageBackend: StorageBounds {
     async fn is_initialized(&self) -> BazaR<bool>;
     async fn list_keys(&self) -> BazaR<Vec<String>>;
+    /// Every key with its value, read in a single transaction.
+    async fn entries(&self) -> BazaR<Vec<(String, Vec<u8>)>>;
     async fn get(&self, key: &str) -> BazaR<Vec<u8>>;
+    /// Values of `keys` in the same order, read in a single transaction.
+    async fn get_many(&self, keys: &[String]) -> BazaR<Vec<Vec<u8>>>;
     async fn set(&self, key: &str, value: Vec<u8>) -> BazaR<()>;
+    /// Store all entries in a single transaction.
+    async fn set_many(&self, entries: Vec<(String, Vec<u8>)>) -> BazaR<()>;
     async fn remove(&self, key: &str) -> BazaR<()>;
+    /// Apply all changes in a single transaction: either every change is stored or none.
+    async fn apply(&self, changes: Vec<Change>) -> BazaR<()>;
+    /// Run `f` inside a single write transaction, committed only if `f` succeeds.
+    async fn transaction<'a>(&self, f: TransactionFn<'a>) -> BazaR<()>;
+    /// Record the writes since the previous checkpoint as one change described by `message`.
+    /// Only backends keeping a history of changes do something with it.
+    async fn checkpoint(&self, _message: &str) -> BazaR<()> {
+        Ok(())
+    }
+    /// Give the space left by removed entries back to the file system, `None` when the
+    /// backend has nothing to compact.
+    async fn compact(&self) -> BazaR<Option<Compaction>> {
+        Ok(None)
+    }
+    /// Overwrite what removed entries left behind in the storage file. Backends that
+    /// overwrite entries as they remove them, or keep nothing on disk, do nothing.
+    async fn scrub(&self) -> BazaR<()> {
+        Ok(())
+    }
+}
+
+/// Size of the storage file before and after [`compact`], in bytes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Compaction {
+    pub before: u64,
+    pub after: u64,
+}
+
+/// Size of the file at `path`, in bytes.
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn file_size(path: &std::path::Path) -> BazaR<u64> {
+    std::fs::metadata(path)
+        .map(|metadata| metadata.len())
+        .or_raise(|| Error::Message(format!("Failed to read size of '{}'", path.display())))
+}
+
+/// Overwrite the file at `path` with zeros in place, so the data it held does not linger
+/// in freed disk blocks once the file is replaced or removed.
+///
+/// Copy-on-write file systems and SSDs may still keep the old blocks around.
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn overwrite(path: &std::path::Path) -> BazaR<()> {
+    use std::io::Write;
+
+    let failed = || Error::Message(format!("Failed to overwrite '{}'", path.display()));
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .open(path)
+        .or_raise(failed)?;
+    let zeros = [0u8; 64 * 1024];
+    let mut left = file.metadata().or_raise(failed)?.len();
+    while left > 0 {
+        let chunk = left.min(zeros.len() as u64) as usize;
+        file.write_all(&zeros[..chunk]).or_raise(failed)?;
+        left -= chunk as u64;
+    }
+    file.sync_all().or_raise(failed)
+}
+
+/// [`overwrite`] the file at `path`, then remove it.
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn shred(path: &std::path::Path) -> BazaR<()> {
+    overwrite(path)?;
+    std::fs::remove_file(path)
+        .or_raise(|| Error::Message(format!("Failed to remove '{}'", path.display())))
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn backend(r#type: Type) -> BazaR<&'static dyn StorageBackend> {
+    match r#type {
+        Type::Redb => self::redb::Redb::instance(),
+        Type::Files => self::files::Files::instance(),
+        Type::Git => self::git::Git::instance(),
+        Type::Sqlite => self::sqlite::Sqlite::instance(),
+        Type::Memory => self::memory::Memory::instance(),
+    }
+}
+
+/// Create an empty `r#type` storage, replacing the previous one.
+#[cfg(not(target_arch = "wasm32"))]
+fn initialize_backend(r#type: Type) -> BazaR<()> {
+    match r#type {
+        Type::Redb => self::redb::initialize(),
+        Type::Files => self::files::initialize(),
+        Type::Git => self::git::initialize(),
+        Type::Sqlite => self::sqlite::initialize(),
+        Type::Memory => self::memory::initialize(),
+    }
+}
+
+/// Set by [`ephemeral`]: the vault is in memory whatever the configuration says.
+#[cfg(not(target_arch = "wasm32"))]
+static EPHEMERAL: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+/// Storage type in use: `storage.type`, unless the session is ephemeral.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn current_type() -> Type {
+    if EPHEMERAL.load(std::sync::atomic::Ordering::Relaxed) {
+        return Type::Memory;
+    }
+    Config::get().storage.r#type
+}
+
+/// Work on the entries of a dump in memory for the rest of the process.
+///
+/// Nothing is written to the configured storage; [`dump`] exports the changes.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn ephemeral(data: Vec<(String, Vec<u8>)>) -> BazaR<()> {
+    if data.iter().all(|(key, _)| key == BACKUP_KEY) {
+        exn::bail!(Error::Message("Dump is empty".into()));
+    }
+    self::memory::Memory::get().load(data)?;
+    EPHEMERAL.store(true, std::sync::atomic::Ordering::Relaxed);
+    Ok(())
 }
 
 pub(crate) async fn with_backend<F, Fut, R>(f: F) -> BazaR<R>
@@ -40,7 +190,7 @@ where
 {
     #[cfg(not(target_arch = "wasm32"))]
     {
-        let backend = self::redb::Redb::instance()?;
+        let backend = backend(current_type())?;
         f(backend).await
     }
 
@@ -53,37 +203,100 @@ where
 
 pub fn initialize() -> BazaR<()> {
     #[cfg(not(target_arch = "wasm32"))]
-    self::redb::initialize()?;
+    initialize_backend(current_type())?;
+    Ok(())
+}
+
+/// Shrink the storage file, `None` when the storage type has nothing to compact.
+pub async fn compact() -> BazaR<Option<Compaction>> {
+    with_backend(|backend| backend.compact()).await
+}
+
+/// Overwrite the data of removed entries, after writes dropping much of the vault.
+pub(crate) async fn scrub() -> BazaR<()> {
+    with_backend(|backend| backend.scrub()).await
+}
+
+/// Compact after writes replacing the whole vault, when `storage.auto_compact` is set.
+pub(crate) async fn auto_compact() -> BazaR<()> {
+    #[cfg(not(target_arch = "wasm32"))]
+    if Config::get().storage.auto_compact {
+        if let Some(compaction) = compact().await? {
+            tracing::info!(
+                "Storage compacted from {} to {} bytes",
+                compaction.before,
+                compaction.after
+            );
+        }
+    }
     Ok(())
 }
 
+/// Describe the writes since the previous checkpoint. Messages refer to bundles by
+/// [`index::short_id`], which is their name only when file names show it already.
+pub(crate) async fn checkpoint(message: String) -> BazaR<()> {
+    with_backend(|backend| backend.checkpoint(&message)).await
+}
+
 // --- Public Utility Functions (The new "API") ---
 
 pub async fn is_initialized() -> BazaR<bool> {
     with_backend(|backend| backend.is_initialized()).await
 }
 
+/// Names of all bundles, read from the encrypted name index.
 pub async fn list_all_keys() -> BazaR<Vec<String>> {
+    let key = crate::key()?;
+    Ok(index::load(&key).await?.into_iter().collect())
+}
+
+pub(crate) async fn contains(name: &str) -> BazaR<bool> {
     let keys = with_backend(|backend| backend.list_keys()).await?;
-    Ok(keys
-        .into_iter()
-        .filter(|key| !crate::is_system_key(key))
-        .collect())
+    Ok(keys.iter().any(|key| key == name))
 }
 
-pub async fn get_content(name: &str) -> BazaR<String> {
+pub async fn get_content(name: &str) -> BazaR<Zeroizing<String>> {
     let key = crate::key()?;
-    let encrypted = with_backend(|backend| backend.get(name)).await?;
-    let plaintext = crate::decrypt_data(&encrypted, &key)?;
-    String::from_utf8(plaintext)
-        .map_err(|_| crate::error::Error::Message("Failed to decode utf8".into()).into())
+    let id = index::storage_key(name, &key)?;
+    let encrypted = with_backend(|backend| backend.get(&id)).await?;
+    let plaintext = crate::decrypt_data(&encrypted, &key, name)?;
+    let content = std::str::from_utf8(&plaintext)
+        .map_err(|_| crate::error::Error::Message("Failed to decode utf8".into()))?;
+    Ok(Zeroizing::new(content.to_string()))
 }
 
 pub async fn save_content(name: String, content: String) -> BazaR<()> {
+    let content = Zeroizing::new(content);
     // Check if vault is unlocked before allowing save
     let key = crate::key()?;
-    let encrypted = crate::encrypt_data(content.as_bytes(), &key)?;
-    with_backend(|backend| backend.set(&name, encrypted)).await
+    let id = index::storage_key(&name, &key)?;
+    let encrypted = crate::encrypt_data(content.as_bytes(), &key, &name)?;
+    let mut names = index::load(&key).await?;
+    if crate::is_system_key(&name) {
+        let message = format!("Update system entry {}", index::short_id(&id));
+        with_backend(|backend| backend.set(&id, encrypted)).await?;
+        return checkpoint(message).await;
+    }
+    if names.contains(&name) {
+        // Keep the replaced content as a revision, written together with the new one
+        let message = format!("Update bundle {}", index::short_id(&id));
+        let mut changes = vec![Change::Set(id, encrypted), meta::saved(&name, &key).await?];
+        changes.extend(history::record(&name, &content, &key).await?);
+        with_backend(|backend| backend.apply(changes)).await?;
+        return checkpoint(message).await;
+    }
+
+    // New bundle: store it together with its metadata and the updated name index
+    let meta = meta::saved(&name, &key).await?;
+    names.insert(name);
+    let message = format!("Add bundle {}", index::short_id(&id));
+    let changes = vec![
+        Change::Set(id, encrypted),
+        meta,
+        Change::Set(INDEX_KEY.to_string(), index::seal(&names, &key)?),
+    ];
+    with_backend(|backend| backend.apply(changes)).await?;
+    checkpoint(message).await
 }
 
 pub async fn get_raw(name: String) -> BazaR<String> {
@@ -97,37 +310,177 @@ pub async fn save_raw(name: String, content: String) -> BazaR<()> {
 }
 
 pub async fn delete_by_name(name: String) -> BazaR<()> {
-    with_backend(|backend| backend.remove(&name)).await
+    if crate::is_system_key(&name) {
+        return with_backend(|backend| backend.remove(&name)).await;
+    }
+
+    let key = crate::key()?;
+    let mut names = index::load(&key).await?;
+    if !names.remove(&name) {
+        exn::bail!(Error::Message(format!("Bundle '{name}' not found")));
+    }
+    let id = index::storage_key(&name, &key)?;
+    let message = format!("Delete bundle {}", index::short_id(&id));
+    let mut changes: Vec<Change> = trash::keep(&name, &key).await?.into_iter().collect();
+    changes.extend([
+        Change::Remove(history::storage_key(&id)),
+        Change::Remove(meta::storage_key(&id)),
+        Change::Remove(id),
+        Change::Set(INDEX_KEY.to_string(), index::seal(&names, &key)?),
+    ]);
+    with_backend(|backend| backend.apply(changes)).await?;
+    checkpoint(message).await
 }
 
+/// Every entry of the database, without the backup kept by the last restore.
 pub async fn dump() -> BazaR<Vec<(String, Vec<u8>)>> {
-    with_backend(|backend| async move {
-        let keys = backend.list_keys().await?;
-        let mut data = Vec::with_capacity(keys.len());
-        for key in keys {
-            let value = backend.get(&key).await?;
-            data.push((key, value));
+    let mut entries = with_backend(|backend| backend.entries()).await?;
+    entries.retain(|(key, _)| key != BACKUP_KEY);
+    Ok(entries)
+}
+
+/// Check that every encrypted entry of a dump decrypts with `key`.
+fn validate(data: &[(String, Vec<u8>)], key: &[u8]) -> BazaR<()> {
+    let invalid = || Error::Message("Dump does not decrypt with the current vault key".into());
+    let values: HashMap<&str, &[u8]> = data
+        .iter()
+        .map(|(name, value)| (name.as_str(), value.as_slice()))
+        .collect();
+    if let Some(verifier) = values.get(VERIFIER_KEY) {
+        crate::decrypt_data(verifier, key, VERIFIER_KEY).or_raise(invalid)?;
+    }
+    let mut names = HashMap::new();
+    if let Some(sealed) = values.get(INDEX_KEY) {
+        let plaintext = crate::decrypt_data(sealed, key, INDEX_KEY).or_raise(invalid)?;
+        let index: std::collections::BTreeSet<String> = postcard::from_bytes(&plaintext)
+            .or_raise(|| Error::Message("Failed to parse name index of the dump".into()))?;
+        for name in index {
+            names.insert(index::id(&name, key)?, name);
         }
-        Ok(data)
-    })
-    .await
+    }
+    for (id, value) in data
+        .iter()
+        .filter(|(id, _)| !crate::is_raw_key(id) && id != INDEX_KEY)
+    {
+        let name = index::entry_name(id, &names);
+        crate::decrypt_data(value, key, &name)
+            .or_raise(|| Error::Message(format!("Entry '{name}' of the dump does not decrypt")))?;
+    }
+    Ok(())
 }
 
-pub async fn restore(data: Vec<(String, Vec<u8>)>) -> BazaR<()> {
-    with_backend(|backend| async move {
-        // Clear existing data
-        let keys = backend.list_keys().await?;
-        for key in keys {
-            backend.remove(&key).await?;
+/// Data key of a dump, unwrapped from its own key slots with `passphrase`.
+fn dump_key(data: &[(String, Vec<u8>)], passphrase: &str) -> BazaR<Zeroizing<Vec<u8>>> {
+    let mut slots = Vec::new();
+    for (key, raw) in data {
+        if let Some(name) = key.strip_prefix(SLOT_PREFIX) {
+            slots.push((name.to_string(), slot::parse(name, raw)?));
         }
+    }
+    if slots.is_empty() {
+        exn::bail!(Error::Message(
+            "Dump has no key slot to check it with, unlock the vault to restore it".into()
+        ));
+    }
+    let keyfile = keyfile::secret()?;
+    let keyfile = keyfile.as_ref().map(|secret| secret.as_slice());
+    let (_, key) = slot::open_any(slots, passphrase.trim(), keyfile)?;
+    Ok(key)
+}
 
-        // Restore new data
-        for (key, value) in data {
-            backend.set(&key, value).await?;
+/// Replace every entry with `data`, keeping the previous entries as the backup.
+fn swap(tx: &mut dyn Transaction, data: Vec<(String, Vec<u8>)>) -> BazaR<()> {
+    let mut previous = Vec::new();
+    for key in tx.list_keys()? {
+        if key != BACKUP_KEY {
+            if let Some(value) = tx.get(&key)? {
+                previous.push((key.clone(), value));
+            }
         }
-        Ok(())
+        tx.remove(&key)?;
+    }
+    for (key, value) in data {
+        if key != BACKUP_KEY {
+            tx.set(&key, value)?;
+        }
+    }
+    let backup = postcard::to_stdvec(&previous)
+        .or_raise(|| Error::Message("Failed to serialize backup".into()))?;
+    tx.set(BACKUP_KEY, backup)
+}
+
+/// Replace the whole database with `data` in a single transaction.
+///
+/// The dump must decrypt with the key of the unlocked vault or, when the vault is locked,
+/// with the key its own slots unwrap with `passphrase`. The previous entries are kept as
+/// a backup that [`undo_restore`] brings back.
+pub async fn restore(data: Vec<(String, Vec<u8>)>, passphrase: Option<String>) -> BazaR<()> {
+    if data.iter().all(|(key, _)| key == BACKUP_KEY) {
+        exn::bail!(Error::Message("Dump is empty".into()));
+    }
+    let key = match (crate::key(), passphrase) {
+        (Ok(key), _) => key,
+        (Err(_), Some(passphrase)) => dump_key(&data, &Zeroizing::new(passphrase))?,
+        (Err(e), None) => return Err(e),
+    };
+    validate(&data, &key)?;
+    with_backend(|backend| backend.transaction(Box::new(move |tx| swap(tx, data)))).await?;
+    checkpoint("Restore the vault from a dump".into()).await?;
+    scrub().await?;
+    auto_compact().await
+}
+
+/// Bring back the entries replaced by the last restore. The restored entries become
+/// the backup in turn, so undoing twice restores again.
+pub async fn undo_restore() -> BazaR<()> {
+    with_backend(|backend| {
+        backend.transaction(Box::new(|tx| {
+            let Some(backup) = tx.get(BACKUP_KEY)? else {
+                exn::bail!(Error::Message("No restore to undo".into()));
+            };
+            let previous = postcard::from_bytes(&backup)
+                .or_raise(|| Error::Message("Failed to parse backup".into()))?;
+            swap(tx, previous)
+        }))
     })
-    .await
+    .await?;
+    checkpoint("Undo the last restore".into()).await?;
+    scrub().await?;
+    auto_compact().await
+}
+
+/// Copy every entry of the vault to an empty `to` storage, returning how many were copied.
+///
+/// Entries are copied as they are, still encrypted. The vault keeps using the configured
+/// storage until `storage.type` is changed to `to`.
+#[cfg(not(target_arch = "wasm32"))]
+pub async fn migrate(to: Type) -> BazaR<usize> {
+    let from = current_type();
+    if from == to {
+        exn::bail!(Error::Message(format!(
+            "The vault is already stored in {to}"
+        )));
+    }
+    if to == Type::Memory {
+        exn::bail!(Error::Message(
+            "Memory storage is gone when baza exits, migrate to another type".into()
+        ));
+    }
+    let target = backend(to)?;
+    if target.is_initialized().await? && !target.list_keys().await?.is_empty() {
+        exn::bail!(Error::Message(format!(
+            "The {to} storage already holds a vault, remove it before migrating"
+        )));
+    }
+    let entries = backend(from)?.entries().await?;
+    let count = entries.len();
+    initialize_backend(to)?;
+    target.set_many(entries).await?;
+    target
+        .checkpoint(&format!("Migrate the vault from {from}"))
+        .await?;
+    tracing::info!("{count} entries copied from {from} to {to}");
+    Ok(count)
 }
 
 // storage.rs
@@ -192,3 +545,148 @@ pub async fn delete_database() -> BazaR<()> {
         Err(crate::error::Error::Message("Not implemented for this platform".into()).into())
     }
 }
+
+#[cfg(test)]
+#[cfg(not(target_arch = "wasm32"))]
+mod tests {
+    use super::*;
+    use crate::init;
+
+    #[test]
+    fn test_restore_and_undo() {
+        crate::test_config();
+
+        pollster::block_on(async {
+            init(Some("passphrase".into())).await.expect("init failed");
+            save_content("test::restore::before".into(), "before".into())
+                .await
+                .expect("save_content failed");
+            let before = dump().await.expect("dump failed");
+            save_content("test::restore::after".into(), "after".into())
+                .await
+                .expect("save_content failed");
+
+            // A dump of another vault is refused and the vault is left untouched
+            let mut foreign = before.clone();
+            let other_key = crate::keyring::generate();
+            foreign.push((
+                "forged".into(),
+                crate::encrypt_data(b"forged", &other_key, "forged").expect("encrypt failed"),
+            ));
+            assert!(restore(foreign, None).await.is_err());
+            assert!(restore(vec![], None).await.is_err());
+            assert_eq!(
+                list_all_keys().await.expect("list_all_keys failed").len(),
+                2
+            );
+            assert!(undo_restore().await.is_err());
+
+            restore(before.clone(), None).await.expect("restore failed");
+            assert_eq!(
+                list_all_keys().await.expect("list_all_keys failed"),
+                vec!["test::restore::before".to_string()]
+            );
+            assert_eq!(dump().await.expect("dump failed"), before);
+
+            undo_restore().await.expect("undo_restore failed");
+            assert_eq!(
+                list_all_keys().await.expect("list_all_keys failed").len(),
+                2
+            );
+            let content = get_content("test::restore::after")
+                .await
+                .expect("get_content failed");
+            assert_eq!(content.as_str(), "after");
+
+            // A locked vault checks the dump with its own key slots
+            crate::lock().expect("lock failed");
+            assert!(restore(before.clone(), None).await.is_err());
+            assert!(restore(before.clone(), Some("wrong".into())).await.is_err());
+            let mut unchecked = before.clone();
+            unchecked.retain(|(key, _)| !key.starts_with(SLOT_PREFIX));
+            assert!(restore(unchecked, Some("passphrase".into())).await.is_err());
+            restore(before.clone(), Some("passphrase".into()))
+                .await
+                .expect("restore failed");
+            assert_eq!(dump().await.expect("dump failed"), before);
+        });
+    }
+
+    #[test]
+    fn test_rekey_drops_backup() {
+        crate::test_config();
+
+        pollster::block_on(async {
+            init(Some("passphrase".into())).await.expect("init failed");
+            crate::slot::add("other".into(), "other".into(), None)
+                .await
+                .expect("add failed");
+            save_content("test::restore::login".into(), "secret".into())
+                .await
+                .expect("save_content failed");
+            restore(dump().await.expect("dump failed"), None)
+                .await
+                .expect("restore failed");
+
+            // The backup holds the old key and the slot wrapping it
+            crate::rekey("passphrase".into())
+                .await
+                .expect("rekey failed");
+            assert!(undo_restore().await.is_err());
+            crate::lock().expect("lock failed");
+            assert!(crate::unlock("other".into(), None).await.is_err());
+            crate::unlock("passphrase".into(), None)
+                .await
+                .expect("unlock failed");
+
+            restore(dump().await.expect("dump failed"), None)
+                .await
+                .expect("restore failed");
+            crate::change_passphrase("changed".into())
+                .await
+                .expect("change_passphrase failed");
+            assert!(undo_restore().await.is_err());
+            crate::lock().expect("lock failed");
+            assert!(crate::unlock("passphrase".into(), None).await.is_err());
+            crate::unlock("changed".into(), None)
+                .await
+                .expect("unlock failed");
+            let content = get_content("test::restore::login")
+                .await
+                .expect("get_content failed");
+            assert_eq!(content.as_str(), "secret");
+        });
+    }
+
+    #[test]
+    fn test_migrate() {
+        let _lock = crate::TEST_MUTEX.lock().unwrap();
+        crate::test_config();
+
+        pollster::block_on(async {
+            init(Some("passphrase".into())).await.expect("init failed");
+            save_content("test::migrate::login".into(), "secret".into())
+                .await
+                .expect("save_content failed");
+            // Start from an empty database left by a previous run
+            self::sqlite::initialize().expect("initialize failed");
+
+            assert!(migrate(Type::Memory).await.is_err());
+            let entries = backend(Type::Memory)
+                .expect("backend failed")
+                .entries()
+                .await
+                .expect("entries failed");
+            let count = migrate(Type::Sqlite).await.expect("migrate failed");
+            assert_eq!(count, entries.len());
+            let copied = backend(Type::Sqlite)
+                .expect("backend failed")
+                .entries()
+                .await
+                .expect("entries failed");
+            assert_eq!(copied, entries);
+            // An existing vault is never overwritten
+            assert!(migrate(Type::Sqlite).await.is_err());
+        });
+    }
+}
@@ -0,0 +1,538 @@
+//! One encrypted file per entry, in a directory that can be versioned with git.
+//!
+//! Keys are split on `::` into directories: `__baza__::index` is stored in
+//! `__baza__/index.baza`. Bundles are stored under their identifier, so file names
+//! reveal no bundle names, unless the `paths` layout keys them by name and
+//! `work::github::login` ends up in `work/github/login.baza`. Writes go through a journal that is replayed after a crash,
+//! so a batch is either fully applied or not at all. Replaced and removed files, the
+//! journal included, are overwritten with zeros first.
+
+use async_trait::async_trait;
+use exn::ResultExt;
+use std::collections::BTreeMap;
+use std::fs::{self, File, TryLockError};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+use crate::{
+    error::Error,
+    storage::{Change, StorageBackend, Transaction, TransactionFn},
+    BazaR, Config,
+};
+
+const DIR: &str = "files";
+const EXTENSION: &str = "baza";
+const KEY_DELIMITER: &str = "::";
+/// Present in every initialized vault directory.
+const MARKER: &str = ".baza-vault";
+const JOURNAL: &str = ".journal";
+const LOCK: &str = ".lock";
+const GITIGNORE: &str = ".gitignore";
+/// Failed unlock attempts, and the total seen by the last unlock, are counted by each
+/// copy of the vault on its own.
+const IGNORED: &str =
+    ".lock\n.journal\n*.tmp\n/__baza__/auth/attempts.baza\n/__baza__/auth/attempts/seen.baza\n";
+/// How long to wait for another baza process to finish writing.
+const LOCK_TIMEOUT: Duration = Duration::from_secs(3);
+
+static INSTANCE: OnceLock<Files> = OnceLock::new();
+
+pub struct Files {
+    root: PathBuf,
+}
+
+/// Percent-encode a key segment into a portable file name.
+fn encode(segment: &str) -> String {
+    if segment.is_empty() {
+        return "%".into();
+    }
+    let mut name = String::with_capacity(segment.len());
+    for (i, byte) in segment.bytes().enumerate() {
+        match byte {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_' => name.push(byte as char),
+            // Names never start with a dot, hidden files are not entries
+            b'.' if i > 0 => name.push('.'),
+            _ => name.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    name
+}
+
+fn decode(name: &str) -> Option<String> {
+    if name == "%" {
+        return Some(String::new());
+    }
+    let mut bytes = Vec::with_capacity(name.len());
+    let mut rest = name.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'%' {
+            let hex = std::str::from_utf8(tail.get(..2)?).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(byte);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+/// Exclusive lock of the vault directory, released when dropped.
+pub(super) struct Lock(File);
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        let _ = self.0.unlock();
+    }
+}
+
+/// Pending writes of a transaction over the files on disk.
+struct FilesTransaction<'a> {
+    files: &'a Files,
+    changes: BTreeMap<String, Option<Vec<u8>>>,
+}
+
+impl Transaction for FilesTransaction<'_> {
+    fn list_keys(&self) -> BazaR<Vec<String>> {
+        let mut keys = self.files.keys()?;
+        keys.retain(|key| !self.changes.contains_key(key));
+        keys.extend(
+            self.changes
+                .iter()
+                .filter(|(_, value)| value.is_some())
+                .map(|(key, _)| key.clone()),
+        );
+        keys.sort();
+        Ok(keys)
+    }
+
+    fn get(&self, key: &str) -> BazaR<Option<Vec<u8>>> {
+        match self.changes.get(key) {
+            Some(value) => Ok(value.clone()),
+            None => self.files.read(key),
+        }
+    }
+
+    fn set(&mut self, key: &str, value: Vec<u8>) -> BazaR<()> {
+        self.changes.insert(key.to_string(), Some(value));
+        Ok(())
+    }
+
+    fn remove(&mut self, key: &str) -> BazaR<()> {
+        self.changes.insert(key.to_string(), None);
+        Ok(())
+    }
+}
+
+impl Files {
+    fn get() -> &'static Files {
+        // The vault directory is `storage.path`, or `data/files` in the data directory
+        INSTANCE.get_or_init(|| match &Config::get().storage.path {
+            Some(path) => Self::new(PathBuf::from(path)),
+            None => Self::new(super::storage_dir(DIR)),
+        })
+    }
+
+    pub(crate) fn instance() -> BazaR<&'static dyn StorageBackend> {
+        Ok(Self::get())
+    }
+
+    pub fn new(root: PathBuf) -> Self {
+        Self { root }
+    }
+
+    pub(super) fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub(super) fn path(&self, key: &str) -> PathBuf {
+        let mut path = self.root.clone();
+        for segment in key.split(KEY_DELIMITER) {
+            path.push(encode(segment));
+        }
+        path.set_extension(match path.extension() {
+            Some(extension) => format!("{}.{EXTENSION}", extension.to_string_lossy()),
+            None => EXTENSION.to_string(),
+        });
+        path
+    }
+
+    /// Key stored at `path`, `None` for files that are not entries.
+    pub(super) fn key(&self, path: &Path) -> Option<String> {
+        let relative = path.strip_prefix(&self.root).ok()?;
+        let mut segments = Vec::new();
+        for component in relative.components() {
+            let name = component.as_os_str().to_str()?;
+            if name.starts_with('.') {
+                return None;
+            }
+            segments.push(name);
+        }
+        let last = segments.pop()?.strip_suffix(&format!(".{EXTENSION}"))?;
+        segments.push(last);
+        let segments: Option<Vec<String>> = segments.into_iter().map(decode).collect();
+        Some(segments?.join(KEY_DELIMITER))
+    }
+
+    fn keys(&self) -> BazaR<Vec<String>> {
+        let mut keys = Vec::new();
+        for entry in walkdir::WalkDir::new(&self.root).min_depth(1) {
+            let entry = entry.map_err(|e| exn::Exn::new(e.into()))?;
+            if entry.file_type().is_file() {
+                keys.extend(self.key(entry.path()));
+            }
+        }
+        keys.sort();
+        Ok(keys)
+    }
+
+    pub(super) fn read(&self, key: &str) -> BazaR<Option<Vec<u8>>> {
+        match fs::read(self.path(key)) {
+            Ok(value) => Ok(Some(value)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).or_raise(|| Error::Message(format!("Failed to read entry '{key}'"))),
+        }
+    }
+
+    /// Wait for other baza processes and finish a batch interrupted by a crash.
+    pub(super) fn lock(&self) -> BazaR<Lock> {
+        if !self.root.join(MARKER).exists() {
+            exn::bail!(Error::Message(format!(
+                "Vault directory '{}' is not initialized",
+                self.root.display()
+            )));
+        }
+        let file = File::options()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(self.root.join(LOCK))
+            .or_raise(|| Error::Message("Failed to open lock file".into()))?;
+        let started = Instant::now();
+        loop {
+            match file.try_lock() {
+                Ok(()) => break,
+                Err(TryLockError::WouldBlock) if started.elapsed() < LOCK_TIMEOUT => {
+                    std::thread::sleep(Duration::from_millis(100));
+                }
+                Err(TryLockError::WouldBlock) => exn::bail!(Error::Message(format!(
+                    "Vault directory '{}' is in use by another baza process",
+                    self.root.display()
+                ))),
+                Err(TryLockError::Error(e)) => {
+                    return Err(e)
+                        .or_raise(|| Error::Message("Failed to lock vault directory".into()))
+                }
+            }
+        }
+        let lock = Lock(file);
+        self.recover()?;
+        Ok(lock)
+    }
+
+    fn recover(&self) -> BazaR<()> {
+        let journal = self.root.join(JOURNAL);
+        let raw = match fs::read(&journal) {
+            Ok(raw) => raw,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(exn::Exn::new(e.into())),
+        };
+        tracing::warn!("Finishing a write interrupted in '{}'", self.root.display());
+        let changes = postcard::from_bytes(&raw)
+            .or_raise(|| Error::Message("Failed to parse write journal".into()))?;
+        self.replay(changes)?;
+        super::shred(&journal)
+    }
+
+    /// Apply journaled changes while holding the lock. Replaying them twice gives the same result.
+    pub(super) fn replay(&self, changes: Vec<(String, Option<Vec<u8>>)>) -> BazaR<()> {
+        for (key, value) in changes {
+            let path = self.path(&key);
+            // Old values are overwritten, a crash in between is replayed from the journal
+            match value {
+                Some(value) => {
+                    if path.exists() {
+                        super::overwrite(&path)?;
+                    }
+                    write_file(&path, &value)?
+                }
+                None => {
+                    if path.exists() {
+                        super::shred(&path)?;
+                    }
+                    self.prune(&path);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove directories left empty by a removal, git does not keep them anyway.
+    fn prune(&self, path: &Path) {
+        let mut dir = path.parent();
+        while let Some(current) = dir.filter(|dir| *dir != self.root) {
+            if fs::remove_dir(current).is_err() {
+                break;
+            }
+            dir = current.parent();
+        }
+    }
+
+    fn commit(&self, changes: BTreeMap<String, Option<Vec<u8>>>) -> BazaR<()> {
+        if changes.is_empty() {
+            return Ok(());
+        }
+        let changes: Vec<(String, Option<Vec<u8>>)> = changes.into_iter().collect();
+        let journal = postcard::to_stdvec(&changes)
+            .or_raise(|| Error::Message("Failed to serialize write journal".into()))?;
+        write_file(&self.root.join(JOURNAL), &journal)?;
+        self.replay(changes)?;
+        super::shred(&self.root.join(JOURNAL))
+    }
+
+    /// Remove every entry, keeping other files such as a git repository.
+    pub fn initialize(&self) -> BazaR<()> {
+        fs::create_dir_all(&self.root).or_raise(|| {
+            Error::Message(format!(
+                "Failed to create vault directory '{}'",
+                self.root.display()
+            ))
+        })?;
+        let _ = fs::remove_file(self.root.join(JOURNAL));
+        for key in self.keys()? {
+            let path = self.path(&key);
+            super::shred(&path)?;
+            self.prune(&path);
+        }
+        if !self.root.join(GITIGNORE).exists() {
+            write_file(&self.root.join(GITIGNORE), IGNORED.as_bytes())?;
+        }
+        write_file(&self.root.join(MARKER), b"1\n")
+    }
+}
+
+/// Replace the file at `path` atomically and durably.
+fn write_file(path: &Path, value: &[u8]) -> BazaR<()> {
+    let failed = || Error::Message(format!("Failed to write '{}'", path.display()));
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).or_raise(failed)?;
+    }
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let mut file = File::create(&tmp).or_raise(failed)?;
+    file.write_all(value).or_raise(failed)?;
+    file.sync_all().or_raise(failed)?;
+    fs::rename(&tmp, path).or_raise(failed)
+}
+
+pub fn initialize() -> BazaR<()> {
+    Files::get().initialize()
+}
+
+#[async_trait(?Send)]
+impl StorageBackend for Files {
+    async fn is_initialized(&self) -> BazaR<bool> {
+        Ok(self.root.join(MARKER).exists())
+    }
+
+    async fn list_keys(&self) -> BazaR<Vec<String>> {
+        let _lock = self.lock()?;
+        self.keys()
+    }
+
+    async fn entries(&self) -> BazaR<Vec<(String, Vec<u8>)>> {
+        let _lock = self.lock()?;
+        let mut entries = Vec::new();
+        for key in self.keys()? {
+            if let Some(value) = self.read(&key)? {
+                entries.push((key, value));
+            }
+        }
+        Ok(entries)
+    }
+
+    async fn get(&self, key: &str) -> BazaR<Vec<u8>> {
+        let _lock = self.lock()?;
+        self.read(key)?
+            .ok_or_else(|| exn::Exn::new(Error::Message("No such key".into())))
+    }
+
+    async fn get_many(&self, keys: &[String]) -> BazaR<Vec<Vec<u8>>> {
+        let _lock = self.lock()?;
+        keys.iter()
+            .map(|key| {
+                self.read(key)?
+                    .ok_or_else(|| exn::Exn::new(Error::Message("No such key".into())))
+            })
+            .collect()
+    }
+
+    async fn set(&self, key: &str, value: Vec<u8>) -> BazaR<()> {
+        self.apply(vec![Change::Set(key.to_string(), value)]).await
+    }
+
+    async fn set_many(&self, entries: Vec<(String, Vec<u8>)>) -> BazaR<()> {
+        self.apply(
+            entries
+                .into_iter()
+                .map(|(key, value)| Change::Set(key, value))
+                .collect(),
+        )
+        .await
+    }
+
+    async fn remove(&self, key: &str) -> BazaR<()> {
+        self.apply(vec![Change::Remove(key.to_string())]).await
+    }
+
+    async fn apply(&self, changes: Vec<Change>) -> BazaR<()> {
+        self.transaction(Box::new(move |tx| {
+            for change in changes {
+                match change {
+                    Change::Set(key, value) => tx.set(&key, value)?,
+                    Change::Remove(key) => tx.remove(&key)?,
+                }
+            }
+            Ok(())
+        }))
+        .await
+    }
+
+    async fn transaction<'a>(&self, f: TransactionFn<'a>) -> BazaR<()> {
+        let _lock = self.lock()?;
+        let mut tx = FilesTransaction {
+            files: self,
+            changes: BTreeMap::new(),
+        };
+        f(&mut tx)?;
+        self.commit(tx.changes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn files() -> (tempfile::TempDir, Files) {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        let files = Files::new(dir.path().join("vault"));
+        files.initialize().expect("initialize failed");
+        (dir, files)
+    }
+
+    #[test]
+    fn test_paths() {
+        let (_dir, files) = files();
+        for key in [
+            "__baza__::index",
+            "ab12",
+            "__baza__::auth::slot::Ålice/../x",
+            "a::::.b",
+        ] {
+            let path = files.path(key);
+            assert!(path.starts_with(&files.root));
+            assert_eq!(files.key(&path).as_deref(), Some(key));
+        }
+        assert_eq!(
+            files.path("__baza__::index"),
+            files.root.join("__baza__").join("index.baza")
+        );
+        assert_eq!(
+            files.path("work::github::login"),
+            files.root.join("work").join("github").join("login.baza")
+        );
+        assert!(files.key(&files.root.join(".git").join("HEAD")).is_none());
+        assert!(files.key(&files.root.join("README.md")).is_none());
+    }
+
+    #[test]
+    fn test_files_backend() {
+        let (_dir, files) = files();
+        pollster::block_on(async {
+            assert!(files.is_initialized().await.expect("is_initialized failed"));
+            files
+                .set_many(vec![
+                    ("__baza__::index".into(), b"1".to_vec()),
+                    ("ab12".into(), b"2".to_vec()),
+                ])
+                .await
+                .expect("set_many failed");
+            assert_eq!(
+                files.list_keys().await.expect("list_keys failed"),
+                ["__baza__::index", "ab12"]
+            );
+            assert_eq!(files.get("ab12").await.expect("get failed"), b"2");
+
+            // A failing transaction leaves no trace
+            let result = files
+                .transaction(Box::new(|tx| {
+                    tx.remove("ab12")?;
+                    tx.set("cd34", b"3".to_vec())?;
+                    assert_eq!(tx.list_keys()?, ["__baza__::index", "cd34"]);
+                    exn::bail!(Error::Message("Interrupted".into()))
+                }))
+                .await;
+            assert!(result.is_err());
+            assert_eq!(
+                files.list_keys().await.expect("list_keys failed"),
+                ["__baza__::index", "ab12"]
+            );
+
+            // A batch interrupted after its journal was written is finished on next use
+            let journal: Vec<(String, Option<Vec<u8>>)> = vec![
+                ("__baza__::index".into(), None),
+                ("cd34".into(), Some(b"4".to_vec())),
+            ];
+            let raw = postcard::to_stdvec(&journal).expect("serialize failed");
+            write_file(&files.root.join(JOURNAL), &raw).expect("write failed");
+            assert_eq!(
+                files.entries().await.expect("entries failed"),
+                [
+                    ("ab12".to_string(), b"2".to_vec()),
+                    ("cd34".to_string(), b"4".to_vec())
+                ]
+            );
+            assert!(!files.root.join(JOURNAL).exists());
+            assert!(!files.root.join("__baza__").exists());
+
+            // Re-initializing keeps files that are not entries
+            fs::write(files.root.join("README.md"), "notes").expect("write failed");
+            files.initialize().expect("initialize failed");
+            assert!(files
+                .list_keys()
+                .await
+                .expect("list_keys failed")
+                .is_empty());
+            assert!(files.root.join("README.md").exists());
+        });
+    }
+
+    #[test]
+    fn test_overwrite() {
+        let (dir, files) = files();
+        // A second link keeps the old data readable after the file is replaced
+        let link = |name: &str| {
+            let link = dir.path().join(name);
+            fs::hard_link(files.path("ab12"), &link).expect("link failed");
+            link
+        };
+        pollster::block_on(async {
+            files
+                .set("ab12", b"old".to_vec())
+                .await
+                .expect("set failed");
+            let replaced = link("replaced");
+            files
+                .set("ab12", b"new".to_vec())
+                .await
+                .expect("set failed");
+            assert_eq!(fs::read(&replaced).expect("read failed"), [0; 3]);
+
+            let removed = link("removed");
+            files.remove("ab12").await.expect("remove failed");
+            assert_eq!(fs::read(&removed).expect("read failed"), [0; 3]);
+        });
+    }
+}
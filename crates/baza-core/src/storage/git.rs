@@ -0,0 +1,426 @@
+//! The `files` layout inside a git repository, with a commit for every change.
+//!
+//! Runs the `git` command, so anything git can push to works as a remote, including a
+//! bare repository on a shared disk. Pulling merges the remote branch; changes of the
+//! name index and of metadata in both copies are merged entry by entry, other entries
+//! changed on both sides abort the pull.
+
+use async_trait::async_trait;
+use exn::ResultExt;
+use std::collections::{BTreeSet, HashMap};
+use std::ffi::OsStr;
+use std::path::PathBuf;
+use std::process::{Command, Output};
+use std::sync::OnceLock;
+
+use crate::{
+    error::Error,
+    index, meta,
+    storage::{files::Files, Change, StorageBackend, TransactionFn},
+    BazaR, Config, Type, DEFAULT_AUTHOR, INDEX_KEY, META_PREFIX,
+};
+
+const DIR: &str = "git";
+const BRANCH: &str = "main";
+/// Committer e-mail when git has none configured.
+const EMAIL: &str = "baza@localhost";
+
+static INSTANCE: OnceLock<Git> = OnceLock::new();
+
+pub struct Git {
+    files: Files,
+}
+
+impl Git {
+    fn get() -> &'static Git {
+        // The repository is `storage.path`, or `data/git` in the data directory
+        INSTANCE.get_or_init(|| match &Config::get().storage.path {
+            Some(path) => Self::new(PathBuf::from(path)),
+            None => Self::new(super::storage_dir(DIR)),
+        })
+    }
+
+    pub(crate) fn instance() -> BazaR<&'static dyn StorageBackend> {
+        Ok(Self::get())
+    }
+
+    pub fn new(root: PathBuf) -> Self {
+        Self {
+            files: Files::new(root),
+        }
+    }
+
+    fn run<S: AsRef<OsStr>>(&self, args: &[S]) -> BazaR<Output> {
+        Command::new("git")
+            .arg("-C")
+            .arg(self.files.root())
+            .args(args)
+            .output()
+            .or_raise(|| Error::Message("Failed to run git, is it installed?".into()))
+    }
+
+    /// Output of a git command that has to succeed.
+    fn git<S: AsRef<OsStr>>(&self, args: &[S]) -> BazaR<Vec<u8>> {
+        let output = self.run(args)?;
+        if !output.status.success() {
+            let command: Vec<_> = args
+                .iter()
+                .map(|arg| arg.as_ref().to_string_lossy())
+                .collect();
+            exn::bail!(Error::Message(format!(
+                "git {} failed: {}",
+                command.join(" "),
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        Ok(output.stdout)
+    }
+
+    /// Options making the configured author the committer when git has no identity.
+    fn identity(&self) -> BazaR<Vec<String>> {
+        if self.run(&["config", "user.email"])?.status.success() {
+            return Ok(Vec::new());
+        }
+        let author = Config::get()
+            .main
+            .author
+            .clone()
+            .unwrap_or_else(|| DEFAULT_AUTHOR.to_string());
+        Ok(vec![
+            "-c".into(),
+            format!("user.name={author}"),
+            "-c".into(),
+            format!("user.email={EMAIL}"),
+        ])
+    }
+
+    /// Commit the staged changes.
+    fn commit(&self, args: &[&str]) -> BazaR<()> {
+        let mut command = self.identity()?;
+        command.extend(["commit".into(), "-q".into()]);
+        command.extend(args.iter().map(|arg| arg.to_string()));
+        self.git(&command).map(|_| ())
+    }
+
+    /// Commit every change of the working tree, if there is any. The caller holds the lock.
+    fn commit_all(&self, message: &str) -> BazaR<()> {
+        self.git(&["add", "-A"])?;
+        if self.run(&["diff", "--cached", "--quiet"])?.status.success() {
+            return Ok(());
+        }
+        self.commit(&["-m", message])
+    }
+
+    pub fn initialize(&self) -> BazaR<()> {
+        self.files.initialize()?;
+        if !self.files.root().join(".git").exists() {
+            self.git(&["init", "-q", "-b", BRANCH])?;
+        }
+        Ok(())
+    }
+
+    fn push_to(&self, remote: &str) -> BazaR<()> {
+        let _lock = self.files.lock()?;
+        self.commit_all("Sync changes")?;
+        self.git(&["push", "-q", remote, &format!("HEAD:refs/heads/{BRANCH}")])
+            .or_raise(|| Error::Message(format!("Failed to push to '{remote}'")))?;
+        Ok(())
+    }
+
+    fn pull_from(&self, remote: &str, key: &[u8]) -> BazaR<()> {
+        let _lock = self.files.lock()?;
+        self.commit_all("Sync changes")?;
+        self.git(&["fetch", "-q", remote, BRANCH])
+            .or_raise(|| Error::Message(format!("Failed to fetch from '{remote}'")))?;
+        let mut command = self.identity()?;
+        command.extend(["merge", "-q", "--no-edit", "FETCH_HEAD"].map(String::from));
+        let merge = self.run(&command)?;
+        if merge.status.success() {
+            return Ok(());
+        }
+        let conflicts: Vec<String> =
+            String::from_utf8_lossy(&self.git(&["diff", "--name-only", "--diff-filter=U"])?)
+                .lines()
+                .map(str::to_string)
+                .collect();
+        if conflicts.is_empty() {
+            exn::bail!(Error::Message(format!(
+                "Failed to merge '{remote}': {}",
+                String::from_utf8_lossy(&merge.stderr).trim()
+            )));
+        }
+        if let Err(e) = self.resolve(&conflicts, key) {
+            self.git(&["merge", "--abort"])?;
+            return Err(e);
+        }
+        self.git(&["add", "-A"])?;
+        self.commit(&["--no-edit"])
+    }
+
+    /// Version of the conflicting `path` on one side of a merge, `None` if it was removed there.
+    fn side(&self, path: &str, theirs: bool) -> BazaR<Option<Vec<u8>>> {
+        let stage = if theirs { 3 } else { 2 };
+        let output = self.run(&["show", &format!(":{stage}:{path}")])?;
+        Ok(output.status.success().then_some(output.stdout))
+    }
+
+    fn resolve(&self, conflicts: &[String], key: &[u8]) -> BazaR<()> {
+        let mut index_path = None;
+        let mut metas = Vec::new();
+        for path in conflicts {
+            match self.files.key(&self.files.root().join(path)) {
+                Some(entry) if entry == INDEX_KEY => index_path = Some(path),
+                Some(entry) if entry.starts_with(META_PREFIX) => metas.push((path, entry)),
+                _ => exn::bail!(Error::Message(format!(
+                    "Pull conflicts with local changes of {} entries, resolve them with git in '{}'",
+                    conflicts.len(),
+                    self.files.root().display()
+                ))),
+            }
+        }
+
+        // Names of both sides, kept when their bundle made it into the merged tree
+        let mut names = BTreeSet::new();
+        match index_path {
+            Some(path) => {
+                for theirs in [false, true] {
+                    if let Some(encrypted) = self.side(path, theirs)? {
+                        names.extend(index::open(&encrypted, key)?);
+                    }
+                }
+            }
+            None => {
+                if let Some(encrypted) = self.files.read(INDEX_KEY)? {
+                    names = index::open(&encrypted, key)?;
+                }
+            }
+        }
+        let mut ids = HashMap::new();
+        for name in names {
+            let id = index::id(&name, key)?;
+            if self.files.path(&id).exists() {
+                ids.insert(id, name);
+            }
+        }
+
+        let mut changes = Vec::new();
+        if index_path.is_some() {
+            let names: BTreeSet<String> = ids.values().cloned().collect();
+            changes.push((INDEX_KEY.to_string(), Some(index::seal(&names, key)?)));
+        }
+        for (path, entry) in metas {
+            let id = &entry[META_PREFIX.len()..];
+            let Some(name) = ids.get(id) else {
+                // The bundle is gone, so is its metadata
+                changes.push((entry, None));
+                continue;
+            };
+            let mut merged: Option<meta::Meta> = None;
+            for theirs in [false, true] {
+                if let Some(encrypted) = self.side(path, theirs)? {
+                    let side = meta::open(name, &encrypted, key)?;
+                    merged = Some(match merged {
+                        Some(merged) => merged.merge(side),
+                        None => side,
+                    });
+                }
+            }
+            if let Some(Change::Set(_, encrypted)) =
+                merged.map(|meta| meta.seal(name, key)).transpose()?
+            {
+                changes.push((entry, Some(encrypted)));
+            }
+        }
+        self.files.replay(changes)
+    }
+}
+
+pub fn initialize() -> BazaR<()> {
+    Git::get().initialize()
+}
+
+/// The vault repository and the remote to sync it with: `remote`, or `storage.remote`.
+fn repository(remote: Option<String>) -> BazaR<(&'static Git, String)> {
+    let config = Config::get();
+    if !matches!(super::current_type(), Type::Git) {
+        exn::bail!(Error::Message(
+            "The vault is not stored in git, set storage.type = \"git\"".into()
+        ));
+    }
+    let Some(remote) = remote.or_else(|| config.storage.remote.clone()) else {
+        exn::bail!(Error::Message(
+            "No remote to sync with, set storage.remote".into()
+        ));
+    };
+    Ok((Git::get(), remote))
+}
+
+/// Commit pending changes and push them to the remote.
+pub async fn push(remote: Option<String>) -> BazaR<()> {
+    let (git, remote) = repository(remote)?;
+    git.push_to(&remote)?;
+    tracing::info!("Vault pushed to '{remote}'");
+    Ok(())
+}
+
+/// Commit pending changes and merge the changes of the remote.
+pub async fn pull(remote: Option<String>) -> BazaR<()> {
+    let key = crate::key()?;
+    let (git, remote) = repository(remote)?;
+    git.pull_from(&remote, &key)?;
+    tracing::info!("Vault pulled from '{remote}'");
+    Ok(())
+}
+
+#[async_trait(?Send)]
+impl StorageBackend for Git {
+    async fn is_initialized(&self) -> BazaR<bool> {
+        self.files.is_initialized().await
+    }
+
+    async fn list_keys(&self) -> BazaR<Vec<String>> {
+        self.files.list_keys().await
+    }
+
+    async fn entries(&self) -> BazaR<Vec<(String, Vec<u8>)>> {
+        self.files.entries().await
+    }
+
+    async fn get(&self, key: &str) -> BazaR<Vec<u8>> {
+        self.files.get(key).await
+    }
+
+    async fn get_many(&self, keys: &[String]) -> BazaR<Vec<Vec<u8>>> {
+        self.files.get_many(keys).await
+    }
+
+    async fn set(&self, key: &str, value: Vec<u8>) -> BazaR<()> {
+        self.files.set(key, value).await
+    }
+
+    async fn set_many(&self, entries: Vec<(String, Vec<u8>)>) -> BazaR<()> {
+        self.files.set_many(entries).await
+    }
+
+    async fn remove(&self, key: &str) -> BazaR<()> {
+        self.files.remove(key).await
+    }
+
+    async fn apply(&self, changes: Vec<Change>) -> BazaR<()> {
+        self.files.apply(changes).await
+    }
+
+    async fn transaction<'a>(&self, f: TransactionFn<'a>) -> BazaR<()> {
+        self.files.transaction(f).await
+    }
+
+    async fn checkpoint(&self, message: &str) -> BazaR<()> {
+        let _lock = self.files.lock()?;
+        self.commit_all(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keyring;
+
+    fn commits(git: &Git) -> usize {
+        let count = git
+            .git(&["rev-list", "--count", "HEAD"])
+            .expect("rev-list failed");
+        String::from_utf8_lossy(&count).trim().parse().unwrap()
+    }
+
+    /// Add the bundle `name` with `content` to the vault in `git`.
+    async fn save(git: &Git, name: &str, content: &str, key: &[u8]) {
+        let mut names = match git.files.read(INDEX_KEY).expect("read failed") {
+            Some(encrypted) => index::open(&encrypted, key).expect("open failed"),
+            None => BTreeSet::new(),
+        };
+        names.insert(name.to_string());
+        let id = index::id(name, key).expect("id failed");
+        let encrypted = crate::encrypt_data(content.as_bytes(), key, name).expect("encrypt failed");
+        git.apply(vec![
+            Change::Set(id, encrypted),
+            Change::Set(
+                INDEX_KEY.into(),
+                index::seal(&names, key).expect("seal failed"),
+            ),
+        ])
+        .await
+        .expect("apply failed");
+        git.checkpoint(&format!("Save {name}"))
+            .await
+            .expect("checkpoint failed");
+    }
+
+    async fn names(git: &Git, key: &[u8]) -> Vec<String> {
+        let encrypted = git.get(INDEX_KEY).await.expect("get failed");
+        index::open(&encrypted, key)
+            .expect("open failed")
+            .into_iter()
+            .collect()
+    }
+
+    #[test]
+    fn test_git_sync() {
+        crate::test_config();
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        let remote = dir.path().join("remote.git").to_string_lossy().to_string();
+        let key = keyring::generate();
+
+        pollster::block_on(async {
+            let alice = Git::new(dir.path().join("alice"));
+            alice.initialize().expect("initialize failed");
+            save(&alice, "shared", "first", &key).await;
+            assert_eq!(commits(&alice), 1);
+            // Nothing to commit, no commit
+            alice
+                .checkpoint("Nothing")
+                .await
+                .expect("checkpoint failed");
+            assert_eq!(commits(&alice), 1);
+
+            alice
+                .git(&["init", "-q", "--bare", &remote])
+                .expect("init failed");
+            alice.push_to(&remote).expect("push failed");
+            let bob = Git::new(dir.path().join("bob"));
+            alice
+                .git(&[
+                    "clone",
+                    "-q",
+                    "-b",
+                    BRANCH,
+                    &remote,
+                    &bob.files.root().to_string_lossy(),
+                ])
+                .expect("clone failed");
+            assert_eq!(names(&bob, &key).await, ["shared"]);
+
+            // Bundles added on both sides end up in the merged name index
+            save(&alice, "alice", "a", &key).await;
+            alice.push_to(&remote).expect("push failed");
+            save(&bob, "bob", "b", &key).await;
+            assert!(bob.push_to(&remote).is_err());
+            bob.pull_from(&remote, &key).expect("pull failed");
+            assert_eq!(names(&bob, &key).await, ["alice", "bob", "shared"]);
+            bob.push_to(&remote).expect("push failed");
+            alice.pull_from(&remote, &key).expect("pull failed");
+            assert_eq!(names(&alice, &key).await, ["alice", "bob", "shared"]);
+
+            // A bundle changed on both sides aborts the pull
+            save(&alice, "shared", "alice", &key).await;
+            alice.push_to(&remote).expect("push failed");
+            save(&bob, "shared", "bob", &key).await;
+            let head = bob.git(&["rev-parse", "HEAD"]).expect("rev-parse failed");
+            assert!(bob.pull_from(&remote, &key).is_err());
+            assert_eq!(
+                bob.git(&["rev-parse", "HEAD"]).expect("rev-parse failed"),
+                head
+            );
+            assert!(!bob.files.root().join(".git/MERGE_HEAD").exists());
+        });
+    }
+}
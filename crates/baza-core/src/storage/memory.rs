@@ -0,0 +1,237 @@
+//! Entries kept in memory only, gone when the process exits.
+//!
+//! Backs `storage.type = "memory"` for tests, and ephemeral sessions working on a dump
+//! without touching the vault on disk.
+
+use async_trait::async_trait;
+use std::collections::BTreeMap;
+#[cfg(not(test))]
+use std::sync::OnceLock;
+use std::sync::{Mutex, MutexGuard};
+
+use crate::{
+    error::Error,
+    storage::{Change, StorageBackend, Transaction, TransactionFn},
+    BazaR,
+};
+
+type Entries = BTreeMap<String, Vec<u8>>;
+
+#[cfg(not(test))]
+static INSTANCE: OnceLock<Memory> = OnceLock::new();
+
+/// `None` until initialized.
+#[derive(Default)]
+pub struct Memory {
+    entries: Mutex<Option<Entries>>,
+}
+
+impl Memory {
+    /// The instance of the process, or of the calling thread under test so that every
+    /// test gets a storage of its own.
+    pub(crate) fn get() -> &'static Memory {
+        #[cfg(not(test))]
+        {
+            INSTANCE.get_or_init(Memory::default)
+        }
+        #[cfg(test)]
+        {
+            thread_local! {
+                static INSTANCE: &'static Memory = Box::leak(Box::default());
+            }
+            INSTANCE.with(|memory| *memory)
+        }
+    }
+
+    pub(crate) fn instance() -> BazaR<&'static dyn StorageBackend> {
+        Ok(Self::get())
+    }
+
+    fn handle(&self) -> BazaR<MutexGuard<'_, Option<Entries>>> {
+        self.entries
+            .lock()
+            .map_err(|_| exn::Exn::new(Error::Message("Failed to lock memory storage".into())))
+    }
+
+    /// Run `f` on the entries, which have to be initialized.
+    fn with_entries<R>(&self, f: impl FnOnce(&mut Entries) -> BazaR<R>) -> BazaR<R> {
+        match self.handle()?.as_mut() {
+            Some(entries) => f(entries),
+            None => exn::bail!(Error::Message("Memory storage is not initialized".into())),
+        }
+    }
+
+    /// Replace every entry with `entries`.
+    pub fn load(&self, entries: Vec<(String, Vec<u8>)>) -> BazaR<()> {
+        *self.handle()? = Some(entries.into_iter().collect());
+        Ok(())
+    }
+
+    pub fn initialize(&self) -> BazaR<()> {
+        self.load(Vec::new())
+    }
+}
+
+pub fn initialize() -> BazaR<()> {
+    Memory::get().initialize()
+}
+
+/// Writes of a transaction, applied to a copy of the entries.
+struct MemoryTransaction {
+    entries: Entries,
+}
+
+impl Transaction for MemoryTransaction {
+    fn list_keys(&self) -> BazaR<Vec<String>> {
+        Ok(self.entries.keys().cloned().collect())
+    }
+
+    fn get(&self, key: &str) -> BazaR<Option<Vec<u8>>> {
+        Ok(self.entries.get(key).cloned())
+    }
+
+    fn set(&mut self, key: &str, value: Vec<u8>) -> BazaR<()> {
+        self.entries.insert(key.to_string(), value);
+        Ok(())
+    }
+
+    fn remove(&mut self, key: &str) -> BazaR<()> {
+        self.entries.remove(key);
+        Ok(())
+    }
+}
+
+fn required(entries: &Entries, key: &str) -> BazaR<Vec<u8>> {
+    entries
+        .get(key)
+        .cloned()
+        .ok_or_else(|| exn::Exn::new(Error::Message("No such key".into())))
+}
+
+#[async_trait(?Send)]
+impl StorageBackend for Memory {
+    async fn is_initialized(&self) -> BazaR<bool> {
+        Ok(self.handle()?.is_some())
+    }
+
+    async fn list_keys(&self) -> BazaR<Vec<String>> {
+        self.with_entries(|entries| Ok(entries.keys().cloned().collect()))
+    }
+
+    async fn entries(&self) -> BazaR<Vec<(String, Vec<u8>)>> {
+        self.with_entries(|entries| {
+            Ok(entries
+                .iter()
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect())
+        })
+    }
+
+    async fn get(&self, key: &str) -> BazaR<Vec<u8>> {
+        self.with_entries(|entries| required(entries, key))
+    }
+
+    async fn get_many(&self, keys: &[String]) -> BazaR<Vec<Vec<u8>>> {
+        self.with_entries(|entries| keys.iter().map(|key| required(entries, key)).collect())
+    }
+
+    async fn set(&self, key: &str, value: Vec<u8>) -> BazaR<()> {
+        self.apply(vec![Change::Set(key.to_string(), value)]).await
+    }
+
+    async fn set_many(&self, entries: Vec<(String, Vec<u8>)>) -> BazaR<()> {
+        self.apply(
+            entries
+                .into_iter()
+                .map(|(key, value)| Change::Set(key, value))
+                .collect(),
+        )
+        .await
+    }
+
+    async fn remove(&self, key: &str) -> BazaR<()> {
+        self.apply(vec![Change::Remove(key.to_string())]).await
+    }
+
+    async fn apply(&self, changes: Vec<Change>) -> BazaR<()> {
+        self.transaction(Box::new(move |tx| {
+            for change in changes {
+                match change {
+                    Change::Set(key, value) => tx.set(&key, value)?,
+                    Change::Remove(key) => tx.remove(&key)?,
+                }
+            }
+            Ok(())
+        }))
+        .await
+    }
+
+    async fn transaction<'a>(&self, f: TransactionFn<'a>) -> BazaR<()> {
+        self.with_entries(|entries| {
+            let mut tx = MemoryTransaction {
+                entries: entries.clone(),
+            };
+            f(&mut tx)?;
+            *entries = tx.entries;
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_memory_backend() {
+        // A backend of its own, no need to wait for other tests
+        let memory = Memory::default();
+
+        pollster::block_on(async {
+            assert!(!memory
+                .is_initialized()
+                .await
+                .expect("is_initialized failed"));
+            assert!(memory.list_keys().await.is_err());
+            memory.initialize().expect("initialize failed");
+            memory
+                .set_many(vec![
+                    ("b".into(), b"2".to_vec()),
+                    ("a".into(), b"1".to_vec()),
+                ])
+                .await
+                .expect("set_many failed");
+            assert_eq!(
+                memory
+                    .get_many(&["b".into(), "a".into()])
+                    .await
+                    .expect("get_many failed"),
+                [b"2".to_vec(), b"1".to_vec()]
+            );
+            assert!(memory.get("c").await.is_err());
+
+            // A failing transaction leaves no trace
+            let result = memory
+                .transaction(Box::new(|tx| {
+                    tx.remove("a")?;
+                    tx.set("c", b"3".to_vec())?;
+                    assert_eq!(tx.list_keys()?, ["b", "c"]);
+                    exn::bail!(Error::Message("Interrupted".into()))
+                }))
+                .await;
+            assert!(result.is_err());
+            assert_eq!(
+                memory.entries().await.expect("entries failed"),
+                [
+                    ("a".to_string(), b"1".to_vec()),
+                    ("b".to_string(), b"2".to_vec())
+                ]
+            );
+
+            memory
+                .load(vec![("c".into(), b"3".to_vec())])
+                .expect("load failed");
+            assert_eq!(memory.list_keys().await.expect("list_keys failed"), ["c"]);
+        });
+    }
+}
@@ -1,20 +1,33 @@
 use exn::ResultExt;
-use redb::{Database, ReadableDatabase, ReadableTable, TableDefinition};
-use std::sync::OnceLock;
+use redb::{Database, DatabaseError, ReadableDatabase, ReadableTable, TableDefinition};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
 
-use crate::{storage::StorageBackend, BazaR, Config};
+use crate::{
+    storage::{Change, Compaction, StorageBackend, Transaction, TransactionFn},
+    BazaR, Config,
+};
 
 const DIR: &str = "redb";
 const TABLE: TableDefinition<&str, Vec<u8>> = TableDefinition::new("passwords");
+/// How long to wait for another baza process to release the database file.
+const LOCK_TIMEOUT: Duration = Duration::from_secs(3);
 
+static INSTANCE: OnceLock<Redb> = OnceLock::new();
+
+/// The database is opened once per process and kept open, holding its file lock.
 pub struct Redb {
     path: String,
+    db: Mutex<Option<Arc<Database>>>,
 }
 
 impl Redb {
+    fn get() -> &'static Redb {
+        INSTANCE.get_or_init(|| Self::new().expect("Failed to initialize Redb storage"))
+    }
+
     pub(crate) fn instance() -> BazaR<&'static dyn StorageBackend> {
-        static INSTANCE: OnceLock<Redb> = OnceLock::new();
-        Ok(INSTANCE.get_or_init(|| Self::new().expect("Failed to initialize Redb storage")))
+        Ok(Self::get())
     }
 
     pub fn new() -> BazaR<Self> {
@@ -22,20 +35,130 @@ impl Redb {
             std::path::PathBuf::from(format!("{}/data/{}", &Config::get().main.datadir, DIR));
         std::fs::create_dir_all(&folder).map_err(|e| exn::Exn::new(e.into()))?;
         let path = format!("{}/db.redb", folder.to_string_lossy());
-        Ok(Self { path })
+        // A rewrite interrupted after the old file was overwritten left the new one behind
+        let rewritten = rewritten(&path);
+        if !std::path::Path::new(&path).exists() && std::path::Path::new(&rewritten).exists() {
+            std::fs::rename(&rewritten, &path).map_err(|e| exn::Exn::new(e.into()))?;
+        }
+        Ok(Self {
+            path,
+            db: Mutex::new(None),
+        })
     }
 
-    fn db(&self) -> BazaR<Database> {
-        Database::open(&self.path).map_err(|e| exn::Exn::new(e.into()))
+    fn handle(&self) -> BazaR<std::sync::MutexGuard<'_, Option<Arc<Database>>>> {
+        self.db.lock().map_err(|_| {
+            exn::Exn::new(crate::error::Error::Message(
+                "Failed to lock database mutex".into(),
+            ))
+        })
+    }
+
+    /// The open database, opened on first use.
+    fn db(&self) -> BazaR<Arc<Database>> {
+        let mut handle = self.handle()?;
+        if let Some(db) = handle.as_ref() {
+            return Ok(db.clone());
+        }
+        let db = Arc::new(open(&self.path)?);
+        *handle = Some(db.clone());
+        Ok(db)
+    }
+
+    /// Compact the database file, holding the database for the whole compaction.
+    fn compact_file(&self) -> BazaR<Compaction> {
+        let mut handle = self.handle()?;
+        if handle.is_none() {
+            *handle = Some(Arc::new(open(&self.path)?));
+        }
+        let path = std::path::Path::new(&self.path);
+        let before = super::file_size(path)?;
+        let db = handle.as_mut().and_then(Arc::get_mut).ok_or_else(|| {
+            exn::Exn::new(crate::error::Error::Message(
+                "Database is in use, try again later".into(),
+            ))
+        })?;
+        db.compact()
+            .or_raise(|| crate::error::Error::Message("Failed to compact database".into()))?;
+        Ok(Compaction {
+            before,
+            after: super::file_size(path)?,
+        })
+    }
+
+    /// Copy the entries to a new database file and overwrite the old one before replacing
+    /// it, so removed entries do not survive in its freed pages.
+    fn rewrite(&self) -> BazaR<()> {
+        let mut handle = self.handle()?;
+        if handle.is_none() {
+            *handle = Some(Arc::new(open(&self.path)?));
+        }
+        let db = handle.as_mut().and_then(Arc::get_mut).ok_or_else(|| {
+            exn::Exn::new(crate::error::Error::Message(
+                "Database is in use, try again later".into(),
+            ))
+        })?;
+        let failed = || crate::error::Error::Message("Failed to rewrite database".into());
+        let tmp = rewritten(&self.path);
+        if std::path::Path::new(&tmp).exists() {
+            std::fs::remove_file(&tmp).or_raise(failed)?;
+        }
+        {
+            let copy = Database::create(&tmp).or_raise(failed)?;
+            let read_txn = db.begin_read().or_raise(failed)?;
+            let table = read_txn.open_table(TABLE).or_raise(failed)?;
+            let write_txn = copy.begin_write().or_raise(failed)?;
+            {
+                let mut copied = write_txn.open_table(TABLE).or_raise(failed)?;
+                for result in table.iter().or_raise(failed)? {
+                    let (key, value) = result.or_raise(failed)?;
+                    copied.insert(key.value(), value.value()).or_raise(failed)?;
+                }
+            }
+            write_txn.commit().or_raise(failed)?;
+        }
+        *handle = None;
+        super::shred(std::path::Path::new(&self.path))?;
+        std::fs::rename(&tmp, &self.path).or_raise(failed)?;
+        *handle = Some(Arc::new(open(&self.path)?));
+        Ok(())
+    }
+}
+
+/// Path of the copy made by [`Redb::rewrite`].
+fn rewritten(path: &str) -> String {
+    format!("{path}.tmp")
+}
+
+/// Open the database file, waiting a little while another process holds its lock.
+fn open(path: &str) -> BazaR<Database> {
+    let started = Instant::now();
+    loop {
+        match Database::open(path) {
+            Ok(db) => return Ok(db),
+            Err(DatabaseError::DatabaseAlreadyOpen) if started.elapsed() < LOCK_TIMEOUT => {
+                std::thread::sleep(Duration::from_millis(100));
+            }
+            Err(DatabaseError::DatabaseAlreadyOpen) => {
+                exn::bail!(crate::error::Error::Message(format!(
+                    "Database '{path}' is in use by another baza process"
+                )))
+            }
+            Err(e) => return Err(exn::Exn::new(e.into())),
+        }
     }
 }
 
 pub fn initialize() -> BazaR<()> {
+    let redb = Redb::get();
+    // Release the file of the previous database before replacing it
+    let mut handle = redb.handle()?;
+    *handle = None;
     let folder = std::path::PathBuf::from(format!("{}/data/{}", &Config::get().main.datadir, DIR));
     std::fs::create_dir_all(&folder).map_err(|e| exn::Exn::new(e.into()))?;
-    let path = format!("{}/db.redb", folder.to_string_lossy());
-    if std::path::Path::new(&path).exists() {
-        std::fs::remove_file(&path).map_err(|e| exn::Exn::new(e.into()))?;
+    let path = &redb.path;
+    if std::path::Path::new(path).exists() {
+        super::shred(std::path::Path::new(path))?;
     }
     let db = Database::create(path).map_err(|e| exn::Exn::new(e.into()))?;
     let write_txn = db.begin_write().or_raise(|| {
@@ -49,9 +172,54 @@ pub fn initialize() -> BazaR<()> {
     write_txn.commit().or_raise(|| {
         crate::error::Error::Message("Failed to commit initialization transaction".into())
     })?;
+    *handle = Some(Arc::new(db));
     Ok(())
 }
 
+/// Table of an open write transaction.
+struct RedbTransaction<'txn> {
+    table: redb::Table<'txn, &'static str, Vec<u8>>,
+}
+
+impl Transaction for RedbTransaction<'_> {
+    fn list_keys(&self) -> BazaR<Vec<String>> {
+        let mut keys = Vec::new();
+        for result in self
+            .table
+            .iter()
+            .or_raise(|| crate::error::Error::Message("Failed to iterate over table".into()))?
+        {
+            let (key, _) = result.or_raise(|| {
+                crate::error::Error::Message("Failed to read entry from table".into())
+            })?;
+            keys.push(key.value().to_string());
+        }
+        Ok(keys)
+    }
+
+    fn get(&self, key: &str) -> BazaR<Option<Vec<u8>>> {
+        Ok(self
+            .table
+            .get(key)
+            .or_raise(|| crate::error::Error::Message("Failed to get value from table".into()))?
+            .map(|value| value.value()))
+    }
+
+    fn set(&mut self, key: &str, value: Vec<u8>) -> BazaR<()> {
+        self.table
+            .insert(key, value)
+            .or_raise(|| crate::error::Error::Message("Failed to insert into table".into()))?;
+        Ok(())
+    }
+
+    fn remove(&mut self, key: &str) -> BazaR<()> {
+        self.table
+            .remove(key)
+            .or_raise(|| crate::error::Error::Message("Failed to remove key from table".into()))?;
+        Ok(())
+    }
+}
+
 use async_trait::async_trait;
 
 #[async_trait(?Send)]
@@ -83,6 +251,28 @@ impl StorageBackend for Redb {
         Ok(keys)
     }
 
+    async fn entries(&self) -> BazaR<Vec<(String, Vec<u8>)>> {
+        let db = self.db()?;
+        let read_txn = db
+            .begin_read()
+            .or_raise(|| crate::error::Error::Message("Failed to begin read transaction".into()))?;
+        let table = read_txn
+            .open_table(TABLE)
+            .or_raise(|| crate::error::Error::Message("Failed to open table".into()))?;
+
+        let mut entries = Vec::new();
+        for result in table
+            .iter()
+            .or_raise(|| crate::error::Error::Message("Failed to iterate over table".into()))?
+        {
+            let (key, value) = result.or_raise(|| {
+                crate::error::Error::Message("Failed to read entry from table".into())
+            })?;
+            entries.push((key.value().to_string(), value.value()));
+        }
+        Ok(entries)
+    }
+
     async fn get(&self, key: &str) -> BazaR<Vec<u8>> {
         let db = self.db()?;
         let read_txn = db
@@ -100,6 +290,27 @@ impl StorageBackend for Redb {
         Ok(data)
     }
 
+    async fn get_many(&self, keys: &[String]) -> BazaR<Vec<Vec<u8>>> {
+        let db = self.db()?;
+        let read_txn = db
+            .begin_read()
+            .or_raise(|| crate::error::Error::Message("Failed to begin read transaction".into()))?;
+        let table = read_txn
+            .open_table(TABLE)
+            .or_raise(|| crate::error::Error::Message("Failed to open table".into()))?;
+
+        let mut values = Vec::with_capacity(keys.len());
+        for key in keys {
+            let value = table
+                .get(key.as_str())
+                .or_raise(|| crate::error::Error::Message("Failed to get value from table".into()))?
+                .ok_or(crate::error::Error::Message("No such key".into()))?
+                .value();
+            values.push(value);
+        }
+        Ok(values)
+    }
+
     async fn set(&self, key: &str, value: Vec<u8>) -> BazaR<()> {
         let db = self.db()?;
         let write_txn = db.begin_write().or_raise(|| {
@@ -119,6 +330,52 @@ impl StorageBackend for Redb {
         Ok(())
     }
 
+    async fn apply(&self, changes: Vec<Change>) -> BazaR<()> {
+        self.transaction(Box::new(move |tx| {
+            for change in changes {
+                match change {
+                    Change::Set(key, value) => tx.set(&key, value)?,
+                    Change::Remove(key) => tx.remove(&key)?,
+                }
+            }
+            Ok(())
+        }))
+        .await
+    }
+
+    async fn set_many(&self, entries: Vec<(String, Vec<u8>)>) -> BazaR<()> {
+        self.transaction(Box::new(move |tx| {
+            for (key, value) in entries {
+                tx.set(&key, value)?;
+            }
+            Ok(())
+        }))
+        .await
+    }
+
+    async fn transaction<'a>(&self, f: TransactionFn<'a>) -> BazaR<()> {
+        let db = self.db()?;
+        let write_txn = db.begin_write().or_raise(|| {
+            crate::error::Error::Message("Failed to begin write transaction".into())
+        })?;
+        let result = {
+            let table = write_txn
+                .open_table(TABLE)
+                .or_raise(|| crate::error::Error::Message("Failed to open table".into()))?;
+            f(&mut RedbTransaction { table })
+        };
+        if let Err(e) = result {
+            write_txn
+                .abort()
+                .or_raise(|| crate::error::Error::Message("Failed to abort transaction".into()))?;
+            return Err(e);
+        }
+        write_txn
+            .commit()
+            .or_raise(|| crate::error::Error::Message("Failed to commit transaction".into()))?;
+        Ok(())
+    }
+
     async fn remove(&self, key: &str) -> BazaR<()> {
         let db = self.db()?;
         let write_txn = db.begin_write().or_raise(|| {
@@ -137,4 +394,142 @@ impl StorageBackend for Redb {
         })?;
         Ok(())
     }
+
+    async fn compact(&self) -> BazaR<Option<Compaction>> {
+        self.compact_file().map(Some)
+    }
+
+    async fn scrub(&self) -> BazaR<()> {
+        self.rewrite()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_single_handle() {
+        let _lock = crate::TEST_MUTEX.lock().unwrap();
+        crate::test_config();
+        let redb = Redb::get();
+
+        pollster::block_on(async {
+            initialize().expect("Failed to initialize storage");
+            let data = vec![
+                ("a".to_string(), b"1".to_vec()),
+                ("b".to_string(), b"2".to_vec()),
+            ];
+            redb.set_many(data.clone()).await.expect("set_many failed");
+            assert_eq!(redb.entries().await.expect("entries failed"), data);
+
+            // Re-initializing replaces the open database
+            initialize().expect("Failed to initialize storage");
+            assert!(redb.entries().await.expect("entries failed").is_empty());
+            redb.set_many(data[..1].to_vec())
+                .await
+                .expect("set_many failed");
+            assert_eq!(redb.entries().await.expect("entries failed"), data[..1]);
+        });
+    }
+
+    #[test]
+    fn test_batches_and_transactions() {
+        let _lock = crate::TEST_MUTEX.lock().unwrap();
+        crate::test_config();
+        let redb = Redb::get();
+
+        pollster::block_on(async {
+            initialize().expect("Failed to initialize storage");
+            let entries = vec![
+                ("a".to_string(), b"1".to_vec()),
+                ("b".to_string(), b"2".to_vec()),
+            ];
+            redb.set_many(entries).await.expect("set_many failed");
+            let keys = ["b".to_string(), "a".to_string()];
+            let values = redb.get_many(&keys).await.expect("get_many failed");
+            assert_eq!(values, vec![b"2".to_vec(), b"1".to_vec()]);
+            let missing = ["c".to_string()];
+            assert!(redb.get_many(&missing).await.is_err());
+
+            // A failing transaction leaves no trace
+            let result = redb
+                .transaction(Box::new(|tx| {
+                    tx.remove("a")?;
+                    tx.set("c", b"3".to_vec())?;
+                    assert_eq!(tx.get("c")?, Some(b"3".to_vec()));
+                    exn::bail!(crate::error::Error::Message("Interrupted".into()))
+                }))
+                .await;
+            assert!(result.is_err());
+            let mut keys = redb.list_keys().await.expect("list_keys failed");
+            keys.sort();
+            assert_eq!(keys, ["a", "b"]);
+
+            redb.transaction(Box::new(|tx| {
+                let value = tx.get("a")?.unwrap_or_default();
+                tx.set("c", value)?;
+                tx.remove("a")
+            }))
+            .await
+            .expect("transaction failed");
+            let values = redb.get_many(&missing).await.expect("get_many failed");
+            assert_eq!(values, vec![b"1".to_vec()]);
+        });
+    }
+
+    #[test]
+    fn test_compact() {
+        let _lock = crate::TEST_MUTEX.lock().unwrap();
+        crate::test_config();
+        let redb = Redb::get();
+
+        pollster::block_on(async {
+            initialize().expect("Failed to initialize storage");
+            let entries: Vec<_> = (0..200)
+                .map(|i| (format!("key{i}"), vec![i as u8; 4096]))
+                .collect();
+            redb.set_many(entries.clone())
+                .await
+                .expect("set_many failed");
+            let keys: Vec<_> = entries.iter().map(|(key, _)| key.clone()).collect();
+            redb.apply(keys[1..].iter().cloned().map(Change::Remove).collect())
+                .await
+                .expect("apply failed");
+
+            let compaction = redb
+                .compact()
+                .await
+                .expect("compact failed")
+                .expect("redb compacts");
+            assert!(compaction.after < compaction.before, "{compaction:?}");
+            assert_eq!(redb.entries().await.expect("entries failed"), entries[..1]);
+        });
+    }
+
+    #[test]
+    fn test_scrub() {
+        let _lock = crate::TEST_MUTEX.lock().unwrap();
+        crate::test_config();
+        let redb = Redb::get();
+        let removed = b"removed-secret-value".repeat(8);
+
+        pollster::block_on(async {
+            initialize().expect("Failed to initialize storage");
+            let entries = vec![
+                ("kept".to_string(), b"kept".to_vec()),
+                ("removed".to_string(), removed.clone()),
+            ];
+            redb.set_many(entries.clone())
+                .await
+                .expect("set_many failed");
+            redb.remove("removed").await.expect("remove failed");
+
+            redb.scrub().await.expect("scrub failed");
+            let file = std::fs::read(&redb.path).expect("read failed");
+            assert!(!file.windows(removed.len()).any(|window| window == removed));
+            assert!(!std::path::Path::new(&rewritten(&redb.path)).exists());
+            assert_eq!(redb.entries().await.expect("entries failed"), entries[..1]);
+        });
+    }
 }
@@ -0,0 +1,347 @@
+//! Entries in an SQLite database, one row per key, for tools that already read SQLite.
+//!
+//! Values are encrypted the same way as with redb; keys are bundle identifiers and
+//! system entries, so bundle names stay in the encrypted index.
+
+use async_trait::async_trait;
+use exn::ResultExt;
+use rusqlite::{Connection, OptionalExtension, TransactionBehavior};
+use std::path::PathBuf;
+use std::sync::{Mutex, MutexGuard, OnceLock};
+use std::time::Duration;
+
+use crate::{
+    error::Error,
+    storage::{Change, Compaction, StorageBackend, Transaction, TransactionFn},
+    BazaR,
+};
+
+const DIR: &str = "sqlite";
+const FILE: &str = "db.sqlite";
+const SCHEMA: &str = "CREATE TABLE IF NOT EXISTS entries (
+    key TEXT PRIMARY KEY NOT NULL,
+    value BLOB NOT NULL
+) WITHOUT ROWID";
+/// How long to wait for another baza process to finish writing.
+const LOCK_TIMEOUT: Duration = Duration::from_secs(3);
+
+static INSTANCE: OnceLock<Sqlite> = OnceLock::new();
+
+/// The database is opened on first use and kept open for the rest of the process.
+pub struct Sqlite {
+    path: PathBuf,
+    db: Mutex<Option<Connection>>,
+}
+
+impl Sqlite {
+    fn get() -> &'static Sqlite {
+        INSTANCE.get_or_init(|| Self::new(super::storage_dir(DIR).join(FILE)))
+    }
+
+    pub(crate) fn instance() -> BazaR<&'static dyn StorageBackend> {
+        Ok(Self::get())
+    }
+
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            db: Mutex::new(None),
+        }
+    }
+
+    fn handle(&self) -> BazaR<MutexGuard<'_, Option<Connection>>> {
+        self.db
+            .lock()
+            .map_err(|_| exn::Exn::new(Error::Message("Failed to lock database mutex".into())))
+    }
+
+    /// The open database, opened on first use.
+    fn db(&self) -> BazaR<MutexGuard<'_, Option<Connection>>> {
+        let mut handle = self.handle()?;
+        if handle.is_none() {
+            *handle = Some(open(&self.path)?);
+        }
+        Ok(handle)
+    }
+
+    /// Run `f` on the open database.
+    fn with_db<R>(&self, f: impl FnOnce(&mut Connection) -> BazaR<R>) -> BazaR<R> {
+        let mut handle = self.db()?;
+        match handle.as_mut() {
+            Some(db) => f(db),
+            None => exn::bail!(Error::Message("Database is not open".into())),
+        }
+    }
+
+    /// Create an empty database, replacing the previous one.
+    pub fn initialize(&self) -> BazaR<()> {
+        // Close the previous database before replacing it
+        let mut handle = self.handle()?;
+        *handle = None;
+        if let Some(folder) = self.path.parent() {
+            std::fs::create_dir_all(folder).map_err(|e| exn::Exn::new(e.into()))?;
+        }
+        if self.path.exists() {
+            super::shred(&self.path)?;
+        }
+        let db = open(&self.path)?;
+        db.execute_batch(SCHEMA)
+            .or_raise(|| Error::Message("Failed to create entries table".into()))?;
+        *handle = Some(db);
+        Ok(())
+    }
+}
+
+fn open(path: &std::path::Path) -> BazaR<Connection> {
+    let db = Connection::open(path)
+        .or_raise(|| Error::Message(format!("Failed to open database '{}'", path.display())))?;
+    db.busy_timeout(LOCK_TIMEOUT)
+        .or_raise(|| Error::Message("Failed to set database busy timeout".into()))?;
+    // Zero the content of removed and replaced rows instead of leaving it in free pages
+    db.pragma_update(None, "secure_delete", true)
+        .or_raise(|| Error::Message("Failed to enable secure delete".into()))?;
+    Ok(db)
+}
+
+pub fn initialize() -> BazaR<()> {
+    Sqlite::get().initialize()
+}
+
+fn list_keys(db: &Connection) -> BazaR<Vec<String>> {
+    let mut statement = db
+        .prepare_cached("SELECT key FROM entries ORDER BY key")
+        .or_raise(|| Error::Message("Failed to list keys".into()))?;
+    let keys = statement
+        .query_map([], |row| row.get(0))
+        .or_raise(|| Error::Message("Failed to list keys".into()))?
+        .collect::<Result<Vec<String>, _>>()
+        .or_raise(|| Error::Message("Failed to read key".into()))?;
+    Ok(keys)
+}
+
+fn get(db: &Connection, key: &str) -> BazaR<Option<Vec<u8>>> {
+    db.prepare_cached("SELECT value FROM entries WHERE key = ?1")
+        .and_then(|mut statement| statement.query_row([key], |row| row.get(0)).optional())
+        .or_raise(|| Error::Message("Failed to get value from table".into()))
+}
+
+fn required(value: Option<Vec<u8>>) -> BazaR<Vec<u8>> {
+    value.ok_or_else(|| exn::Exn::new(Error::Message("No such key".into())))
+}
+
+/// An open write transaction.
+struct SqliteTransaction<'a> {
+    tx: rusqlite::Transaction<'a>,
+}
+
+impl Transaction for SqliteTransaction<'_> {
+    fn list_keys(&self) -> BazaR<Vec<String>> {
+        list_keys(&self.tx)
+    }
+
+    fn get(&self, key: &str) -> BazaR<Option<Vec<u8>>> {
+        get(&self.tx, key)
+    }
+
+    fn set(&mut self, key: &str, value: Vec<u8>) -> BazaR<()> {
+        self.tx
+            .execute(
+                "INSERT OR REPLACE INTO entries (key, value) VALUES (?1, ?2)",
+                rusqlite::params![key, value],
+            )
+            .or_raise(|| Error::Message("Failed to insert into table".into()))?;
+        Ok(())
+    }
+
+    fn remove(&mut self, key: &str) -> BazaR<()> {
+        self.tx
+            .execute("DELETE FROM entries WHERE key = ?1", [key])
+            .or_raise(|| Error::Message("Failed to remove key from table".into()))?;
+        Ok(())
+    }
+}
+
+#[async_trait(?Send)]
+impl StorageBackend for Sqlite {
+    async fn is_initialized(&self) -> BazaR<bool> {
+        Ok(self.path.exists())
+    }
+
+    async fn list_keys(&self) -> BazaR<Vec<String>> {
+        self.with_db(|db| list_keys(db))
+    }
+
+    async fn entries(&self) -> BazaR<Vec<(String, Vec<u8>)>> {
+        self.with_db(|db| {
+            let mut statement = db
+                .prepare_cached("SELECT key, value FROM entries ORDER BY key")
+                .or_raise(|| Error::Message("Failed to read entries".into()))?;
+            let entries = statement
+                .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+                .or_raise(|| Error::Message("Failed to read entries".into()))?
+                .collect::<Result<Vec<(String, Vec<u8>)>, _>>()
+                .or_raise(|| Error::Message("Failed to read entry".into()))?;
+            Ok(entries)
+        })
+    }
+
+    async fn get(&self, key: &str) -> BazaR<Vec<u8>> {
+        self.with_db(|db| required(get(db, key)?))
+    }
+
+    async fn get_many(&self, keys: &[String]) -> BazaR<Vec<Vec<u8>>> {
+        self.with_db(|db| {
+            let tx = db
+                .transaction()
+                .or_raise(|| Error::Message("Failed to begin read transaction".into()))?;
+            keys.iter().map(|key| required(get(&tx, key)?)).collect()
+        })
+    }
+
+    async fn set(&self, key: &str, value: Vec<u8>) -> BazaR<()> {
+        self.apply(vec![Change::Set(key.to_string(), value)]).await
+    }
+
+    async fn set_many(&self, entries: Vec<(String, Vec<u8>)>) -> BazaR<()> {
+        self.transaction(Box::new(move |tx| {
+            for (key, value) in entries {
+                tx.set(&key, value)?;
+            }
+            Ok(())
+        }))
+        .await
+    }
+
+    async fn remove(&self, key: &str) -> BazaR<()> {
+        self.apply(vec![Change::Remove(key.to_string())]).await
+    }
+
+    async fn apply(&self, changes: Vec<Change>) -> BazaR<()> {
+        self.transaction(Box::new(move |tx| {
+            for change in changes {
+                match change {
+                    Change::Set(key, value) => tx.set(&key, value)?,
+                    Change::Remove(key) => tx.remove(&key)?,
+                }
+            }
+            Ok(())
+        }))
+        .await
+    }
+
+    async fn transaction<'a>(&self, f: TransactionFn<'a>) -> BazaR<()> {
+        self.with_db(|db| {
+            let tx = db
+                .transaction_with_behavior(TransactionBehavior::Immediate)
+                .or_raise(|| Error::Message("Failed to begin write transaction".into()))?;
+            let mut tx = SqliteTransaction { tx };
+            // Dropping the transaction without committing rolls it back
+            f(&mut tx)?;
+            tx.tx
+                .commit()
+                .or_raise(|| Error::Message("Failed to commit transaction".into()))
+        })
+    }
+
+    async fn compact(&self) -> BazaR<Option<Compaction>> {
+        let before = super::file_size(&self.path)?;
+        self.with_db(|db| {
+            db.execute_batch("VACUUM")
+                .or_raise(|| Error::Message("Failed to vacuum database".into()))
+        })?;
+        Ok(Some(Compaction {
+            before,
+            after: super::file_size(&self.path)?,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_secure_delete() {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        let sqlite = Sqlite::new(dir.path().join(DIR).join(FILE));
+        let removed = b"removed-secret-value".repeat(8);
+
+        pollster::block_on(async {
+            sqlite.initialize().expect("initialize failed");
+            sqlite
+                .set("removed", removed.clone())
+                .await
+                .expect("set failed");
+            sqlite.remove("removed").await.expect("remove failed");
+            let file = std::fs::read(&sqlite.path).expect("read failed");
+            assert!(!file.windows(removed.len()).any(|window| window == removed));
+        });
+    }
+
+    #[test]
+    fn test_sqlite_backend() {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        let sqlite = Sqlite::new(dir.path().join(DIR).join(FILE));
+
+        pollster::block_on(async {
+            assert!(!sqlite
+                .is_initialized()
+                .await
+                .expect("is_initialized failed"));
+            sqlite.initialize().expect("initialize failed");
+            sqlite
+                .set_many(vec![
+                    ("b".into(), b"2".to_vec()),
+                    ("a".into(), b"1".to_vec()),
+                ])
+                .await
+                .expect("set_many failed");
+            assert_eq!(
+                sqlite.list_keys().await.expect("list_keys failed"),
+                ["a", "b"]
+            );
+            assert_eq!(
+                sqlite
+                    .get_many(&["b".into(), "a".into()])
+                    .await
+                    .expect("get_many failed"),
+                [b"2".to_vec(), b"1".to_vec()]
+            );
+            assert!(sqlite.get("c").await.is_err());
+
+            // A failing transaction leaves no trace
+            let result = sqlite
+                .transaction(Box::new(|tx| {
+                    tx.remove("a")?;
+                    tx.set("c", b"3".to_vec())?;
+                    assert_eq!(tx.list_keys()?, ["b", "c"]);
+                    exn::bail!(Error::Message("Interrupted".into()))
+                }))
+                .await;
+            assert!(result.is_err());
+            assert_eq!(
+                sqlite.entries().await.expect("entries failed"),
+                [
+                    ("a".to_string(), b"1".to_vec()),
+                    ("b".to_string(), b"2".to_vec())
+                ]
+            );
+
+            sqlite.remove("a").await.expect("remove failed");
+            assert_eq!(sqlite.list_keys().await.expect("list_keys failed"), ["b"]);
+            let compaction = sqlite
+                .compact()
+                .await
+                .expect("compact failed")
+                .expect("sqlite compacts");
+            assert!(compaction.after <= compaction.before, "{compaction:?}");
+
+            // Other tools can read the same file
+            let db = Connection::open(dir.path().join(DIR).join(FILE)).expect("open failed");
+            let count: i64 = db
+                .query_row("SELECT COUNT(*) FROM entries", [], |row| row.get(0))
+                .expect("query failed");
+            assert_eq!(count, 1);
+        });
+    }
+}
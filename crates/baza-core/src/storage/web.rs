@@ -1,7 +1,8 @@
-use super::StorageBackend;
-use crate::BazaR;
+use super::{Change, StorageBackend, Transaction, TransactionFn};
+use crate::{vault::DEFAULT_VAULT, BazaR};
 use async_trait::async_trait;
-use rexie::{Rexie, TransactionMode};
+use rexie::{Rexie, Store, TransactionMode};
+use std::collections::BTreeMap;
 use wasm_bindgen::JsValue;
 
 const DB_NAME: &str = "baza";
@@ -11,16 +12,50 @@ struct SafeWebStorage(WebStorage);
 unsafe impl Send for SafeWebStorage {}
 unsafe impl Sync for SafeWebStorage {}
 
-use std::sync::OnceLock;
-static STORAGE: OnceLock<SafeWebStorage> = OnceLock::new();
+use std::sync::Mutex;
+/// Databases opened so far, by vault name.
+static STORAGES: Mutex<BTreeMap<String, &'static SafeWebStorage>> = Mutex::new(BTreeMap::new());
+static VAULT: Mutex<Option<String>> = Mutex::new(None);
+
+/// Name of the vault in use.
+pub(crate) fn vault() -> String {
+    VAULT
+        .lock()
+        .ok()
+        .and_then(|vault| vault.clone())
+        .unwrap_or_else(|| DEFAULT_VAULT.into())
+}
+
+pub(crate) fn select(name: &str) {
+    if let Ok(mut vault) = VAULT.lock() {
+        *vault = Some(name.to_string());
+    }
+}
+
+/// The default vault keeps the database of the days before named vaults.
+fn db_name(vault: &str) -> String {
+    if vault == DEFAULT_VAULT {
+        DB_NAME.to_string()
+    } else {
+        format!("{DB_NAME}-{vault}")
+    }
+}
+
+fn storages() -> BazaR<std::sync::MutexGuard<'static, BTreeMap<String, &'static SafeWebStorage>>> {
+    STORAGES.lock().map_err(|_| {
+        exn::Exn::new(crate::error::Error::Message(
+            "Failed to lock WebStorage instances".into(),
+        ))
+    })
+}
 
 pub struct WebStorage {
     rexie: Rexie,
 }
 
 impl WebStorage {
-    async fn new() -> BazaR<Self> {
-        let rexie = Rexie::builder(DB_NAME)
+    async fn new(vault: &str) -> BazaR<Self> {
+        let rexie = Rexie::builder(&db_name(vault))
             .version(1)
             .add_object_store(rexie::ObjectStore::new(STORE_NAME))
             .build()
@@ -30,22 +65,21 @@ impl WebStorage {
         Ok(Self { rexie })
     }
 
+    /// Database of the vault in use, opened once and kept for the rest of the session.
     pub(crate) async fn instance() -> BazaR<&'static Self> {
-        if let Some(s) = STORAGE.get() {
-            Ok(&s.0)
-        } else {
-            let s = Self::new().await?;
-            let _ = STORAGE.set(SafeWebStorage(s));
-            STORAGE.get().map(|s| &s.0).ok_or_else(|| {
-                exn::Exn::new(crate::error::Error::Message(
-                    "WebStorage instance unavailable".into(),
-                ))
-            })
+        let vault = vault();
+        if let Some(s) = storages()?.get(&vault) {
+            return Ok(&s.0);
         }
+        let s = Self::new(&vault).await?;
+        let s = *storages()?
+            .entry(vault)
+            .or_insert_with(|| Box::leak(Box::new(SafeWebStorage(s))));
+        Ok(&s.0)
     }
 
-
-
+    /// Remove every entry. IndexedDB gives no way to overwrite the removed data, the
+    /// browser frees it in its own files whenever it sees fit.
     pub async fn delete_database() -> BazaR<()> {
         // Instead of deleting the database (which causes closure issues),
         // we'll clear all data from it
@@ -53,6 +87,7 @@ impl WebStorage {
 
         // Get all keys
         let keys = instance.list_keys().await?;
+        let writes = prepare(keys.into_iter().map(Change::Remove).collect())?;
 
         // Delete all entries
         let transaction = instance
@@ -64,12 +99,8 @@ impl WebStorage {
             .store(STORE_NAME)
             .map_err(|e| crate::error::Error::Message(e.to_string()))?;
 
-        for key in keys {
-            let js_key = JsValue::from_str(&key);
-            store
-                .delete(js_key)
-                .await
-                .map_err(|e| crate::error::Error::Message(e.to_string()))?;
+        if let Err(e) = write(&store, writes).await {
+            return abort(transaction, e).await;
         }
 
         transaction
@@ -81,6 +112,74 @@ impl WebStorage {
     }
 }
 
+/// Key and, unless it is removed, value of a change, converted for IndexedDB.
+type Write = (JsValue, Option<JsValue>);
+
+/// Convert `changes` up front, so that one failing to convert fails before anything is written.
+fn prepare(changes: Vec<Change>) -> BazaR<Vec<Write>> {
+    changes
+        .into_iter()
+        .map(|change| {
+            Ok(match change {
+                Change::Set(key, value) => {
+                    let js_value = serde_wasm_bindgen::to_value(&value)
+                        .map_err(|e| crate::error::Error::Message(e.to_string()))?;
+                    (JsValue::from_str(&key), Some(js_value))
+                }
+                Change::Remove(key) => (JsValue::from_str(&key), None),
+            })
+        })
+        .collect()
+}
+
+/// Issue `writes` in the transaction of `store`.
+async fn write(store: &Store, writes: Vec<Write>) -> BazaR<()> {
+    for (key, value) in writes {
+        match value {
+            Some(value) => store.put(&value, Some(&key)).await.map(drop),
+            None => store.delete(key).await,
+        }
+        .map_err(|e| crate::error::Error::Message(e.to_string()))?;
+    }
+    Ok(())
+}
+
+/// Abort `transaction` after `error`, so that none of the writes it issued are committed.
+async fn abort(transaction: rexie::Transaction, error: exn::Exn<crate::error::Error>) -> BazaR<()> {
+    // A failed request may have aborted the transaction already
+    let _ = transaction.abort().await;
+    Err(error)
+}
+
+/// IndexedDB requests are asynchronous, so the closure of a transaction works on a
+/// snapshot of the store read inside the transaction, and its writes are applied after it.
+struct Snapshot {
+    entries: BTreeMap<String, Vec<u8>>,
+    changes: Vec<Change>,
+}
+
+impl Transaction for Snapshot {
+    fn list_keys(&self) -> BazaR<Vec<String>> {
+        Ok(self.entries.keys().cloned().collect())
+    }
+
+    fn get(&self, key: &str) -> BazaR<Option<Vec<u8>>> {
+        Ok(self.entries.get(key).cloned())
+    }
+
+    fn set(&mut self, key: &str, value: Vec<u8>) -> BazaR<()> {
+        self.entries.insert(key.to_string(), value.clone());
+        self.changes.push(Change::Set(key.to_string(), value));
+        Ok(())
+    }
+
+    fn remove(&mut self, key: &str) -> BazaR<()> {
+        self.entries.remove(key);
+        self.changes.push(Change::Remove(key.to_string()));
+        Ok(())
+    }
+}
+
 #[async_trait(?Send)]
 impl StorageBackend for WebStorage {
     async fn is_initialized(&self) -> BazaR<bool> {
@@ -126,6 +225,33 @@ impl StorageBackend for WebStorage {
         Ok(result)
     }
 
+    async fn entries(&self) -> BazaR<Vec<(String, Vec<u8>)>> {
+        let transaction = self
+            .rexie
+            .transaction(&[STORE_NAME], TransactionMode::ReadOnly)
+            .map_err(|e| crate::error::Error::Message(e.to_string()))?;
+
+        let store = transaction
+            .store(STORE_NAME)
+            .map_err(|e| crate::error::Error::Message(e.to_string()))?;
+
+        let pairs = store
+            .scan(None, None, None, None)
+            .await
+            .map_err(|e| crate::error::Error::Message(e.to_string()))?;
+
+        let mut result = Vec::with_capacity(pairs.len());
+        for (key, value) in pairs {
+            if let Some(key) = key.as_string() {
+                let data: Vec<u8> = serde_wasm_bindgen::from_value(value)
+                    .map_err(|e| crate::error::Error::Message(e.to_string()))?;
+                result.push((key, data));
+            }
+        }
+
+        Ok(result)
+    }
+
     async fn get(&self, key: &str) -> BazaR<Vec<u8>> {
         let transaction = self
             .rexie
@@ -154,6 +280,32 @@ impl StorageBackend for WebStorage {
         Ok(data)
     }
 
+    async fn get_many(&self, keys: &[String]) -> BazaR<Vec<Vec<u8>>> {
+        let transaction = self
+            .rexie
+            .transaction(&[STORE_NAME], TransactionMode::ReadOnly)
+            .map_err(|e| crate::error::Error::Message(e.to_string()))?;
+
+        let store = transaction
+            .store(STORE_NAME)
+            .map_err(|e| crate::error::Error::Message(e.to_string()))?;
+
+        let mut values = Vec::with_capacity(keys.len());
+        for key in keys {
+            let js_value = store
+                .get(JsValue::from_str(key))
+                .await
+                .map_err(|e| crate::error::Error::Message(e.to_string()))?
+                .filter(|value| !value.is_null() && !value.is_undefined())
+                .ok_or(crate::error::Error::Message("No such key".into()))?;
+            let data: Vec<u8> = serde_wasm_bindgen::from_value(js_value)
+                .map_err(|e| crate::error::Error::Message(e.to_string()))?;
+            values.push(data);
+        }
+
+        Ok(values)
+    }
+
     async fn set(&self, key: &str, value: Vec<u8>) -> BazaR<()> {
         let transaction = self
             .rexie
@@ -181,6 +333,79 @@ impl StorageBackend for WebStorage {
         Ok(())
     }
 
+    async fn apply(&self, changes: Vec<Change>) -> BazaR<()> {
+        let writes = prepare(changes)?;
+        let transaction = self
+            .rexie
+            .transaction(&[STORE_NAME], TransactionMode::ReadWrite)
+            .map_err(|e| crate::error::Error::Message(e.to_string()))?;
+
+        let store = transaction
+            .store(STORE_NAME)
+            .map_err(|e| crate::error::Error::Message(e.to_string()))?;
+
+        if let Err(e) = write(&store, writes).await {
+            return abort(transaction, e).await;
+        }
+
+        transaction
+            .done()
+            .await
+            .map_err(|e| crate::error::Error::Message(e.to_string()))?;
+
+        Ok(())
+    }
+
+    async fn set_many(&self, entries: Vec<(String, Vec<u8>)>) -> BazaR<()> {
+        let changes = entries
+            .into_iter()
+            .map(|(key, value)| Change::Set(key, value))
+            .collect();
+        self.apply(changes).await
+    }
+
+    async fn transaction<'a>(&self, f: TransactionFn<'a>) -> BazaR<()> {
+        let transaction = self
+            .rexie
+            .transaction(&[STORE_NAME], TransactionMode::ReadWrite)
+            .map_err(|e| crate::error::Error::Message(e.to_string()))?;
+
+        let store = transaction
+            .store(STORE_NAME)
+            .map_err(|e| crate::error::Error::Message(e.to_string()))?;
+
+        let result = async {
+            let mut snapshot = Snapshot {
+                entries: BTreeMap::new(),
+                changes: Vec::new(),
+            };
+            for (key, value) in store
+                .scan(None, None, None, None)
+                .await
+                .map_err(|e| crate::error::Error::Message(e.to_string()))?
+            {
+                if let Some(key) = key.as_string() {
+                    let data: Vec<u8> = serde_wasm_bindgen::from_value(value)
+                        .map_err(|e| crate::error::Error::Message(e.to_string()))?;
+                    snapshot.entries.insert(key, data);
+                }
+            }
+            f(&mut snapshot)?;
+            write(&store, prepare(snapshot.changes)?).await
+        }
+        .await;
+        if let Err(e) = result {
+            return abort(transaction, e).await;
+        }
+
+        transaction
+            .done()
+            .await
+            .map_err(|e| crate::error::Error::Message(e.to_string()))?;
+
+        Ok(())
+    }
+
     async fn remove(&self, key: &str) -> BazaR<()> {
         let transaction = self
             .rexie
@@ -0,0 +1,354 @@
+//! Unlock throttling: failed attempts are counted in the system box, every attempt past
+//! the first few waits twice as long as the previous one, and an optional lockout refuses
+//! to unlock for a while after too many failures.
+//!
+//! Failures are recorded before the vault key is known, so the record itself is neither
+//! encrypted nor authenticated: a key signing it would have to be readable without the
+//! passphrase. Instead the record keeps a total of failures that is never reset, and every
+//! successful unlock stores that total encrypted with the vault key. The next successful
+//! unlock compares both, so a record deleted or rewritten in between is reported. Putting
+//! back a copy taken right after an unlock is not detected.
+
+use crate::{
+    decrypt_data, encrypt_data, error::Error, storage, utils::get_timestamp, BazaR, Config,
+    ATTEMPTS_KEY, ATTEMPTS_SEEN_KEY,
+};
+use exn::ResultExt;
+use serde::{Deserialize, Serialize};
+
+/// Failed attempts allowed without waiting.
+const FREE_ATTEMPTS: u32 = 3;
+const MAX_DELAY_SECONDS: u64 = 300;
+
+/// Refuse to unlock for `seconds` after `attempts` failures in a row.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LockoutConfig {
+    pub attempts: u32,
+    #[serde(default = "default_lockout_seconds")]
+    pub seconds: u64,
+}
+
+fn default_lockout_seconds() -> u64 {
+    900
+}
+
+/// Failed unlock attempts since the last successful one.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+struct Attempts {
+    failures: u32,
+    /// UNIX timestamp of the last failure.
+    last: u64,
+    /// Failures ever recorded, kept across successful unlocks.
+    total: u64,
+}
+
+/// Record written before `total` was added, read once and upgraded on the next write.
+#[derive(Deserialize)]
+struct LegacyAttempts {
+    failures: u32,
+    last: u64,
+}
+
+impl Attempts {
+    /// Seconds to wait before the next attempt is allowed.
+    fn wait(&self, lockout: Option<&LockoutConfig>, now: u64) -> u64 {
+        let delay = match self.failures.checked_sub(FREE_ATTEMPTS) {
+            Some(extra) => 1u64
+                .checked_shl(extra)
+                .unwrap_or(MAX_DELAY_SECONDS)
+                .min(MAX_DELAY_SECONDS),
+            None => 0,
+        };
+        let delay = match lockout {
+            Some(lockout) if self.failures >= lockout.attempts => delay.max(lockout.seconds),
+            _ => delay,
+        };
+        self.last.saturating_add(delay).saturating_sub(now)
+    }
+
+    /// Whether the record was reset or rewritten since the last successful unlock, which
+    /// saw `seen` failures in total: every failure since then must still be counted.
+    fn tampered(record: Option<&Self>, seen: u64) -> bool {
+        match record {
+            Some(attempts) => attempts.total.checked_sub(seen) != Some(attempts.failures.into()),
+            None => true,
+        }
+    }
+}
+
+fn parse(raw: &[u8]) -> BazaR<Attempts> {
+    if let Ok(attempts) = postcard::from_bytes(raw) {
+        return Ok(attempts);
+    }
+    let legacy: LegacyAttempts = postcard::from_bytes(raw)
+        .or_raise(|| Error::Message("Failed to parse unlock attempts".into()))?;
+    Ok(Attempts {
+        failures: legacy.failures,
+        last: legacy.last,
+        total: legacy.failures.into(),
+    })
+}
+
+async fn load_record() -> BazaR<Option<Attempts>> {
+    if !storage::contains(ATTEMPTS_KEY).await? {
+        return Ok(None);
+    }
+    let raw = storage::with_backend(|backend| backend.get(ATTEMPTS_KEY)).await?;
+    parse(&raw).map(Some)
+}
+
+async fn load() -> BazaR<Attempts> {
+    Ok(load_record().await?.unwrap_or_default())
+}
+
+/// Total failures seen at the last successful unlock, if one was recorded.
+async fn load_seen(key: &[u8]) -> BazaR<Option<u64>> {
+    if !storage::contains(ATTEMPTS_SEEN_KEY).await? {
+        return Ok(None);
+    }
+    let raw = storage::with_backend(|backend| backend.get(ATTEMPTS_SEEN_KEY)).await?;
+    let plain = decrypt_data(&raw, key, ATTEMPTS_SEEN_KEY)?;
+    postcard::from_bytes(&plain)
+        .map(Some)
+        .or_raise(|| Error::Message("Failed to parse seen unlock attempts".into()))
+}
+
+/// Changes storing `attempts` and remembering its total as seen by an unlock.
+fn store(attempts: &Attempts, key: &[u8]) -> BazaR<Vec<storage::Change>> {
+    let record = postcard::to_stdvec(attempts)
+        .or_raise(|| Error::Message("Failed to serialize unlock attempts".into()))?;
+    let seen = postcard::to_stdvec(&attempts.total)
+        .or_raise(|| Error::Message("Failed to serialize seen unlock attempts".into()))?;
+    Ok(vec![
+        storage::Change::Set(ATTEMPTS_KEY.to_string(), record),
+        storage::Change::Set(
+            ATTEMPTS_SEEN_KEY.to_string(),
+            encrypt_data(&seen, key, ATTEMPTS_SEEN_KEY)?,
+        ),
+    ])
+}
+
+/// Changes creating an empty record for a new vault, so that removing it is noticed.
+pub(crate) fn initial(key: &[u8]) -> BazaR<Vec<storage::Change>> {
+    store(&Attempts::default(), key)
+}
+
+/// Refuse the attempt while the backoff or the lockout is in effect.
+pub(crate) async fn check() -> BazaR<()> {
+    let wait = load()
+        .await?
+        .wait(Config::get().main.lockout.as_ref(), get_timestamp());
+    if wait > 0 {
+        exn::bail!(Error::Throttled(wait));
+    }
+    Ok(())
+}
+
+/// Whether `error` from an unlock attempt means wrong credentials.
+pub(crate) fn is_failure(error: &Error) -> bool {
+    matches!(error, Error::InvalidPassphrase | Error::InvalidTotp(_))
+}
+
+/// Count a failed attempt. The counter is read and written in one transaction,
+/// so concurrent attempts cannot overwrite each other's failures.
+pub(crate) async fn failed() -> BazaR<()> {
+    storage::with_backend(|backend| {
+        backend.transaction(Box::new(|tx| {
+            let mut attempts = match tx.get(ATTEMPTS_KEY)? {
+                Some(raw) => parse(&raw)?,
+                None => Attempts::default(),
+            };
+            attempts.failures = attempts.failures.saturating_add(1);
+            attempts.total = attempts.total.saturating_add(1);
+            attempts.last = get_timestamp();
+            let raw = postcard::to_stdvec(&attempts)
+                .or_raise(|| Error::Message("Failed to serialize unlock attempts".into()))?;
+            tx.set(ATTEMPTS_KEY, raw)
+        }))
+    })
+    .await
+}
+
+/// Forget failed attempts after a successful unlock, reporting them first along with
+/// a record that was reset or rewritten since the previous unlock.
+pub(crate) async fn succeeded() -> BazaR<()> {
+    let key = crate::key()?;
+    let record = load_record().await?;
+    let seen = load_seen(&key).await?;
+    let tampered = seen.is_some_and(|seen| Attempts::tampered(record.as_ref(), seen));
+    if tampered {
+        tracing::warn!(
+            "The unlock attempts record was reset or altered since the last unlock, \
+             failed attempts may have gone unreported"
+        );
+    }
+    let attempts = record.unwrap_or_default();
+    if attempts.failures > 0 {
+        tracing::warn!(
+            "{} failed unlock attempts since the last unlock",
+            attempts.failures
+        );
+    }
+    if !tampered && attempts.failures == 0 && seen == Some(attempts.total) {
+        return Ok(());
+    }
+    let changes = store(
+        &Attempts {
+            failures: 0,
+            ..attempts
+        },
+        &key,
+    )?;
+    storage::with_backend(|backend| backend.apply(changes)).await?;
+    storage::checkpoint("Reset failed unlock attempts".into()).await
+}
+
+#[cfg(test)]
+#[cfg(not(target_arch = "wasm32"))]
+mod tests {
+    use super::*;
+    use crate::{init, lock, unlock};
+
+    #[test]
+    fn test_wait() {
+        let attempts = |failures| Attempts {
+            failures,
+            last: 1000,
+            total: failures.into(),
+        };
+        assert_eq!(attempts(0).wait(None, 1000), 0);
+        assert_eq!(attempts(FREE_ATTEMPTS - 1).wait(None, 1000), 0);
+        assert_eq!(attempts(FREE_ATTEMPTS).wait(None, 1000), 1);
+        assert_eq!(attempts(FREE_ATTEMPTS + 3).wait(None, 1000), 8);
+        assert_eq!(attempts(FREE_ATTEMPTS + 3).wait(None, 1005), 3);
+        assert_eq!(attempts(FREE_ATTEMPTS + 3).wait(None, 1010), 0);
+        assert_eq!(attempts(u32::MAX).wait(None, 1000), MAX_DELAY_SECONDS);
+
+        let lockout = LockoutConfig {
+            attempts: 5,
+            seconds: 900,
+        };
+        assert_eq!(attempts(4).wait(Some(&lockout), 1000), 2);
+        assert_eq!(attempts(5).wait(Some(&lockout), 1000), 900);
+        assert_eq!(attempts(5).wait(Some(&lockout), 1900), 0);
+
+        let last = Attempts {
+            last: u64::MAX,
+            ..attempts(FREE_ATTEMPTS + 3)
+        };
+        assert_eq!(last.wait(None, 1000), u64::MAX - 1000);
+    }
+
+    #[test]
+    fn test_tampered() {
+        let attempts = |failures, total| Attempts {
+            failures,
+            last: 1000,
+            total,
+        };
+        assert!(!Attempts::tampered(Some(&attempts(0, 4)), 4));
+        assert!(!Attempts::tampered(Some(&attempts(2, 6)), 4));
+        assert!(Attempts::tampered(None, 0));
+        assert!(Attempts::tampered(Some(&attempts(0, 0)), 4));
+        assert!(Attempts::tampered(Some(&attempts(1, 6)), 4));
+        assert!(Attempts::tampered(Some(&attempts(2, 2)), 4));
+    }
+
+    #[test]
+    fn test_reset_detected() {
+        crate::test_config();
+
+        pollster::block_on(async {
+            init(Some("passphrase".into())).await.expect("init failed");
+            let key = crate::key().expect("no key");
+            assert_eq!(load_seen(&key).await.expect("load failed"), Some(0));
+            lock().expect("lock failed");
+
+            assert!(unlock("wrong".into(), None).await.is_err());
+            assert!(unlock("wrong".into(), None).await.is_err());
+            let record = load_record().await.expect("load failed");
+            assert!(!Attempts::tampered(record.as_ref(), 0));
+
+            // Deleting the record hides the failures, but not from the next unlock
+            storage::with_backend(|backend| backend.remove(ATTEMPTS_KEY))
+                .await
+                .expect("remove failed");
+            let record = load_record().await.expect("load failed");
+            assert!(Attempts::tampered(record.as_ref(), 0));
+            unlock("passphrase".into(), None)
+                .await
+                .expect("unlock failed");
+            let record = load_record().await.expect("load failed");
+            let seen = load_seen(&key).await.expect("load failed");
+            assert_eq!(seen, Some(0));
+            assert!(!Attempts::tampered(record.as_ref(), 0));
+            lock().expect("lock failed");
+
+            // So does clearing its failures
+            assert!(unlock("wrong".into(), None).await.is_err());
+            assert!(unlock("wrong".into(), None).await.is_err());
+            let raw = postcard::to_stdvec(&Attempts {
+                failures: 0,
+                last: get_timestamp(),
+                total: 2,
+            })
+            .expect("serialize failed");
+            storage::with_backend(|backend| backend.set(ATTEMPTS_KEY, raw))
+                .await
+                .expect("set failed");
+            let record = load_record().await.expect("load failed");
+            assert!(Attempts::tampered(record.as_ref(), 0));
+            unlock("passphrase".into(), None)
+                .await
+                .expect("unlock failed");
+            assert_eq!(load_seen(&key).await.expect("load failed"), Some(2));
+        });
+    }
+
+    #[test]
+    fn test_legacy_record() {
+        let raw = postcard::to_stdvec(&(4u32, 1000u64)).expect("serialize failed");
+        let attempts = parse(&raw).expect("parse failed");
+        assert_eq!(attempts.failures, 4);
+        assert_eq!(attempts.last, 1000);
+        assert_eq!(attempts.total, 4);
+    }
+
+    #[test]
+    fn test_throttled_unlock() {
+        crate::test_config();
+
+        pollster::block_on(async {
+            init(Some("passphrase".into())).await.expect("init failed");
+            lock().expect("lock failed");
+
+            for _ in 0..FREE_ATTEMPTS - 1 {
+                assert!(unlock("wrong".into(), None).await.is_err());
+            }
+            assert_eq!(load().await.expect("load failed").failures, 2);
+            unlock("passphrase".into(), None)
+                .await
+                .expect("unlock failed");
+            let attempts = load().await.expect("load failed");
+            assert_eq!(attempts.failures, 0);
+            assert_eq!(attempts.total, 2);
+            lock().expect("lock failed");
+
+            // Even the right passphrase is refused during the backoff
+            let attempts = Attempts {
+                failures: FREE_ATTEMPTS + 6,
+                last: get_timestamp(),
+                total: u64::from(FREE_ATTEMPTS) + 8,
+            };
+            let raw = postcard::to_stdvec(&attempts).expect("serialize failed");
+            storage::with_backend(|backend| backend.set(ATTEMPTS_KEY, raw))
+                .await
+                .expect("set failed");
+            let err = unlock("passphrase".into(), None)
+                .await
+                .expect_err("unlock during backoff succeeded");
+            assert!(matches!(*err, Error::Throttled(_)));
+            assert!(crate::key().is_err());
+        });
+    }
+}
@@ -6,7 +6,7 @@ use totp_rs::{Algorithm, Secret, TOTP};
 /// Returns the generated secret as base32, the provisioning URI, and the base64 QR code.
 pub async fn enable() -> BazaR<(String, String, String)> {
     // Check if vault is unlocked (if not, we cannot get the encryption key to save the secret)
-    let _ = crate::key()?;
+    let key = crate::key()?;
 
     // Generate a random secret
     let secret = Secret::generate_secret();
@@ -33,9 +33,14 @@ pub async fn enable() -> BazaR<(String, String, String)> {
         .get_qr_base64()
         .map_err(|e| exn::Exn::new(Error::Message(format!("Failed to generate QR code: {}", e))))?;
 
-    // Save the secret and UUID in the database
-    crate::storage::save_content(TOTP_KEY.to_string(), secret_base32.clone()).await?;
-    crate::storage::save_raw(crate::TOTP_UUID_KEY.to_string(), uuid).await?;
+    // Save the secret and UUID in the database, together
+    let encrypted = crate::encrypt_data(secret_base32.as_bytes(), &key, TOTP_KEY)?;
+    let entries = vec![
+        (TOTP_KEY.to_string(), encrypted),
+        (crate::TOTP_UUID_KEY.to_string(), uuid.into_bytes()),
+    ];
+    crate::storage::with_backend(|backend| backend.set_many(entries)).await?;
+    crate::storage::checkpoint("Enable TOTP".into()).await?;
 
     Ok((secret_base32, url, qr_base64))
 }
@@ -61,7 +66,7 @@ pub async fn get_uuid() -> BazaR<String> {
 }
 
 /// Helper function to construct a TOTP verifier from the stored secret base32 string.
-fn get_totp(secret_base32: &str) -> BazaR<TOTP> {
+pub(crate) fn get_totp(secret_base32: &str) -> BazaR<TOTP> {
     let secret = Secret::Encoded(secret_base32.to_string());
     let secret_bytes = secret
         .to_bytes()
@@ -79,25 +84,10 @@ fn get_totp(secret_base32: &str) -> BazaR<TOTP> {
     .or_raise(|| Error::Message("Failed to initialize TOTP".into()))
 }
 
-/// Get the current UNIX timestamp in seconds, compatible with WASM and native platforms.
-fn get_timestamp() -> u64 {
-    #[cfg(target_arch = "wasm32")]
-    {
-        (js_sys::Date::now() / 1000.0) as u64
-    }
-    #[cfg(not(target_arch = "wasm32"))]
-    {
-        std::time::SystemTime::now()
-            .duration_since(std::time::SystemTime::UNIX_EPOCH)
-            .map(|d| d.as_secs())
-            .unwrap_or(0)
-    }
-}
-
 /// Internal helper to verify the code against the secret base32 string.
 pub(crate) fn verify_code(secret_base32: &str, code: &str) -> BazaR<bool> {
     let totp = get_totp(secret_base32)?;
-    let timestamp = get_timestamp();
+    let timestamp = crate::utils::get_timestamp();
     Ok(totp.check(code, timestamp))
 }
 
@@ -105,21 +95,11 @@ pub(crate) fn verify_code(secret_base32: &str, code: &str) -> BazaR<bool> {
 #[cfg(not(target_arch = "wasm32"))]
 mod tests {
     use super::*;
-    use crate::{init, Config};
+    use crate::init;
 
     #[test]
     fn test_totp_flow() {
-        let _lock = crate::TEST_MUTEX.lock().unwrap();
-        let test_dir = std::path::PathBuf::from(crate::test_datadir());
-        let _ = std::fs::remove_dir_all(&test_dir);
-        std::fs::create_dir_all(&test_dir).expect("Failed to create test dir");
-
-        let config_path = test_dir.join("baza.toml");
-        let mut config = Config::default();
-        config.main.datadir = test_dir.to_string_lossy().to_string();
-        let config_str = toml::to_string(&config).expect("Failed to serialize config");
-        std::fs::write(&config_path, config_str).expect("Failed to write config");
-        Config::build(&config_path).expect("Failed to build config");
+        crate::test_config();
 
         pollster::block_on(async {
             init(Some("test_passphrase".to_string()))
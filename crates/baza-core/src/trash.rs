@@ -0,0 +1,258 @@
+//! Deleted bundles are kept in the system box for a while before they are gone for good.
+//!
+//! Each deleted bundle becomes a single encrypted record holding its content, metadata
+//! and history, under a random key so that a name can be deleted more than once.
+
+use crate::{
+    error::Error,
+    history::{self, Revision},
+    index,
+    meta::{self, Meta},
+    storage::{self, Change},
+    utils::get_timestamp,
+    BazaR, Config, INDEX_KEY, TRASH_PREFIX,
+};
+use exn::ResultExt;
+use serde::{Deserialize, Serialize};
+use zeroize::Zeroizing;
+
+const DAY_SECONDS: u64 = 86_400;
+
+/// Default number of days deleted bundles stay in the trash.
+pub(crate) fn default_days() -> u64 {
+    30
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Record {
+    name: String,
+    /// UNIX timestamp of the deletion.
+    deleted: u64,
+    content: Zeroizing<String>,
+    meta: Option<Meta>,
+    history: Vec<(u64, Zeroizing<String>)>,
+}
+
+/// A bundle in the trash.
+pub struct Trashed {
+    pub name: String,
+    /// UNIX timestamp of the deletion.
+    pub deleted: u64,
+}
+
+/// Trash records with their keys, newest deletion first.
+async fn records(key: &[u8]) -> BazaR<Vec<(String, Record)>> {
+    let keys: Vec<String> = storage::with_backend(|backend| backend.list_keys())
+        .await?
+        .into_iter()
+        .filter(|key| key.starts_with(TRASH_PREFIX))
+        .collect();
+    let values = storage::with_backend(|backend| backend.get_many(&keys)).await?;
+    let mut records = Vec::with_capacity(keys.len());
+    for (id, encrypted) in keys.into_iter().zip(values) {
+        // Records are stored under their own name
+        let plaintext = crate::decrypt_data(&encrypted, key, &id)?;
+        let record: Record = postcard::from_bytes(&plaintext)
+            .or_raise(|| Error::Message("Failed to parse trash record".into()))?;
+        records.push((id, record));
+    }
+    records.sort_by_key(|(_, record)| std::cmp::Reverse(record.deleted));
+    Ok(records)
+}
+
+/// Trash record of the bundle `name` about to be deleted, or `None` when the trash is disabled.
+pub(crate) async fn keep(name: &str, key: &[u8]) -> BazaR<Option<Change>> {
+    if Config::get().main.trash_days == 0 {
+        return Ok(None);
+    }
+    let record = Record {
+        name: name.to_string(),
+        deleted: get_timestamp(),
+        content: storage::get_content(name).await?,
+        meta: meta::load(name, key).await?,
+        history: history::load(name, key)
+            .await?
+            .into_iter()
+            .map(|revision| (revision.timestamp, revision.content))
+            .collect(),
+    };
+    let plaintext = postcard::to_stdvec(&record)
+        .or_raise(|| Error::Message(format!("Failed to serialize trash record of '{name}'")))?;
+    let plaintext = Zeroizing::new(plaintext);
+    let id = format!("{TRASH_PREFIX}{}", uuid::Uuid::new_v4().simple());
+    let encrypted = crate::encrypt_data(&plaintext, key, &id)?;
+    Ok(Some(Change::Set(id, encrypted)))
+}
+
+/// Bundles in the trash, most recently deleted first.
+pub async fn list() -> BazaR<Vec<Trashed>> {
+    let key = crate::key()?;
+    Ok(records(&key)
+        .await?
+        .into_iter()
+        .map(|(_, record)| Trashed {
+            name: record.name,
+            deleted: record.deleted,
+        })
+        .collect())
+}
+
+/// Bring the most recently deleted bundle `name` back, with its metadata and history.
+pub async fn restore(name: &str) -> BazaR<()> {
+    let key = crate::key()?;
+    let mut names = index::load(&key).await?;
+    if names.contains(name) {
+        exn::bail!(Error::Message(format!(
+            "Bundle '{name}' already exists, delete it before restoring it from the trash"
+        )));
+    }
+    let Some((trash_id, record)) = records(&key)
+        .await?
+        .into_iter()
+        .find(|(_, record)| record.name == name)
+    else {
+        exn::bail!(Error::Message(format!(
+            "Bundle '{name}' is not in the trash"
+        )));
+    };
+
+    let id = index::id(name, &key)?;
+    let message = format!("Restore bundle {}", index::short_id(&id));
+    let mut changes = vec![
+        Change::Remove(trash_id),
+        Change::Set(
+            id.clone(),
+            crate::encrypt_data(record.content.as_bytes(), &key, name)?,
+        ),
+    ];
+    if let Some(meta) = record.meta {
+        changes.push(meta.seal(name, &key)?);
+    }
+    if !record.history.is_empty() {
+        let revisions: Vec<Revision> = record
+            .history
+            .into_iter()
+            .map(|(timestamp, content)| Revision { timestamp, content })
+            .collect();
+        changes.push(Change::Set(
+            history::storage_key(&id),
+            history::seal(name, &revisions, &key)?,
+        ));
+    }
+    names.insert(name.to_string());
+    changes.push(Change::Set(
+        INDEX_KEY.to_string(),
+        index::seal(&names, &key)?,
+    ));
+    storage::with_backend(|backend| backend.apply(changes)).await?;
+    storage::checkpoint(message).await?;
+    tracing::info!("Bundle '{name}' restored from the trash");
+    Ok(())
+}
+
+/// Delete every bundle in the trash for good, returning how many there were.
+pub async fn empty() -> BazaR<usize> {
+    crate::key()?;
+    let changes: Vec<Change> = storage::with_backend(|backend| backend.list_keys())
+        .await?
+        .into_iter()
+        .filter(|key| key.starts_with(TRASH_PREFIX))
+        .map(Change::Remove)
+        .collect();
+    let count = changes.len();
+    storage::with_backend(|backend| backend.apply(changes)).await?;
+    storage::checkpoint("Empty the trash".into()).await?;
+    storage::scrub().await?;
+    Ok(count)
+}
+
+/// Delete bundles that stayed in the trash longer than configured.
+pub(crate) async fn purge() -> BazaR<()> {
+    let key = crate::key()?;
+    let days = Config::get().main.trash_days;
+    let cutoff = get_timestamp().saturating_sub(days.saturating_mul(DAY_SECONDS));
+    let changes: Vec<Change> = records(&key)
+        .await?
+        .into_iter()
+        .filter(|(_, record)| days == 0 || record.deleted < cutoff)
+        .map(|(id, _)| Change::Remove(id))
+        .collect();
+    if changes.is_empty() {
+        return Ok(());
+    }
+    tracing::info!("Purging {} bundles from the trash", changes.len());
+    storage::with_backend(|backend| backend.apply(changes)).await?;
+    storage::checkpoint("Purge the trash".into()).await?;
+    storage::scrub().await
+}
+
+#[cfg(test)]
+#[cfg(not(target_arch = "wasm32"))]
+mod tests {
+    use super::*;
+    use crate::{init, rekey};
+
+    const NAME: &str = "test::trash::login";
+
+    async fn trashed() -> Vec<String> {
+        list()
+            .await
+            .expect("list failed")
+            .into_iter()
+            .map(|trashed| trashed.name)
+            .collect()
+    }
+
+    #[test]
+    fn test_trash() {
+        crate::test_config();
+
+        pollster::block_on(async {
+            init(Some("passphrase".into())).await.expect("init failed");
+            for content in ["first", "second"] {
+                storage::save_content(NAME.into(), content.into())
+                    .await
+                    .expect("save_content failed");
+            }
+            meta::tag(NAME, vec!["work".into()], vec![])
+                .await
+                .expect("tag failed");
+            assert!(restore(NAME).await.is_err());
+
+            storage::delete_by_name(NAME.into())
+                .await
+                .expect("delete_by_name failed");
+            assert!(storage::get_content(NAME).await.is_err());
+            assert!(storage::delete_by_name(NAME.into()).await.is_err());
+            assert_eq!(trashed().await, vec![NAME]);
+
+            // The trash follows the vault through a rekey
+            rekey("passphrase".into()).await.expect("rekey failed");
+            restore(NAME).await.expect("restore failed");
+            assert!(trashed().await.is_empty());
+            let content = storage::get_content(NAME)
+                .await
+                .expect("get_content failed");
+            assert_eq!(content.as_str(), "second");
+            let tags = meta::get(NAME)
+                .await
+                .expect("get failed")
+                .expect("no metadata")
+                .tags;
+            assert!(tags.contains("work"));
+            let revisions = history::list(NAME).await.expect("list failed");
+            assert_eq!(revisions.len(), 1);
+            assert_eq!(revisions[0].content.as_str(), "first");
+
+            // Recent deletions survive a purge, emptying the trash does not
+            storage::delete_by_name(NAME.into())
+                .await
+                .expect("delete_by_name failed");
+            purge().await.expect("purge failed");
+            assert_eq!(trashed().await, vec![NAME]);
+            assert_eq!(empty().await.expect("empty failed"), 1);
+            assert!(trashed().await.is_empty());
+            assert!(restore(NAME).await.is_err());
+        });
+    }
+}
@@ -43,6 +43,47 @@ pub fn cleanup_tmp_folder() -> BazaR<()> {
     Ok(())
 }
 
+/// Get the current UNIX timestamp in seconds, compatible with WASM and native platforms.
+pub(crate) fn get_timestamp() -> u64 {
+    #[cfg(target_arch = "wasm32")]
+    {
+        (js_sys::Date::now() / 1000.0) as u64
+    }
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        std::time::SystemTime::now()
+            .duration_since(std::time::SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0)
+    }
+}
+
+/// Format a UNIX timestamp as a UTC date and time, `YYYY-MM-DD HH:MM:SS`.
+pub fn format_timestamp(timestamp: u64) -> String {
+    let (days, seconds) = (timestamp / 86_400, timestamp % 86_400);
+    // Civil date from days since 1970-01-01, in 400-year eras starting in March
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+    format!(
+        "{year:04}-{month:02}-{day:02} {:02}:{:02}:{:02}",
+        seconds / 3_600,
+        seconds % 3_600 / 60,
+        seconds % 60
+    )
+}
+
 #[cfg_attr(not(target_arch = "wasm32"), allow(dead_code))]
 pub(crate) fn as_hash(str: &str) -> [u8; 32] {
     let mut hasher = sha2::Sha256::new();
@@ -50,3 +91,16 @@ pub(crate) fn as_hash(str: &str) -> [u8; 32] {
     let result = hasher.finalize();
     result.into()
 }
+
+#[cfg(test)]
+#[cfg(not(target_arch = "wasm32"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_timestamp() {
+        assert_eq!(format_timestamp(0), "1970-01-01 00:00:00");
+        assert_eq!(format_timestamp(951_782_400), "2000-02-29 00:00:00");
+        assert_eq!(format_timestamp(1_792_237_845), "2026-10-17 11:50:45");
+    }
+}
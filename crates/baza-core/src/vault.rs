@@ -0,0 +1,347 @@
+//! Named vaults, each with its own data directory and storage.
+//!
+//! `[vaults.<name>]` tables of the config describe them; `--vault` or `main.vault` picks
+//! the one in use, the top-level `main.datadir` and `[storage]` being the `default` vault.
+//! The web app keeps each vault in an IndexedDB database of its own.
+
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "s3")]
+use crate::S3Config;
+use crate::{error::Error, BazaR, StorageConfig};
+#[cfg(not(target_arch = "wasm32"))]
+use crate::{Config, Type};
+#[cfg(not(target_arch = "wasm32"))]
+use exn::ResultExt;
+#[cfg(not(target_arch = "wasm32"))]
+use std::{fs, path::Path};
+#[cfg(not(target_arch = "wasm32"))]
+use toml_edit::{Document, Item};
+
+/// Name of the vault described by the top-level sections of the config.
+pub const DEFAULT_VAULT: &str = "default";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VaultConfig {
+    pub datadir: String,
+    #[serde(default)]
+    pub storage: StorageConfig,
+    #[cfg(feature = "s3")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub s3: Option<S3Config>,
+}
+
+/// Vault names end up in paths and database names.
+pub fn check_name(name: &str) -> BazaR<()> {
+    let valid = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if !valid {
+        exn::bail!(Error::Message(format!(
+            "Invalid vault name '{name}', use letters, digits, '-' and '_'"
+        )));
+    }
+    Ok(())
+}
+
+/// Name of the vault in use.
+pub fn current() -> String {
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        Config::get()
+            .selected
+            .clone()
+            .unwrap_or_else(|| DEFAULT_VAULT.into())
+    }
+    #[cfg(target_arch = "wasm32")]
+    {
+        crate::storage::web::vault()
+    }
+}
+
+/// Switch to another vault, locking the current one.
+///
+/// Only the web app switches at runtime, the CLI picks its vault with `--vault`.
+pub fn select(name: &str) -> BazaR<()> {
+    check_name(name)?;
+    #[cfg(target_arch = "wasm32")]
+    {
+        crate::lock()?;
+        crate::storage::web::select(name);
+        Ok(())
+    }
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        exn::bail!(Error::Message(
+            "Vaults are selected with --vault on this platform".into()
+        ))
+    }
+}
+
+/// A vault of the config file.
+#[cfg(not(target_arch = "wasm32"))]
+#[derive(Debug)]
+pub struct Vault {
+    pub name: String,
+    pub datadir: String,
+    pub storage: Type,
+    /// Used when `--vault` is not given.
+    pub is_default: bool,
+}
+
+/// Apply `f` to the config file at `path`, leaving the tables and comments it does not
+/// touch as they are.
+#[cfg(not(target_arch = "wasm32"))]
+fn edit(path: &Path, f: impl FnOnce(&mut Document) -> BazaR<()>) -> BazaR<()> {
+    let content = fs::read_to_string(path)
+        .or_raise(|| Error::Message("Failed to read config file".into()))?;
+    let mut document = content
+        .parse::<Document>()
+        .or_raise(|| Error::Message("Failed to parse config file".into()))?;
+    f(&mut document)?;
+    fs::write(path, document.to_string())
+        .or_raise(|| Error::Message("Failed to write config file".into()))
+}
+
+/// Set `main.vault`, removing it when `None`.
+#[cfg(not(target_arch = "wasm32"))]
+fn set_main_vault(document: &mut Document, name: Option<&str>) -> BazaR<()> {
+    let Some(main) = document.get_mut("main").and_then(Item::as_table_like_mut) else {
+        exn::bail!(Error::Message(
+            "No [main] section in the config file".into()
+        ));
+    };
+    match name {
+        Some(name) => {
+            main.insert("vault", toml_edit::value(name));
+        }
+        None => {
+            main.remove("vault");
+        }
+    }
+    Ok(())
+}
+
+/// Place `table` and its subtables after every table already in the document.
+#[cfg(not(target_arch = "wasm32"))]
+fn append(table: &mut toml_edit::Table) {
+    table.set_position(usize::MAX);
+    for (_, item) in table.iter_mut() {
+        if let Some(table) = item.as_table_mut() {
+            append(table);
+        }
+    }
+}
+
+/// Every vault of the config file at `path`, the default one first.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn list(path: &Path) -> BazaR<Vec<Vault>> {
+    let config = Config::read(path)?;
+    let default = config.main.vault.as_deref().unwrap_or(DEFAULT_VAULT);
+
+    let mut vaults = vec![Vault {
+        name: DEFAULT_VAULT.into(),
+        datadir: config.main.datadir.clone(),
+        storage: config.storage.r#type,
+        is_default: default == DEFAULT_VAULT,
+    }];
+    vaults.extend(config.vaults.iter().map(|(name, vault)| Vault {
+        name: name.clone(),
+        datadir: vault.datadir.clone(),
+        storage: vault.storage.r#type,
+        is_default: default == name,
+    }));
+    Ok(vaults)
+}
+
+/// Add the `name` vault to the config file at `path`, its data in `vaults/<name>` of the
+/// default data directory unless `datadir` is given.
+///
+/// The vault still has to be initialized with `baza --vault <name> init`.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn create(
+    path: &Path,
+    name: &str,
+    datadir: Option<String>,
+    storage: Option<Type>,
+    default: bool,
+) -> BazaR<VaultConfig> {
+    check_name(name)?;
+    if storage == Some(Type::Memory) {
+        exn::bail!(Error::Message(
+            "A vault cannot use the memory storage, its entries would be gone on exit".into()
+        ));
+    }
+    let config = Config::read(path)?;
+    if name == DEFAULT_VAULT || config.vaults.contains_key(name) {
+        exn::bail!(Error::Message(format!("Vault '{name}' already exists")));
+    }
+
+    let vault = VaultConfig {
+        datadir: datadir.unwrap_or_else(|| format!("{}/vaults/{name}", config.main.datadir)),
+        storage: StorageConfig {
+            r#type: storage.unwrap_or(Type::Redb),
+            ..StorageConfig::default()
+        },
+        #[cfg(feature = "s3")]
+        s3: None,
+    };
+    let mut table = toml::to_string(&vault)
+        .or_raise(|| Error::Message("Failed to serialize vault".into()))?
+        .parse::<Document>()
+        .or_raise(|| Error::Message("Failed to serialize vault".into()))?
+        .as_table()
+        .clone();
+    append(&mut table);
+    edit(path, |document| {
+        let vaults = document
+            .entry("vaults")
+            .or_insert_with(|| {
+                let mut vaults = toml_edit::Table::new();
+                vaults.set_implicit(true);
+                Item::Table(vaults)
+            })
+            .as_table_like_mut()
+            .ok_or_else(|| Error::Message("'vaults' in the config file is not a table".into()))?;
+        vaults.insert(name, Item::Table(table));
+        if default {
+            set_main_vault(document, Some(name))?;
+        }
+        Ok(())
+    })?;
+    Ok(vault)
+}
+
+/// Remove the `name` vault from the config file at `path`, leaving its data in place.
+///
+/// The default vault falls back to the top-level one if it was `name`.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn remove(path: &Path, name: &str) -> BazaR<VaultConfig> {
+    let mut config = Config::read(path)?;
+    if name == DEFAULT_VAULT {
+        exn::bail!(Error::Message(
+            "The default vault is the top-level config, it can't be removed".into()
+        ));
+    }
+    let Some(vault) = config.vaults.remove(name) else {
+        exn::bail!(Error::Message(format!("Unknown vault '{name}'")));
+    };
+    edit(path, |document| {
+        if let Some(vaults) = document.get_mut("vaults").and_then(Item::as_table_like_mut) {
+            vaults.remove(name);
+        }
+        if config.main.vault.as_deref() == Some(name) {
+            set_main_vault(document, None)?;
+        }
+        Ok(())
+    })?;
+    Ok(vault)
+}
+
+/// Use the `name` vault when `--vault` is not given.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn set_default(path: &Path, name: &str) -> BazaR<()> {
+    let config = Config::read(path)?;
+    let vault = if name == DEFAULT_VAULT {
+        None
+    } else if config.vaults.contains_key(name) {
+        Some(name)
+    } else {
+        exn::bail!(Error::Message(format!("Unknown vault '{name}'")));
+    };
+    edit(path, |document| set_main_vault(document, vault))
+}
+
+#[cfg(test)]
+#[cfg(not(target_arch = "wasm32"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_vaults() {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        let path = dir.path().join("baza.toml");
+        let mut config = Config::default();
+        config.main.datadir = "/data".into();
+        config.write(&path).expect("write failed");
+
+        create(&path, "work", None, Some(Type::Sqlite), false).expect("create failed");
+        create(&path, "home", Some("/home".into()), None, true).expect("create failed");
+        assert!(create(&path, "work", None, None, false).is_err());
+        assert!(create(&path, DEFAULT_VAULT, None, None, false).is_err());
+        assert!(create(&path, "../escape", None, None, false).is_err());
+        assert!(create(&path, "scratch", None, Some(Type::Memory), false).is_err());
+
+        let vaults = list(&path).expect("list failed");
+        let names: Vec<_> = vaults.iter().map(|vault| vault.name.as_str()).collect();
+        assert_eq!(names, [DEFAULT_VAULT, "home", "work"]);
+        assert!(vaults[1].is_default);
+        assert_eq!(vaults[2].datadir, "/data/vaults/work");
+
+        // The default vault applies unless another one is asked for
+        let config = Config::read(&path).expect("read failed");
+        let selected = config.select(None).expect("select failed");
+        assert_eq!(selected.main.datadir, "/home");
+        assert_eq!(selected.selected.as_deref(), Some("home"));
+        let config = Config::read(&path).expect("read failed");
+        let selected = config.select(Some("work")).expect("select failed");
+        assert_eq!(selected.main.datadir, "/data/vaults/work");
+        assert_eq!(selected.storage.r#type, Type::Sqlite);
+        let config = Config::read(&path).expect("read failed");
+        let selected = config.select(Some(DEFAULT_VAULT)).expect("select failed");
+        assert_eq!(selected.main.datadir, "/data");
+        assert_eq!(selected.selected, None);
+        let config = Config::read(&path).expect("read failed");
+        assert!(config.select(Some("missing")).is_err());
+
+        remove(&path, "home").expect("remove failed");
+        assert!(remove(&path, "home").is_err());
+        let config = Config::read(&path).expect("read failed");
+        assert_eq!(config.main.vault, None);
+        set_default(&path, "work").expect("set_default failed");
+        assert!(set_default(&path, "home").is_err());
+        let config = Config::read(&path).expect("read failed");
+        assert_eq!(config.main.vault.as_deref(), Some("work"));
+    }
+
+    #[test]
+    fn test_keeps_config() {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        let path = dir.path().join("baza.toml");
+        let content = r#"# Personal vault
+[main]
+datadir = "/data" # kept on the NAS
+box_delimiter = ","
+bundle_delimiter = ":"
+
+[storage]
+type = "redb"
+
+# Backups
+[s3]
+bucket = "baza"
+
+[vaults.team]
+datadir = "/team"
+
+[vaults.team.s3]
+bucket = "team"
+"#;
+        fs::write(&path, content).expect("write failed");
+
+        create(&path, "work", None, Some(Type::Sqlite), true).expect("create failed");
+        set_default(&path, "team").expect("set_default failed");
+        remove(&path, "work").expect("remove failed");
+        set_default(&path, DEFAULT_VAULT).expect("set_default failed");
+        let edited = fs::read_to_string(&path).expect("read failed");
+        assert_eq!(edited, content);
+
+        create(&path, "work", None, Some(Type::Sqlite), false).expect("create failed");
+        let edited = fs::read_to_string(&path).expect("read failed");
+        assert!(edited.starts_with(content));
+        let config = Config::read(&path).expect("read failed");
+        let selected = config.select(Some("work")).expect("select failed");
+        assert_eq!(selected.storage.r#type, Type::Sqlite);
+    }
+}
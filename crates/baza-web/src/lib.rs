@@ -1,4 +1,5 @@
 use baza_core::prelude::*;
+use baza_core::{history, meta, trash, utils, vault};
 use wasm_bindgen::prelude::*;
 use wasm_bindgen::JsCast;
 use wasm_bindgen_futures::spawn_local;
@@ -11,22 +12,43 @@ extern "C" {
     fn uri_encode(s: &str) -> String;
 }
 
+/// Key of the local storage entry remembering the vault across reloads.
+const VAULT_KEY: &str = "baza-vault";
+
+fn local_storage() -> Option<web_sys::Storage> {
+    web_sys::window()?.local_storage().ok()?
+}
+
+/// Switch to the vault used before the page was reloaded.
+fn restore_vault() -> String {
+    let saved = local_storage().and_then(|storage| storage.get_item(VAULT_KEY).ok()?);
+    if let Some(name) = saved {
+        if vault::select(&name).is_err() {
+            return vault::current();
+        }
+    }
+    vault::current()
+}
+
 #[derive(Clone, Copy, Debug, PartialEq)]
 enum AppView {
     Login,
     Dashboard,
     AddBundle,
     TotpSettings,
+    Trash,
 }
 
 #[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
 struct BundleInfo {
     name: String,
+    meta: Option<meta::Meta>,
 }
 
 #[function_component(App)]
 pub fn app() -> Html {
     let view = use_state(|| AppView::Login);
+    let vault_name = use_state(restore_vault);
     let passphrase = use_state(String::new);
     let bundles = use_state(Vec::<BundleInfo>::new);
     let search_query = use_state(String::new);
@@ -39,6 +61,9 @@ pub fn app() -> Html {
     let new_bundle_pass = use_state(String::new);
     let is_editing = use_state(|| false);
     let original_name = use_state(String::new);
+    let revisions = use_state(Vec::<(u64, usize)>::new);
+    let trashed = use_state(Vec::<(String, u64)>::new);
+    let show_empty_trash_confirm = use_state(|| false);
     let show_delete_confirm = use_state(|| false);
     let show_delete_db_confirm = use_state(|| false);
 
@@ -71,9 +96,19 @@ pub fn app() -> Html {
             let bundles = bundles.clone();
             let error_msg = error_msg.clone();
             spawn_local(async move {
-                match storage::list_all_keys().await {
-                    Ok(keys) => {
-                        let info = keys.into_iter().map(|name| BundleInfo { name }).collect();
+                let listed = match storage::list_all_keys().await {
+                    Ok(keys) => meta::list().await.map(|metas| (keys, metas)),
+                    Err(e) => Err(e),
+                };
+                match listed {
+                    Ok((keys, mut metas)) => {
+                        let info = keys
+                            .into_iter()
+                            .map(|name| BundleInfo {
+                                meta: metas.remove(&name),
+                                name,
+                            })
+                            .collect();
                         bundles.set(info);
                     }
                     Err(e) => {
@@ -123,11 +158,15 @@ pub fn app() -> Html {
                     }
                     Err(e) => {
                         let err_str = e.to_string();
-                        if err_str.contains("TOTP code required") {
+                        if matches!(*e, error::Error::InvalidPassphrase) {
+                            error_msg.set("INVALID PASSPHRASE".to_string());
+                        } else if err_str.contains("TOTP code required") {
                             show_totp_input.set(true);
                             error_msg.set(err_str);
                         } else if err_str.contains("Invalid TOTP code") {
                             error_msg.set(err_str);
+                        } else if matches!(*e, error::Error::Throttled(_)) {
+                            error_msg.set(err_str.to_uppercase());
                         } else {
                             error_msg.set(format!("Unlock failed: {}", e));
                         }
@@ -266,6 +305,7 @@ pub fn app() -> Html {
         let set_pass = new_bundle_pass.clone();
         let set_is_editing = is_editing.clone();
         let set_show_delete_confirm = show_delete_confirm.clone();
+        let set_revisions = revisions.clone();
         let set_view = view.clone();
         let error_msg = error_msg.clone();
 
@@ -276,6 +316,7 @@ pub fn app() -> Html {
             let set_pass = set_pass.clone();
             let set_is_editing = set_is_editing.clone();
             let set_show_delete_confirm = set_show_delete_confirm.clone();
+            let set_revisions = set_revisions.clone();
             let set_view = set_view.clone();
             let error_msg = error_msg.clone();
 
@@ -289,8 +330,19 @@ pub fn app() -> Html {
                             parts.push(String::new());
                         }
                         set_name.set(parts);
+                        set_pass.set(content.to_string());
+                        match history::list(&name_clone).await {
+                            Ok(list) => set_revisions.set(
+                                list.iter()
+                                    .map(|r| (r.timestamp, r.content.lines().count()))
+                                    .collect(),
+                            ),
+                            Err(e) => {
+                                tracing::warn!("Failed to load history: {}", e);
+                                set_revisions.set(Vec::new());
+                            }
+                        }
                         set_orig_name.set(name_clone);
-                        set_pass.set(content);
                         set_is_editing.set(true);
                         set_show_delete_confirm.set(false);
                         set_view.set(AppView::AddBundle);
@@ -301,6 +353,25 @@ pub fn app() -> Html {
         })
     };
 
+    let perform_rollback = {
+        let orig_name_state = original_name.clone();
+        let perform_edit = perform_edit.clone();
+        let error_msg = error_msg.clone();
+
+        Callback::from(move |number: usize| {
+            let name = (*orig_name_state).clone();
+            let perform_edit = perform_edit.clone();
+            let error_msg = error_msg.clone();
+
+            spawn_local(async move {
+                match history::rollback(&name, number).await {
+                    Ok(_) => perform_edit.emit(name),
+                    Err(e) => error_msg.set(format!("Restore failed: {}", e)),
+                }
+            });
+        })
+    };
+
     let perform_delete = {
         let orig_name_state = original_name.clone();
         let set_view = view.clone();
@@ -438,9 +509,35 @@ pub fn app() -> Html {
         })
     };
 
+    let perform_rekey = {
+        let passphrase = passphrase.clone();
+        let init_passphrase = init_passphrase.clone();
+        let error_msg = error_msg.clone();
+        Callback::from(move |_| {
+            let p = (*init_passphrase)
+                .clone()
+                .unwrap_or_else(|| (*passphrase).clone());
+            let error_msg = error_msg.clone();
+            spawn_local(async move {
+                match rekey(p).await {
+                    Ok(count) => {
+                        error_msg.set(format!("VAULT RE-KEYED ({} ENTRIES)", count));
+                        let error_msg = error_msg.clone();
+                        spawn_local(async move {
+                            gloo_timers::future::TimeoutFuture::new(2000).await;
+                            error_msg.set(String::new());
+                        });
+                    }
+                    Err(e) => error_msg.set(format!("Rekey failed: {}", e)),
+                }
+            });
+        })
+    };
+
     let perform_restore = {
         let error_msg = error_msg.clone();
         let load_bundles = load_bundles.clone();
+        let passphrase = passphrase.clone();
         Callback::from(move |e: Event| {
             let target_opt = e.target_dyn_into::<HtmlInputElement>();
             let target = match target_opt {
@@ -454,6 +551,8 @@ pub fn app() -> Html {
                 if let Some(file) = files.get(0) {
                     let error_msg = error_msg.clone();
                     let load_bundles = load_bundles.clone();
+                    // A locked vault checks the dump with the passphrase entered above
+                    let passphrase = Some((*passphrase).clone()).filter(|p| !p.is_empty());
                     spawn_local(async move {
                         // Read file as ArrayBuffer for binary .baza format
                         let promise = file.array_buffer();
@@ -462,7 +561,7 @@ pub fn app() -> Html {
                                 let uint8 = js_sys::Uint8Array::new(&buf_js);
                                 let bytes = uint8.to_vec();
                                 match dump::restore::<Vec<(String, Vec<u8>)>>(&bytes) {
-                                    Ok(data) => match storage::restore(data).await {
+                                    Ok(data) => match storage::restore(data, passphrase).await {
                                         Ok(_) => {
                                             error_msg.set("RESTORE SUCCESSFUL".to_string());
                                             load_bundles.emit(());
@@ -492,6 +591,9 @@ pub fn app() -> Html {
             spawn_local(async move {
                 match storage::get_content(&name).await {
                     Ok(content) => {
+                        if let Err(e) = meta::touch(&name).await {
+                            tracing::warn!("Failed to record access: {}", e);
+                        }
                         let first_line = content.lines().next().unwrap_or("").trim().to_string();
                         let mut copied = false;
                         if let Some(window) = web_sys::window() {
@@ -566,7 +668,9 @@ pub fn app() -> Html {
     let generate_password = {
         let set_pass = new_bundle_pass.clone();
         Callback::from(move |_| {
-            let p = Password::generate(24, false, false, false).as_str();
+            let p = Password::generate(24, false, false, false)
+                .as_str()
+                .to_string();
             set_pass.set(p);
         })
     };
@@ -575,7 +679,14 @@ pub fn app() -> Html {
         let query = (*search_query).to_lowercase();
         bundles
             .iter()
-            .filter(|b| b.name.to_lowercase().contains(&query))
+            .filter(|b| {
+                b.name.to_lowercase().contains(&query)
+                    || b.meta.iter().any(|meta| {
+                        meta.tags
+                            .iter()
+                            .any(|tag| tag.to_lowercase().contains(&query))
+                    })
+            })
             .cloned()
             .collect::<Vec<_>>()
     };
@@ -588,6 +699,35 @@ pub fn app() -> Html {
         })
     };
 
+    let on_vault_change = {
+        let vault_name = vault_name.clone();
+        let passphrase = passphrase.clone();
+        let show_totp_input = show_totp_input.clone();
+        let error_msg = error_msg.clone();
+        Callback::from(move |e: Event| {
+            let input: HtmlInputElement = e.target_unchecked_into();
+            let name = match input.value().trim() {
+                "" => vault::DEFAULT_VAULT.to_string(),
+                name => name.to_string(),
+            };
+            if let Err(e) = vault::select(&name) {
+                error_msg.set(e.to_string());
+                return;
+            }
+            if let Some(storage) = local_storage() {
+                let _ = storage.set_item(VAULT_KEY, &name);
+            }
+            vault_name.set(name);
+            passphrase.set(String::new());
+            error_msg.set(String::new());
+
+            let show_totp_input = show_totp_input.clone();
+            spawn_local(async move {
+                show_totp_input.set(totp::is_enabled().await.unwrap_or(false));
+            });
+        })
+    };
+
     let on_search_input = {
         let set_search = search_query.clone();
         Callback::from(move |e: InputEvent| {
@@ -702,6 +842,66 @@ pub fn app() -> Html {
         })
     };
 
+    let load_trash = {
+        let trashed = trashed.clone();
+        let error_msg = error_msg.clone();
+        Callback::from(move |_| {
+            let trashed = trashed.clone();
+            let error_msg = error_msg.clone();
+            spawn_local(async move {
+                match trash::list().await {
+                    Ok(list) => {
+                        trashed.set(list.into_iter().map(|t| (t.name, t.deleted)).collect());
+                    }
+                    Err(e) => {
+                        error_msg.set(format!("Failed to load trash: {}", e));
+                    }
+                }
+            });
+        })
+    };
+
+    let perform_trash_restore = {
+        let load_trash = load_trash.clone();
+        let load_bundles = load_bundles.clone();
+        let error_msg = error_msg.clone();
+        Callback::from(move |name: String| {
+            let load_trash = load_trash.clone();
+            let load_bundles = load_bundles.clone();
+            let error_msg = error_msg.clone();
+            spawn_local(async move {
+                match trash::restore(&name).await {
+                    Ok(_) => {
+                        error_msg.set(String::new());
+                        load_trash.emit(());
+                        load_bundles.emit(());
+                    }
+                    Err(e) => error_msg.set(format!("Restore failed: {}", e)),
+                }
+            });
+        })
+    };
+
+    let perform_empty_trash = {
+        let load_trash = load_trash.clone();
+        let show_empty_trash_confirm = show_empty_trash_confirm.clone();
+        let error_msg = error_msg.clone();
+        Callback::from(move |_| {
+            let load_trash = load_trash.clone();
+            let show_empty_trash_confirm = show_empty_trash_confirm.clone();
+            let error_msg = error_msg.clone();
+            spawn_local(async move {
+                match trash::empty().await {
+                    Ok(_) => {
+                        show_empty_trash_confirm.set(false);
+                        load_trash.emit(());
+                    }
+                    Err(e) => error_msg.set(format!("Empty trash failed: {}", e)),
+                }
+            });
+        })
+    };
+
     let perform_enable_totp = {
         let is_totp_enabled = is_totp_enabled.clone();
         let totp_setup_info = totp_setup_info.clone();
@@ -818,6 +1018,15 @@ pub fn app() -> Html {
                             }
 
                             <div style={if *show_init_confirm || *show_delete_db_confirm { "display: none" } else { "display: block" }}>
+                                <div class="form-group">
+                                    <label>{"VAULT"}</label>
+                                    <input
+                                        type="text"
+                                        placeholder={vault::DEFAULT_VAULT}
+                                        value={(*vault_name).clone()}
+                                        onchange={on_vault_change}
+                                    />
+                                </div>
                                 <div class="form-group">
                                     <label>{"PASSPHRASE"}</label>
                                     <input
@@ -900,13 +1109,27 @@ pub fn app() -> Html {
                                 {
                                     for filtered_bundles.iter().map(|b| {
                                         let name = b.name.clone();
+                                        let details = b.meta.as_ref().map(|meta| {
+                                            let updated = utils::format_timestamp(meta.updated);
+                                            let tags: Vec<&str> = meta.tags.iter().map(String::as_str).collect();
+                                            if tags.is_empty() {
+                                                updated
+                                            } else {
+                                                format!("{} · {}", updated, tags.join(", "))
+                                            }
+                                        });
                                         let name_for_edit = name.clone();
                                         let name_for_copy = name.clone();
                                         let perform_edit = perform_edit.clone();
                                         let perform_copy = perform_copy_first_line.clone();
                                         html! {
                                             <li class="bundle-item" onclick={move |_| perform_copy.emit(name_for_copy.clone())}>
-                                                <span class="bundle-name">{&name}</span>
+                                                <div class="bundle-title">
+                                                    <span class="bundle-name">{&name}</span>
+                                                    if let Some(details) = details {
+                                                        <span class="bundle-meta">{details}</span>
+                                                    }
+                                                </div>
                                                 <div class="bundle-actions">
                                                     <button class="action-btn" title="Edit" onclick={move |e: MouseEvent| {
                                                         e.stop_propagation();
@@ -936,6 +1159,7 @@ pub fn app() -> Html {
 
                             <div class="backup-actions mt-1">
                                 <button class="btn btn-secondary" onclick={move |_| perform_dump.emit(())}>{"DUMP DATABASE"}</button>
+                                <button class="btn btn-secondary ml-1" onclick={move |_| perform_rekey.emit(())}>{"REKEY VAULT"}</button>
                                 <button class="btn btn-secondary ml-1" onclick={
                                     let set_view = view.clone();
                                     let load_totp_status = load_totp_status.clone();
@@ -944,6 +1168,16 @@ pub fn app() -> Html {
                                         set_view.set(AppView::TotpSettings);
                                     }
                                 }>{"TOTP SETTINGS"}</button>
+                                <button class="btn btn-secondary ml-1" onclick={
+                                    let set_view = view.clone();
+                                    let load_trash = load_trash.clone();
+                                    let show_empty_trash_confirm = show_empty_trash_confirm.clone();
+                                    move |_| {
+                                        show_empty_trash_confirm.set(false);
+                                        load_trash.emit(());
+                                        set_view.set(AppView::Trash);
+                                    }
+                                }>{"TRASH"}</button>
                                 <button class="btn btn-secondary ml-1" onclick={move |_| perform_lock.emit(())}>{"LOCK & EXIT"}</button>
                             </div>
 
@@ -983,9 +1217,30 @@ pub fn app() -> Html {
                                 }>{"CANCEL"}</button>
                             </div>
 
+                            if *is_editing && !revisions.is_empty() {
+                                <div class="form-group mt-1">
+                                    <label>{"HISTORY"}</label>
+                                    <ul class="bundle-list">
+                                        {
+                                            for revisions.iter().enumerate().map(|(i, (timestamp, lines))| {
+                                                let perform_rollback = perform_rollback.clone();
+                                                html! {
+                                                    <li class="bundle-item">
+                                                        <span class="bundle-name">{format!("{}  {} lines", utils::format_timestamp(*timestamp), lines)}</span>
+                                                        <div class="bundle-actions">
+                                                            <button class="action-btn" title="Restore" onclick={move |_| perform_rollback.emit(i + 1)}>{"↺"}</button>
+                                                        </div>
+                                                    </li>
+                                                }
+                                            })
+                                        }
+                                    </ul>
+                                </div>
+                            }
+
                             if *show_delete_confirm {
                                 <div class="confirm-box mt-1">
-                                    <p class="warning">{"DELETE THIS BUNDLE?"}</p>
+                                    <p class="warning">{"MOVE THIS BUNDLE TO THE TRASH?"}</p>
                                     <button class="btn btn-danger" onclick={move |_| perform_delete.emit(())}>{"CONFIRM DELETE"}</button>
                                     <button class="btn btn-ghost" onclick={
                                         let set_show_delete_confirm = show_delete_confirm.clone();
@@ -1047,6 +1302,58 @@ pub fn app() -> Html {
                             </div>
                         }
                     }
+                    AppView::Trash => html! {
+                        <div class="view-trash">
+                            <h3>{"TRASH"}</h3>
+                            if trashed.is_empty() {
+                                <p class="small">{"The trash is empty."}</p>
+                            }
+                            <ul class="bundle-list">
+                                {
+                                    for trashed.iter().map(|(name, deleted)| {
+                                        let name_for_restore = name.clone();
+                                        let perform_trash_restore = perform_trash_restore.clone();
+                                        html! {
+                                            <li class="bundle-item">
+                                                <div class="bundle-title">
+                                                    <span class="bundle-name">{name}</span>
+                                                    <span class="bundle-meta">{format!("deleted {}", utils::format_timestamp(*deleted))}</span>
+                                                </div>
+                                                <div class="bundle-actions">
+                                                    <button class="action-btn" title="Restore" onclick={move |_| perform_trash_restore.emit(name_for_restore.clone())}>{"↺"}</button>
+                                                </div>
+                                            </li>
+                                        }
+                                    })
+                                }
+                            </ul>
+
+                            if *show_empty_trash_confirm {
+                                <div class="confirm-box mt-1">
+                                    <p class="warning">{"DELETE EVERY BUNDLE IN THE TRASH FOR GOOD?"}</p>
+                                    <button class="btn btn-danger" onclick={move |_| perform_empty_trash.emit(())}>{"CONFIRM EMPTY"}</button>
+                                    <button class="btn btn-ghost" onclick={
+                                        let show_empty_trash_confirm = show_empty_trash_confirm.clone();
+                                        move |_| show_empty_trash_confirm.set(false)
+                                    }>{"CANCEL"}</button>
+                                </div>
+                            } else if !trashed.is_empty() {
+                                <button class="btn btn-danger mt-1" onclick={
+                                    let show_empty_trash_confirm = show_empty_trash_confirm.clone();
+                                    move |_| show_empty_trash_confirm.set(true)
+                                }>{"EMPTY TRASH"}</button>
+                            }
+
+                            <button class="btn btn-ghost mt-1" onclick={
+                                let set_view = view.clone();
+                                move |_| set_view.set(AppView::Dashboard)
+                            }>{"BACK TO DASHBOARD"}</button>
+
+                            if !error_msg.is_empty() {
+                                <p class="error">{(*error_msg).clone()}</p>
+                            }
+                        </div>
+                    },
                 }
             }
         </div>
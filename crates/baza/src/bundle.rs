@@ -19,6 +19,9 @@ pub(crate) enum SubCommands {
     Search(SearchArgs),
     Copy(CopyArgs),
     Show(ShowArgs),
+    History(HistoryArgs),
+    Rollback(RollbackArgs),
+    Info(InfoArgs),
 }
 
 #[derive(FromArgs, Debug)]
@@ -77,6 +80,119 @@ pub(crate) struct ShowArgs {
     pub(crate) name: String,
 }
 
+#[derive(FromArgs, Debug)]
+#[argh(subcommand, name = "history")]
+/// List previous revisions of a bundle
+pub(crate) struct HistoryArgs {
+    #[argh(positional)]
+    pub(crate) name: String,
+
+    /// show content of the revision
+    #[argh(option)]
+    pub(crate) show: Option<usize>,
+
+    /// show changes from the revision to the current content
+    #[argh(option)]
+    pub(crate) diff: Option<usize>,
+}
+
+#[derive(FromArgs, Debug)]
+#[argh(subcommand, name = "rollback")]
+/// Restore a previous revision of a bundle
+pub(crate) struct RollbackArgs {
+    #[argh(positional)]
+    pub(crate) name: String,
+
+    /// revision to restore, 1 is the most recent one
+    #[argh(positional)]
+    pub(crate) revision: usize,
+}
+
+#[derive(FromArgs, Debug)]
+#[argh(subcommand, name = "info")]
+/// Show metadata of a bundle and edit its tags
+pub(crate) struct InfoArgs {
+    #[argh(positional)]
+    pub(crate) name: String,
+
+    /// add a tag, can be repeated
+    #[argh(option)]
+    pub(crate) tag: Vec<String>,
+
+    /// remove a tag, can be repeated
+    #[argh(option)]
+    pub(crate) untag: Vec<String>,
+}
+
+fn info(args: InfoArgs) -> BazaR<()> {
+    let name = args.name.trim();
+    let meta = if args.tag.is_empty() && args.untag.is_empty() {
+        pollster::block_on(baza_core::meta::get(name))?
+    } else {
+        Some(pollster::block_on(baza_core::meta::tag(
+            name, args.tag, args.untag,
+        ))?)
+    };
+    let Some(meta) = meta else {
+        println!("Bundle '{name}' has no metadata yet, it is recorded at the next save");
+        return Ok(());
+    };
+    let time = baza_core::utils::format_timestamp;
+    let tags: Vec<&str> = meta.tags.iter().map(String::as_str).collect();
+    let tags = if tags.is_empty() {
+        "none".to_string()
+    } else {
+        tags.join(", ")
+    };
+    println!("Name:     {name}");
+    println!("Author:   {}", meta.author);
+    println!("Created:  {}", time(meta.created));
+    println!("Updated:  {}", time(meta.updated));
+    println!(
+        "Accessed: {}",
+        meta.accessed.map(time).unwrap_or("never".into())
+    );
+    println!("Tags:     {tags}");
+    Ok(())
+}
+
+fn history(args: HistoryArgs) -> BazaR<()> {
+    use colored::Colorize;
+
+    let name = args.name.trim();
+    if let Some(number) = args.show {
+        let revision = pollster::block_on(baza_core::history::get(name, number))?;
+        println!("{}", revision.content.as_str());
+        return Ok(());
+    }
+    if let Some(number) = args.diff {
+        let diff = pollster::block_on(baza_core::history::diff(name, number))?;
+        for line in diff.lines() {
+            match line.chars().next() {
+                Some('+') => println!("{}", line.bright_green()),
+                Some('-') => println!("{}", line.bright_red()),
+                Some('@') => println!("{}", line.bright_blue()),
+                _ => println!("{line}"),
+            }
+        }
+        return Ok(());
+    }
+
+    let revisions = pollster::block_on(baza_core::history::list(name))?;
+    if revisions.is_empty() {
+        println!("Bundle '{name}' has no previous revisions");
+    }
+    for (i, revision) in revisions.iter().enumerate() {
+        println!(
+            "{:>3}  {}  {} lines",
+            i + 1,
+            baza_core::utils::format_timestamp(revision.timestamp),
+            revision.content.lines().count()
+        );
+    }
+    Ok(())
+}
+
 pub(crate) fn handle(args: Args) -> BazaR<()> {
     match args.command {
         SubCommands::Add(args) => {
@@ -100,6 +216,13 @@ pub(crate) fn handle(args: Args) -> BazaR<()> {
         SubCommands::Copy(args) => {
             pollster::block_on(container::copy_to_clipboard(args.name))?;
         }
+        SubCommands::History(args) => history(args)?,
+        SubCommands::Info(args) => info(args)?,
+        SubCommands::Rollback(args) => {
+            let name = args.name.trim();
+            pollster::block_on(baza_core::history::rollback(name, args.revision))?;
+            println!("Bundle '{name}' rolled back to revision {}", args.revision);
+        }
     };
     Ok(())
 }
@@ -0,0 +1,49 @@
+use argh::FromArgs;
+use baza_core::BazaR;
+
+#[derive(FromArgs, Debug)]
+#[argh(subcommand, name = "git")]
+/// Sync a vault stored in git with its remote
+pub(crate) struct Args {
+    #[argh(subcommand)]
+    pub(crate) command: SubCommands,
+}
+
+#[derive(FromArgs, Debug)]
+#[argh(subcommand)]
+pub(crate) enum SubCommands {
+    Push(PushArgs),
+    Pull(PullArgs),
+}
+
+#[derive(FromArgs, Debug)]
+#[argh(subcommand, name = "push")]
+/// Commit pending changes and push them to the remote
+pub(crate) struct PushArgs {
+    /// remote to push to instead of storage.remote
+    #[argh(option)]
+    pub(crate) remote: Option<String>,
+}
+
+#[derive(FromArgs, Debug)]
+#[argh(subcommand, name = "pull")]
+/// Merge the changes of the remote into the vault
+pub(crate) struct PullArgs {
+    /// remote to pull from instead of storage.remote
+    #[argh(option)]
+    pub(crate) remote: Option<String>,
+}
+
+pub(crate) fn handle(args: Args) -> BazaR<()> {
+    match args.command {
+        SubCommands::Push(args) => {
+            pollster::block_on(baza_core::storage::git::push(args.remote))?;
+            println!("Vault pushed.");
+        }
+        SubCommands::Pull(args) => {
+            pollster::block_on(baza_core::storage::git::pull(args.remote))?;
+            println!("Vault pulled.");
+        }
+    }
+    Ok(())
+}
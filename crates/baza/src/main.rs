@@ -3,7 +3,13 @@ use baza_core::prelude::*;
 use exn::ResultExt;
 
 mod bundle;
+mod git;
 mod password;
+mod recovery;
+mod slot;
+mod storage;
+mod trash;
+mod vault;
 
 #[derive(FromArgs, Debug)]
 /// Baza: The base password manager
@@ -44,6 +50,10 @@ struct Cli {
     #[argh(option)]
     passphrase: Option<String>,
 
+    /// keyfile for the database
+    #[argh(option)]
+    keyfile: Option<String>,
+
     /// TOTP code for database unlock
     #[argh(option, short = 't')]
     totp: Option<String>,
@@ -52,6 +62,18 @@ struct Cli {
     #[argh(switch, short = 'l')]
     list: bool,
 
+    /// work on this dump in memory, leaving the vault untouched
+    #[argh(option)]
+    ephemeral: Option<String>,
+
+    /// write the ephemeral vault to this dump file when done
+    #[argh(option)]
+    export: Option<String>,
+
+    /// named vault to use instead of the default one
+    #[argh(option)]
+    vault: Option<String>,
+
     #[argh(subcommand)]
     command: Option<Commands>,
 }
@@ -69,6 +91,15 @@ enum Commands {
     Totp(TotpArgs),
     Unlock(UnlockArgs),
     Lock(LockArgs),
+    Passwd(PasswdArgs),
+    Rekey(RekeyArgs),
+    Slot(slot::Args),
+    Recovery(recovery::Args),
+    Trash(trash::Args),
+    Git(git::Args),
+    Storage(storage::Args),
+    Vault(vault::Args),
+    Doctor(DoctorArgs),
     #[cfg(feature = "s3")]
     Push(PushArgs),
     #[cfg(feature = "s3")]
@@ -83,6 +114,10 @@ struct InitArgs {
     #[argh(option, short = 'p')]
     passphrase: Option<String>,
 
+    /// keyfile required to unlock the database
+    #[argh(option, short = 'k')]
+    keyfile: Option<String>,
+
     /// force overwrite of existing database without confirmation prompt
     #[argh(switch, short = 'f')]
     force: bool,
@@ -109,7 +144,24 @@ struct DumpArgs {}
 struct RestoreArgs {
     /// path to dump file
     #[argh(positional)]
-    path: String,
+    path: Option<String>,
+
+    /// bring back the database as it was before the last restore
+    #[argh(switch)]
+    undo: bool,
+}
+
+#[derive(FromArgs, Debug)]
+#[argh(subcommand, name = "doctor")]
+/// Check that every entry of the database decrypts and makes sense
+struct DoctorArgs {
+    /// move broken entries aside so the rest of the vault keeps working
+    #[argh(switch)]
+    quarantine: bool,
+
+    /// print the report as JSON
+    #[argh(switch)]
+    json: bool,
 }
 
 #[derive(FromArgs, Debug)]
@@ -120,6 +172,10 @@ struct UnlockArgs {
     #[argh(option, short = 'p')]
     passphrase: Option<String>,
 
+    /// keyfile for the database
+    #[argh(option, short = 'k')]
+    keyfile: Option<String>,
+
     /// TOTP code for database unlock
     #[argh(option, short = 't')]
     totp: Option<String>,
@@ -130,6 +186,32 @@ struct UnlockArgs {
 /// Print unset command for BAZA_PASSPHRASE
 struct LockArgs {}
 
+#[derive(FromArgs, Debug)]
+#[argh(subcommand, name = "passwd")]
+/// Change the passphrase of the database
+struct PasswdArgs {
+    /// new passphrase for the database
+    #[argh(option, short = 'n')]
+    new_passphrase: Option<String>,
+}
+
+#[derive(FromArgs, Debug)]
+#[argh(subcommand, name = "rekey")]
+/// Re-encrypt all data with a new master key
+struct RekeyArgs {
+    /// passphrase for the database
+    #[argh(option, short = 'p')]
+    passphrase: Option<String>,
+
+    /// keyfile for the database
+    #[argh(option, short = 'k')]
+    keyfile: Option<String>,
+
+    /// TOTP code for database unlock
+    #[argh(option, short = 't')]
+    totp: Option<String>,
+}
+
 #[cfg(feature = "s3")]
 #[derive(FromArgs, Debug)]
 #[argh(subcommand, name = "push")]
@@ -140,7 +222,11 @@ struct PushArgs {}
 #[derive(FromArgs, Debug)]
 #[argh(subcommand, name = "pull")]
 /// Pull database from S3
-struct PullArgs {}
+struct PullArgs {
+    /// passphrase of the pulled database, checked before it replaces the local one
+    #[argh(option, short = 'p')]
+    passphrase: Option<String>,
+}
 
 #[derive(FromArgs, Debug)]
 #[argh(subcommand, name = "totp")]
@@ -180,27 +266,32 @@ fn run_command(cmd: Commands) -> BazaR<()> {
     match cmd {
         Commands::Password(s) => password::handle(s)?,
         Commands::Bundle(s) => bundle::handle(s)?,
+        Commands::Slot(s) => slot::handle(s)?,
+        Commands::Recovery(s) => recovery::handle(s)?,
+        Commands::Trash(s) => trash::handle(s)?,
+        Commands::Git(s) => git::handle(s)?,
+        Commands::Storage(s) => storage::handle(s)?,
+        Commands::Vault(s) => vault::handle(s)?,
         Commands::Init(args) => {
             use colored::Colorize;
-            if pollster::block_on(baza_core::storage::is_initialized())? {
-                if !args.force {
-                    let datadir = &baza_core::Config::get().main.datadir;
-                    eprint!(
-                        "Warning: A Baza vault already exists at: {}\nDo you really want to overwrite it and delete all existing data? [y/N]: ",
-                        datadir
-                    );
-                    std::io::Write::flush(&mut std::io::stderr()).ok();
-                    let mut input = String::new();
-                    std::io::stdin().read_line(&mut input).or_raise(|| {
-                        baza_core::error::Error::Message("Failed to read input".into())
-                    })?;
-                    let input = input.trim().to_lowercase();
-                    if input != "y" && input != "yes" {
-                        println!("Initialization aborted.");
-                        return Ok(());
-                    }
+            if pollster::block_on(baza_core::storage::is_initialized())? && !args.force {
+                let datadir = &baza_core::Config::get().main.datadir;
+                eprint!(
+                    "Warning: A Baza vault already exists at: {}\nDo you really want to overwrite it and delete all existing data? [y/N]: ",
+                    datadir
+                );
+                std::io::Write::flush(&mut std::io::stderr()).ok();
+                let mut input = String::new();
+                std::io::stdin()
+                    .read_line(&mut input)
+                    .or_raise(|| baza_core::error::Error::Message("Failed to read input".into()))?;
+                let input = input.trim().to_lowercase();
+                if input != "y" && input != "yes" {
+                    println!("Initialization aborted.");
+                    return Ok(());
                 }
             }
+            use_keyfile(args.keyfile.as_deref())?;
             let p = pollster::block_on(baza_core::init(args.passphrase))?;
 
             println!(
@@ -218,7 +309,12 @@ fn run_command(cmd: Commands) -> BazaR<()> {
             println!(" Please save the following master passphrase.");
             println!(" You will need it to unlock your vault in the future.");
             println!(" Baza does not store this key, so it CANNOT be recovered!");
-            println!("");
+            println!(" Use 'baza recovery split' to share emergency access.");
+            if baza_core::keyfile::path()?.is_some() {
+                println!(" The vault can only be unlocked together with the keyfile,");
+                println!(" keep a backup of it in a safe place.");
+            }
+            println!();
             println!(" Master Passphrase:");
             println!(" *  {}", p.bright_green().bold());
             println!(
@@ -236,16 +332,35 @@ fn run_command(cmd: Commands) -> BazaR<()> {
         Commands::Dump(_) => {
             handle_dump()?;
         }
+        Commands::Doctor(args) => handle_doctor(args)?,
         Commands::Restore(args) => {
-            handle_restore(args.path)?;
+            if args.undo {
+                pollster::block_on(baza_core::storage::undo_restore())?;
+                println!("Database brought back to its state before the last restore");
+            } else {
+                let path = args.path.ok_or_else(|| {
+                    baza_core::error::Error::Message("Path to dump file is required".into())
+                })?;
+                handle_restore(path)?;
+            }
         }
         #[cfg(feature = "s3")]
         Commands::Push(_) => {
             baza_core::s3::push()?;
         }
         #[cfg(feature = "s3")]
-        Commands::Pull(_) => {
-            baza_core::s3::pull()?;
+        Commands::Pull(args) => {
+            let passphrase = match args
+                .passphrase
+                .or_else(|| std::env::var("BAZA_PASSPHRASE").ok())
+            {
+                Some(passphrase) => passphrase,
+                None => rpassword::prompt_password("Enter passphrase of the pulled database: ")
+                    .or_raise(|| {
+                        baza_core::error::Error::Message("Failed to read passphrase".into())
+                    })?,
+            };
+            baza_core::s3::pull(passphrase)?;
         }
         Commands::Totp(args) => match args.command {
             TotpSubCommands::Enable(enable_args) => {
@@ -287,14 +402,41 @@ fn run_command(cmd: Commands) -> BazaR<()> {
                 .passphrase
                 .or_else(|| std::env::var("BAZA_PASSPHRASE").ok());
             let totp_opt = args.totp.or_else(|| std::env::var("BAZA_TOTP").ok());
+            use_keyfile(args.keyfile.as_deref())?;
 
             let (passphrase, totp_code) = acquire_credentials(passphrase_opt, totp_opt)?;
             pollster::block_on(baza_core::unlock(passphrase.clone(), totp_code))?;
             println!("export BAZA_PASSPHRASE=\"{}\"", passphrase);
+            if let Some(path) = args.keyfile {
+                println!("export BAZA_KEYFILE=\"{}\"", path);
+            }
         }
         Commands::Lock(_) => {
             println!("unset BAZA_PASSPHRASE");
         }
+        Commands::Rekey(args) => {
+            let passphrase_opt = args
+                .passphrase
+                .or_else(|| std::env::var("BAZA_PASSPHRASE").ok());
+            let totp_opt = args.totp.or_else(|| std::env::var("BAZA_TOTP").ok());
+            use_keyfile(args.keyfile.as_deref())?;
+
+            let (passphrase, totp_code) = acquire_credentials(passphrase_opt, totp_opt)?;
+            pollster::block_on(baza_core::unlock(passphrase.clone(), totp_code))?;
+            let count = pollster::block_on(baza_core::rekey(passphrase))?;
+            println!("Vault re-keyed, {} entries re-encrypted.", count);
+        }
+        Commands::Passwd(args) => {
+            let passphrase = match args.new_passphrase {
+                Some(p) => p,
+                None => acquire_new_passphrase()?,
+            };
+            pollster::block_on(baza_core::change_passphrase(passphrase))?;
+            println!("Passphrase changed successfully.");
+            if std::env::var("BAZA_PASSPHRASE").is_ok() {
+                eprintln!("BAZA_PASSPHRASE is outdated now, run 'eval $(baza unlock)' again");
+            }
+        }
     };
     Ok(())
 }
@@ -335,10 +477,16 @@ fn run_main() -> BazaR<()> {
         chosen_path
     };
 
+    let args: Cli = argh::from_env();
+    let vault = args
+        .vault
+        .clone()
+        .or_else(|| std::env::var("BAZA_VAULT").ok());
+
     // Default or found modern config path
-    baza_core::Config::build(&config_path)?;
+    baza_core::Config::build_for(&config_path, vault.as_deref())?;
 
-    handle_args()
+    handle_args(args)
 }
 
 fn acquire_credentials(
@@ -376,13 +524,51 @@ fn acquire_credentials(
     Ok((passphrase, totp_code))
 }
 
-fn handle_args() -> BazaR<()> {
+/// Use `path` as the keyfile for this run, it overrides `BAZA_KEYFILE` and `main.keyfile`.
+fn use_keyfile(path: Option<&str>) -> BazaR<()> {
+    if let Some(path) = path {
+        baza_core::keyfile::set(Some(path.into()))?;
+    }
+    Ok(())
+}
+
+fn acquire_new_passphrase() -> BazaR<String> {
+    let passphrase = rpassword::prompt_password("Enter new passphrase: ")
+        .map(|p| p.trim().to_string())
+        .or_raise(|| baza_core::error::Error::Message("Failed to read passphrase".into()))?;
+    let confirmation = rpassword::prompt_password("Repeat new passphrase: ")
+        .map(|p| p.trim().to_string())
+        .or_raise(|| baza_core::error::Error::Message("Failed to read passphrase".into()))?;
+    if passphrase != confirmation {
+        exn::bail!(baza_core::error::Error::Message(
+            "Passphrases do not match".into()
+        ));
+    }
+    Ok(passphrase)
+}
+
+fn handle_args(mut args: Cli) -> BazaR<()> {
     cleanup_tmp_folder().or_raise(|| {
         baza_core::error::Error::Message("Failed to cleanup temporary folder".into())
     })?;
 
-    let args: Cli = argh::from_env();
+    let export = args.export.take();
+    match &args.ephemeral {
+        Some(path) => baza_core::storage::ephemeral(read_dump(path)?)?,
+        None if export.is_some() => exn::bail!(baza_core::error::Error::Message(
+            "--export only applies to --ephemeral sessions".into()
+        )),
+        None => {}
+    }
+    handle_cli(args)?;
+    if let Some(path) = export {
+        write_dump(&path)?;
+        println!("Ephemeral vault exported to {path}");
+    }
+    Ok(())
+}
 
+fn handle_cli(args: Cli) -> BazaR<()> {
     // Handle passphrase acquisition
     let passphrase = args
         .passphrase
@@ -390,6 +576,9 @@ fn handle_args() -> BazaR<()> {
 
     let totp_code = args.totp.or_else(|| std::env::var("BAZA_TOTP").ok());
 
+    let keyfile = args.keyfile.or_else(|| std::env::var("BAZA_KEYFILE").ok());
+    use_keyfile(keyfile.as_deref())?;
+
     let should_unlock = if let Some(cmd) = &args.command {
         #[cfg(feature = "s3")]
         let is_s3 = matches!(cmd, Commands::Push(_) | Commands::Pull(_));
@@ -403,11 +592,34 @@ fn handle_args() -> BazaR<()> {
             _ => false,
         };
 
+        // Recovery is for vaults whose passphrase is lost
+        let is_recovery_combine = match cmd {
+            Commands::Recovery(r_args) => {
+                matches!(r_args.command, recovery::SubCommands::Combine(_))
+            }
+            _ => false,
+        };
+
+        // Compaction moves encrypted pages around without reading them
+        let is_storage_compact = match cmd {
+            Commands::Storage(s_args) => {
+                matches!(s_args.command, storage::SubCommands::Compact(_))
+            }
+            _ => false,
+        };
+
         !matches!(
             cmd,
-            Commands::Init(_) | Commands::Version(_) | Commands::Unlock(_) | Commands::Lock(_)
+            Commands::Init(_)
+                | Commands::Version(_)
+                | Commands::Unlock(_)
+                | Commands::Lock(_)
+                | Commands::Rekey(_)
+                | Commands::Vault(_)
         ) && !is_s3
             && !is_password_generate
+            && !is_recovery_combine
+            && !is_storage_compact
     } else {
         true
     };
@@ -484,6 +696,12 @@ fn handle_args() -> BazaR<()> {
 }
 
 fn handle_dump() -> BazaR<()> {
+    write_dump("dump.baza")?;
+    println!("Database dumped to dump.baza");
+    Ok(())
+}
+
+fn write_dump(path: &str) -> BazaR<()> {
     use exn::ResultExt;
     use std::fs::File;
     use std::io::Write;
@@ -492,26 +710,58 @@ fn handle_dump() -> BazaR<()> {
     let dumped = baza_core::dump::dump(&data, baza_core::dump::Algorithm::Lz4)
         .or_raise(|| baza_core::error::Error::Message("Failed to dump database".into()))?;
 
-    let mut file = File::create("dump.baza")
+    let mut file = File::create(path)
         .or_raise(|| baza_core::error::Error::Message("Failed to create dump file".into()))?;
     file.write_all(&dumped)
         .or_raise(|| baza_core::error::Error::Message("Failed to write dump file".into()))?;
-
-    println!("Database dumped to dump.baza");
     Ok(())
 }
 
-fn handle_restore(path: String) -> BazaR<()> {
+fn read_dump(path: &str) -> BazaR<Vec<(String, Vec<u8>)>> {
     use exn::ResultExt;
     use std::fs;
 
     let data = fs::read(path)
         .or_raise(|| baza_core::error::Error::Message("Failed to read dump file".into()))?;
-    let restored = baza_core::dump::restore::<Vec<(String, Vec<u8>)>>(&data)
-        .or_raise(|| baza_core::error::Error::Message("Failed to restore database".into()))?;
+    baza_core::dump::restore::<Vec<(String, Vec<u8>)>>(&data)
+        .or_raise(|| baza_core::error::Error::Message("Failed to restore database".into()))
+}
 
-    pollster::block_on(baza_core::storage::restore(restored))?;
+fn handle_doctor(args: DoctorArgs) -> BazaR<()> {
+    use colored::Colorize;
+
+    let report = pollster::block_on(baza_core::doctor::check(args.quarantine))?;
+    if args.json {
+        let json = serde_json::to_string_pretty(&report)
+            .or_raise(|| baza_core::error::Error::Message("Failed to serialize report".into()))?;
+        println!("{json}");
+    } else {
+        for issue in &report.issues {
+            let entry = issue.name.as_deref().unwrap_or(&issue.key);
+            if issue.fixed {
+                println!(
+                    "{} {entry}: {}, fixed",
+                    issue.kind.name().green(),
+                    issue.detail
+                );
+            } else {
+                println!("{} {entry}: {}", issue.kind.name().red(), issue.detail);
+            }
+        }
+        println!(
+            "{} entries checked, {} problem(s) found, {} left.",
+            report.checked,
+            report.issues.len(),
+            report.remaining()
+        );
+    }
+    baza_core::doctor::ensure_healthy(&report)
+}
+
+fn handle_restore(path: String) -> BazaR<()> {
+    let restored = read_dump(&path)?;
+    pollster::block_on(baza_core::storage::restore(restored, None))?;
 
-    println!("Database restored from dump");
+    println!("Database restored from dump, 'baza restore --undo' brings back the previous one");
     Ok(())
 }
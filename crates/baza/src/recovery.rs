@@ -0,0 +1,103 @@
+use argh::FromArgs;
+use baza_core::BazaR;
+use exn::ResultExt;
+
+#[derive(FromArgs, Debug)]
+#[argh(subcommand, name = "recovery")]
+/// Split the vault key into recovery shares and recover access from them
+pub(crate) struct Args {
+    #[argh(subcommand)]
+    pub(crate) command: SubCommands,
+}
+
+#[derive(FromArgs, Debug)]
+#[argh(subcommand)]
+pub(crate) enum SubCommands {
+    Split(SplitArgs),
+    Combine(CombineArgs),
+}
+
+#[derive(FromArgs, Debug)]
+#[argh(subcommand, name = "split")]
+/// Split the vault key into printable shares
+pub(crate) struct SplitArgs {
+    /// number of shares to print
+    #[argh(option)]
+    pub(crate) shares: u8,
+
+    /// number of shares required to recover the vault
+    #[argh(option)]
+    pub(crate) threshold: u8,
+}
+
+#[derive(FromArgs, Debug)]
+#[argh(subcommand, name = "combine")]
+/// Recover access to the vault from recovery shares
+pub(crate) struct CombineArgs {
+    /// key slot to write with the new passphrase
+    #[argh(
+        option,
+        short = 's',
+        default = "baza_core::slot::DEFAULT_SLOT.to_string()"
+    )]
+    pub(crate) slot: String,
+
+    /// new passphrase for the key slot
+    #[argh(option, short = 'n')]
+    pub(crate) new_passphrase: Option<String>,
+
+    /// keyfile required by the key slot
+    #[argh(option, short = 'k')]
+    pub(crate) keyfile: Option<String>,
+}
+
+/// Read shares from stdin, one per line, until an empty line.
+fn read_shares() -> BazaR<Vec<String>> {
+    eprintln!("Enter recovery shares, one per line, and an empty line to finish:");
+    let mut shares = Vec::new();
+    for line in std::io::stdin().lines() {
+        let line = line.or_raise(|| {
+            baza_core::error::Error::Message("Failed to read recovery share".into())
+        })?;
+        let line = line.trim();
+        if line.is_empty() {
+            break;
+        }
+        shares.push(line.to_string());
+    }
+    Ok(shares)
+}
+
+pub(crate) fn handle(args: Args) -> BazaR<()> {
+    match args.command {
+        SubCommands::Split(args) => {
+            let shares = baza_core::recovery::split(args.shares, args.threshold)?;
+            eprintln!(
+                "Any {} of these {} shares recover the vault. Give each one to a different person.",
+                args.threshold, args.shares
+            );
+            eprintln!("The shares stop working after 'baza rekey'.");
+            for share in shares {
+                println!("{}", share);
+            }
+        }
+        SubCommands::Combine(args) => {
+            crate::use_keyfile(args.keyfile.as_deref())?;
+            let shares = read_shares()?;
+            let passphrase = match args.new_passphrase {
+                Some(p) => p,
+                None => crate::acquire_new_passphrase()?,
+            };
+            pollster::block_on(baza_core::recovery::combine(
+                shares,
+                args.slot.clone(),
+                passphrase,
+            ))?;
+            println!(
+                "Vault recovered, key slot '{}' now opens with the new passphrase.",
+                args.slot
+            );
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,78 @@
+use argh::FromArgs;
+use baza_core::BazaR;
+
+#[derive(FromArgs, Debug)]
+#[argh(subcommand, name = "slot")]
+/// Manage key slots, one passphrase per member of a shared vault
+pub(crate) struct Args {
+    #[argh(subcommand)]
+    pub(crate) command: SubCommands,
+}
+
+#[derive(FromArgs, Debug)]
+#[argh(subcommand)]
+pub(crate) enum SubCommands {
+    Add(AddArgs),
+    Remove(RemoveArgs),
+    List(ListArgs),
+}
+
+#[derive(FromArgs, Debug)]
+#[argh(subcommand, name = "add")]
+/// Add a key slot with another passphrase
+pub(crate) struct AddArgs {
+    #[argh(positional)]
+    pub(crate) name: String,
+
+    /// passphrase for the new slot
+    #[argh(option, short = 'n')]
+    pub(crate) new_passphrase: Option<String>,
+
+    /// keyfile required by the new slot
+    #[argh(option, short = 'k')]
+    pub(crate) keyfile: Option<String>,
+}
+
+#[derive(FromArgs, Debug)]
+#[argh(subcommand, name = "remove")]
+/// Remove a key slot
+pub(crate) struct RemoveArgs {
+    #[argh(positional)]
+    pub(crate) name: String,
+}
+
+#[derive(FromArgs, Debug)]
+#[argh(subcommand, name = "list")]
+/// List key slots
+pub(crate) struct ListArgs {}
+
+pub(crate) fn handle(args: Args) -> BazaR<()> {
+    match args.command {
+        SubCommands::Add(args) => {
+            let passphrase = match args.new_passphrase {
+                Some(p) => p,
+                None => crate::acquire_new_passphrase()?,
+            };
+            let keyfile = args.keyfile.map(Into::into);
+            pollster::block_on(baza_core::slot::add(args.name.clone(), passphrase, keyfile))?;
+            println!("Key slot '{}' added.", args.name);
+        }
+        SubCommands::Remove(args) => {
+            pollster::block_on(baza_core::slot::remove(args.name.clone()))?;
+            println!("Key slot '{}' removed.", args.name);
+        }
+        SubCommands::List(_) => {
+            for info in pollster::block_on(baza_core::slot::list())? {
+                let mut line = info.name;
+                if info.keyfile {
+                    line.push_str(" (keyfile)");
+                }
+                if info.current {
+                    line.push_str(" *");
+                }
+                println!("{}", line);
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,71 @@
+use argh::FromArgs;
+use baza_core::BazaR;
+
+#[derive(FromArgs, Debug)]
+#[argh(subcommand, name = "storage")]
+/// Manage where the vault is stored
+pub(crate) struct Args {
+    #[argh(subcommand)]
+    pub(crate) command: SubCommands,
+}
+
+#[derive(FromArgs, Debug)]
+#[argh(subcommand)]
+pub(crate) enum SubCommands {
+    Migrate(MigrateArgs),
+    Compact(CompactArgs),
+}
+
+#[derive(FromArgs, Debug)]
+#[argh(subcommand, name = "migrate")]
+/// Copy the vault to another storage type
+pub(crate) struct MigrateArgs {
+    /// storage type to copy the vault to: redb, files, git or sqlite
+    #[argh(option)]
+    pub(crate) to: baza_core::Type,
+}
+
+#[derive(FromArgs, Debug)]
+#[argh(subcommand, name = "compact")]
+/// Give the space of removed entries back to the disk
+pub(crate) struct CompactArgs {}
+
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{bytes} B")
+    } else {
+        format!("{size:.1} {}", UNITS[unit])
+    }
+}
+
+pub(crate) fn handle(args: Args) -> BazaR<()> {
+    match args.command {
+        SubCommands::Migrate(args) => {
+            let count = pollster::block_on(baza_core::storage::migrate(args.to))?;
+            println!("{count} entries copied.");
+            println!(
+                "Set type = \"{}\" in the [storage] section of the config to use it.",
+                args.to
+            );
+        }
+        SubCommands::Compact(_) => match pollster::block_on(baza_core::storage::compact())? {
+            Some(compaction) => println!(
+                "Storage compacted from {} to {}.",
+                format_size(compaction.before),
+                format_size(compaction.after)
+            ),
+            None => println!(
+                "The {} storage has nothing to compact.",
+                baza_core::storage::current_type()
+            ),
+        },
+    }
+    Ok(())
+}
@@ -0,0 +1,60 @@
+use argh::FromArgs;
+use baza_core::BazaR;
+
+#[derive(FromArgs, Debug)]
+#[argh(subcommand, name = "trash")]
+/// List, restore and empty deleted bundles
+pub(crate) struct Args {
+    #[argh(subcommand)]
+    pub(crate) command: SubCommands,
+}
+
+#[derive(FromArgs, Debug)]
+#[argh(subcommand)]
+pub(crate) enum SubCommands {
+    List(ListArgs),
+    Restore(RestoreArgs),
+    Empty(EmptyArgs),
+}
+
+#[derive(FromArgs, Debug)]
+#[argh(subcommand, name = "list")]
+/// List deleted bundles, most recent first
+pub(crate) struct ListArgs {}
+
+#[derive(FromArgs, Debug)]
+#[argh(subcommand, name = "restore")]
+/// Restore a deleted bundle
+pub(crate) struct RestoreArgs {
+    #[argh(positional)]
+    pub(crate) name: String,
+}
+
+#[derive(FromArgs, Debug)]
+#[argh(subcommand, name = "empty")]
+/// Delete every bundle in the trash for good
+pub(crate) struct EmptyArgs {}
+
+pub(crate) fn handle(args: Args) -> BazaR<()> {
+    match args.command {
+        SubCommands::List(_) => {
+            for trashed in pollster::block_on(baza_core::trash::list())? {
+                println!(
+                    "{}  {}",
+                    baza_core::utils::format_timestamp(trashed.deleted),
+                    trashed.name
+                );
+            }
+        }
+        SubCommands::Restore(args) => {
+            let name = args.name.trim();
+            pollster::block_on(baza_core::trash::restore(name))?;
+            println!("Bundle '{name}' restored.");
+        }
+        SubCommands::Empty(_) => {
+            let count = pollster::block_on(baza_core::trash::empty())?;
+            println!("{count} bundles deleted for good.");
+        }
+    }
+    Ok(())
+}
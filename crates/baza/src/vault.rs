@@ -0,0 +1,107 @@
+use argh::FromArgs;
+use baza_core::{vault, BazaR, Config};
+use std::path::PathBuf;
+
+#[derive(FromArgs, Debug)]
+#[argh(subcommand, name = "vault")]
+/// Manage the named vaults of the config
+pub(crate) struct Args {
+    #[argh(subcommand)]
+    pub(crate) command: SubCommands,
+}
+
+#[derive(FromArgs, Debug)]
+#[argh(subcommand)]
+pub(crate) enum SubCommands {
+    List(ListArgs),
+    Create(CreateArgs),
+    Remove(RemoveArgs),
+    Default(DefaultArgs),
+}
+
+#[derive(FromArgs, Debug)]
+#[argh(subcommand, name = "list")]
+/// List the vaults of the config
+pub(crate) struct ListArgs {}
+
+#[derive(FromArgs, Debug)]
+#[argh(subcommand, name = "create")]
+/// Add a vault to the config
+pub(crate) struct CreateArgs {
+    /// name of the vault
+    #[argh(positional)]
+    pub(crate) name: String,
+
+    /// data directory of the vault, vaults/<name> in the default one otherwise
+    #[argh(option)]
+    pub(crate) datadir: Option<String>,
+
+    /// storage type of the vault: redb, files, git or sqlite
+    #[argh(option)]
+    pub(crate) storage: Option<baza_core::Type>,
+
+    /// use the vault when --vault is not given
+    #[argh(switch)]
+    pub(crate) default: bool,
+}
+
+#[derive(FromArgs, Debug)]
+#[argh(subcommand, name = "remove")]
+/// Remove a vault from the config, leaving its data in place
+pub(crate) struct RemoveArgs {
+    /// name of the vault
+    #[argh(positional)]
+    pub(crate) name: String,
+}
+
+#[derive(FromArgs, Debug)]
+#[argh(subcommand, name = "default")]
+/// Use a vault when --vault is not given
+pub(crate) struct DefaultArgs {
+    /// name of the vault
+    #[argh(positional)]
+    pub(crate) name: String,
+}
+
+fn config_path() -> BazaR<PathBuf> {
+    Config::get().path.clone().ok_or_else(|| {
+        exn::Exn::new(baza_core::error::Error::Message(
+            "No config file in use".into(),
+        ))
+    })
+}
+
+pub(crate) fn handle(args: Args) -> BazaR<()> {
+    let path = config_path()?;
+    match args.command {
+        SubCommands::List(_) => {
+            let current = vault::current();
+            for vault in vault::list(&path)? {
+                let marker = if vault.name == current { "*" } else { " " };
+                let default = if vault.is_default { " (default)" } else { "" };
+                println!(
+                    "{marker} {}{default}\t{}\t{}",
+                    vault.name, vault.storage, vault.datadir
+                );
+            }
+        }
+        SubCommands::Create(args) => {
+            let created =
+                vault::create(&path, &args.name, args.datadir, args.storage, args.default)?;
+            println!("Vault '{}' created in {}.", args.name, created.datadir);
+            println!("Run 'baza --vault {} init' to initialize it.", args.name);
+        }
+        SubCommands::Remove(args) => {
+            let removed = vault::remove(&path, &args.name)?;
+            println!(
+                "Vault '{}' removed, its data is still in {}.",
+                args.name, removed.datadir
+            );
+        }
+        SubCommands::Default(args) => {
+            vault::set_default(&path, &args.name)?;
+            println!("Vault '{}' is now the default one.", args.name);
+        }
+    }
+    Ok(())
+}